@@ -54,30 +54,29 @@ async fn main() {
 }
 
 async fn run_polling(client: lastfm::Client, username: String) {
-    loop {
-        let stream = client.stream_now_playing(&username, Duration::from_secs(3));
+    let stream = client.stream_now_playing(&username, Duration::from_secs(3));
 
-        pin_mut!(stream);
+    pin_mut!(stream);
 
-        while let Some(track) = stream.next().await {
-            match track {
-                Ok(track) => {
-                    if let Some(track) = track {
-                        println!(
-                            "{} is now listening to {} - {} from the album {}",
-                            username,
-                            track.name(),
-                            track.artist(),
-                            track.album()
-                        );
-                    } else {
-                        println!("{} is not listening to anything", username);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error: {}", e);
+    // errors don't end the stream unless polling again can't help
+    while let Some(track) = stream.next().await {
+        match track {
+            Ok(track) => {
+                if let Some(track) = track {
+                    println!(
+                        "{} is now listening to {} - {} from the album {}",
+                        username,
+                        track.name(),
+                        track.artist(),
+                        track.album()
+                    );
+                } else {
+                    println!("{} is not listening to anything", username);
                 }
             }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+            }
         }
     }
 }
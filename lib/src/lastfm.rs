@@ -1,25 +1,81 @@
-use std::{error::Error, fmt, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use async_stream::try_stream;
-use error_stack::{Result, ResultExt};
+use async_stream::stream;
+use chrono::{DateTime, TimeDelta, Utc};
+use error_stack::{Report, Result, ResultExt};
 use futures::Stream;
+use md5::{Digest, Md5};
 use nestify::nest;
 use serde_json::{from_value, Value};
 use tracing::debug;
 use url::Url;
 
 pub const API_BASE: &str = "https://ws.audioscrobbler.com/2.0/";
+/// Where users are sent to let SlackFM act on their Last.fm account
+pub const AUTH_URL: &str = "https://www.last.fm/api/auth/";
+
+/// The shortest polling interval [`Client::stream_now_playing`] will use, so even a zero interval
+/// (or a request that errors immediately) can't poll in a hot loop.
+pub const MIN_POLLING_INTERVAL: Duration = Duration::from_secs(1);
+/// How long [`Client::does_user_exist`] remembers an answer unless configured otherwise
+pub const DEFAULT_USER_EXISTS_TTL: Duration = Duration::from_secs(10 * 60);
+/// The largest page of recent tracks Last.fm will send
+const MAX_RECENT_TRACKS_LIMIT: u32 = 200;
 
 pub struct Client {
     key: String,
+    /// The API key's shared secret, needed to sign write requests like [`Client::love_track`]
+    secret: Option<String>,
     client: reqwest::Client,
     base_url: Url,
+    retry: RequestRetry,
+    /// Whether each (lowercased) username exists and when that was checked. Shared with clients
+    /// made by [`Client::with_key`], since it doesn't depend on the key
+    user_exists_cache: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
+    user_exists_ttl: Duration,
+    /// Whether recent tracks are fetched with extended data
+    extended: bool,
+}
+
+/// How often a read request is retried after a network error or a 5xx/429 response. The delay
+/// doubles after every attempt
+#[derive(Debug, Clone, Copy)]
+pub struct RequestRetry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for RequestRetry {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            delay: Duration::from_millis(500),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum LastFMError {
     RequestError,
     ParseError,
+    /// A signed request was made without a shared secret
+    MissingSecret,
+    /// Last.fm rejected the request's parameters, e.g. because the user doesn't exist
+    InvalidParameters,
+    /// Last.fm couldn't do what was asked, usually a temporary problem on their end
+    OperationFailed,
+    /// Too many requests were made with the API key
+    RateLimited,
+    /// The API key is invalid or suspended
+    InvalidApiKey,
+    /// Last.fm answered with any other error
+    ApiError,
 }
 
 impl fmt::Display for LastFMError {
@@ -27,22 +83,347 @@ impl fmt::Display for LastFMError {
         match self {
             LastFMError::RequestError => f.write_str("An error occurred while making the request"),
             LastFMError::ParseError => f.write_str("An error occurred while parsing the response"),
+            LastFMError::MissingSecret => f.write_str("The Last.fm shared secret isn't configured"),
+            LastFMError::InvalidParameters => f.write_str("Last.fm rejected the request"),
+            LastFMError::OperationFailed => f.write_str("Last.fm failed to handle the request"),
+            LastFMError::RateLimited => f.write_str("Last.fm's rate limit was exceeded"),
+            LastFMError::InvalidApiKey => f.write_str("Last.fm didn't accept the API key"),
+            LastFMError::ApiError => f.write_str("Last.fm returned an error"),
         }
     }
 }
 impl Error for LastFMError {}
 
+impl LastFMError {
+    /// A short label for the kind of error, used when recording metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LastFMError::RequestError => "request",
+            LastFMError::ParseError => "parse",
+            LastFMError::MissingSecret => "missing_secret",
+            LastFMError::InvalidParameters => "invalid_parameters",
+            LastFMError::OperationFailed => "operation_failed",
+            LastFMError::RateLimited => "rate_limited",
+            LastFMError::InvalidApiKey => "invalid_api_key",
+            LastFMError::ApiError => "api",
+        }
+    }
+
+    /// Whether trying again can't help, e.g. because the user doesn't exist
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            LastFMError::InvalidParameters | LastFMError::MissingSecret
+        )
+    }
+}
+
+/// Counts a failed Last.fm request in the `lastfm_errors_total` counter, labeled by the kind of
+/// error and the API method that failed
+fn record_error(method: &'static str, error: &LastFMError) {
+    metrics::counter!("lastfm_errors_total", "kind" => error.kind(), "method" => method)
+        .increment(1);
+}
+
+/// Counts finished Last.fm requests in the `lastfm_requests_total` counter, labeled by the API
+/// method and whether it succeeded (`ok` or `err`). Failures are also counted by
+/// [`record_error`]
+trait RecordRequest {
+    fn record(self, method: &'static str) -> Self;
+}
+
+impl<T> RecordRequest for Result<T, LastFMError> {
+    fn record(self, method: &'static str) -> Self {
+        let outcome = match &self {
+            Ok(_) => "ok",
+            Err(e) => {
+                record_error(method, e.current_context());
+                "err"
+            }
+        };
+        metrics::counter!("lastfm_requests_total", "method" => method, "outcome" => outcome)
+            .increment(1);
+
+        self
+    }
+}
+
 impl Client {
     pub fn new(api_key: String, client: reqwest::Client) -> Self {
+        Self::with_base_url(api_key, client, Url::parse(API_BASE).unwrap())
+    }
+
+    /// A client for an API at a different address than Last.fm's, e.g. a mock server in tests
+    pub fn with_base_url(api_key: String, client: reqwest::Client, base_url: Url) -> Self {
         Self {
             key: api_key,
+            secret: None,
             client,
-            base_url: Url::parse(API_BASE).unwrap(),
+            base_url,
+            retry: RequestRetry::default(),
+            user_exists_cache: Arc::default(),
+            user_exists_ttl: DEFAULT_USER_EXISTS_TTL,
+            extended: true,
+        }
+    }
+
+    /// A client for the same API using a different API key, e.g. one supplied by a user. The
+    /// shared secret belongs to the old key, so it isn't kept
+    pub fn with_key(&self, api_key: String) -> Self {
+        Self {
+            key: api_key,
+            secret: None,
+            client: self.client.clone(),
+            base_url: self.base_url.clone(),
+            retry: self.retry,
+            user_exists_cache: self.user_exists_cache.clone(),
+            user_exists_ttl: self.user_exists_ttl,
+            extended: self.extended,
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RequestRetry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How long [`Client::does_user_exist`] reuses an answer for the same username. Zero turns
+    /// the cache off
+    pub fn with_user_exists_ttl(mut self, ttl: Duration) -> Self {
+        self.user_exists_ttl = ttl;
+        self
+    }
+
+    /// Whether recent tracks are fetched with extended data: whether the user loved each track,
+    /// and the artist's mbid and images. On by default
+    pub fn with_extended(mut self, extended: bool) -> Self {
+        self.extended = extended;
+        self
+    }
+
+    /// Sets the API key's shared secret, which enables the signed (write) methods
+    pub fn with_secret(mut self, secret: String) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Where to send a user so they can let this client act on their account. Last.fm redirects
+    /// them back to `callback` with a `token` to pass to [`Client::get_session`]
+    pub fn auth_url(&self, callback: &Url) -> Url {
+        let mut url = Url::parse(AUTH_URL).unwrap();
+        url.query_pairs_mut()
+            .append_pair("api_key", &self.key)
+            .append_pair("cb", callback.as_str());
+        url
+    }
+
+    /// Exchanges the token from the auth redirect for a session key, which doesn't expire
+    #[tracing::instrument(skip(self))]
+    pub async fn get_session(&self, token: &str) -> Result<Session, LastFMError> {
+        let response: SessionResponse = self
+            .signed_call("auth.getSession", &[("token", token)])
+            .await
+            .record("auth.getSession")?;
+
+        Ok(response.session)
+    }
+
+    /// Loves a track on the account the session key belongs to
+    #[tracing::instrument(skip(self, session_key))]
+    pub async fn love_track(
+        &self,
+        session_key: &str,
+        artist: &str,
+        track: &str,
+    ) -> Result<(), LastFMError> {
+        self.signed_call::<Value>(
+            "track.love",
+            &[("sk", session_key), ("artist", artist), ("track", track)],
+        )
+        .await
+        .record("track.love")?;
+
+        Ok(())
+    }
+
+    /// Unloves a track on the account the session key belongs to
+    #[tracing::instrument(skip(self, session_key))]
+    pub async fn unlove_track(
+        &self,
+        session_key: &str,
+        artist: &str,
+        track: &str,
+    ) -> Result<(), LastFMError> {
+        self.signed_call::<Value>(
+            "track.unlove",
+            &[("sk", session_key), ("artist", artist), ("track", track)],
+        )
+        .await
+        .record("track.unlove")?;
+
+        Ok(())
+    }
+
+    /// Scrobbles a track played at `timestamp` (a unix timestamp) to the account the session key
+    /// belongs to, returning how many scrobbles Last.fm accepted and ignored
+    #[tracing::instrument(skip(self, session_key))]
+    pub async fn scrobble(
+        &self,
+        session_key: &str,
+        track: &str,
+        artist: &str,
+        timestamp: i64,
+    ) -> Result<ScrobbleCounts, LastFMError> {
+        let timestamp = timestamp.to_string();
+        let response: ScrobbleResponse = self
+            .signed_call(
+                "track.scrobble",
+                &[
+                    ("sk", session_key),
+                    ("track", track),
+                    ("artist", artist),
+                    ("timestamp", &timestamp),
+                ],
+            )
+            .await
+            .record("track.scrobble")?;
+
+        Ok(response.scrobbles.attr)
+    }
+
+    /// Sends a GET request, retrying network errors and 5xx/429 responses with exponential
+    /// backoff. Other responses (including Last.fm's 4xx errors) are returned as they are
+    async fn get_with_retry(&self, url: &str) -> Result<reqwest::Response, LastFMError> {
+        let mut delay = self.retry.delay;
+        let mut attempt = 0;
+        loop {
+            let response = self.client.get(url).send().await;
+            let retryable = match &response {
+                Ok(response) => {
+                    let status = response.status();
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => !e.is_builder(),
+            };
+
+            if !retryable || attempt >= self.retry.retries {
+                return response
+                    .attach_printable("Couldn't send request")
+                    .change_context(LastFMError::RequestError);
+            }
+
+            attempt += 1;
+            match &response {
+                Ok(response) => debug!(
+                    "LastFM responded with {}, retrying in {:?} (attempt {} of {})",
+                    response.status(),
+                    delay,
+                    attempt,
+                    self.retry.retries
+                ),
+                Err(e) => debug!(
+                    "Couldn't reach LastFM, retrying in {:?} (attempt {} of {}): {}",
+                    delay, attempt, self.retry.retries, e
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
         }
     }
 
+    /// Makes a request signed with the shared secret, as Last.fm requires for anything that acts
+    /// on a user's account
+    async fn signed_call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T, LastFMError> {
+        let secret = self
+            .secret
+            .as_deref()
+            .ok_or_else(|| Report::new(LastFMError::MissingSecret))?;
+
+        let mut params = params.to_vec();
+        params.push(("method", method));
+        params.push(("api_key", &self.key));
+        let signature = sign(&params, secret);
+        params.push(("api_sig", &signature));
+        // not part of the signature
+        params.push(("format", "json"));
+
+        debug!("Making signed {} request to LastFM", method);
+
+        let response = self
+            .client
+            .post(self.base_url.as_ref())
+            .form(&params)
+            .send()
+            .await
+            .attach_printable("Couldn't send request")
+            .change_context(LastFMError::RequestError)?
+            .json::<Value>()
+            .await
+            .attach_printable("Couldn't deserialise response")
+            .change_context(LastFMError::ParseError)?;
+
+        parse_response(response)
+    }
+
+    /// Whether the Last.fm user exists. Answers are cached, so checking everyone at startup and
+    /// again when they connect doesn't ask Last.fm twice. Errors aren't cached
     #[tracing::instrument(skip(self))]
     pub async fn does_user_exist(&self, user: &str) -> Result<bool, LastFMError> {
+        // Last.fm usernames aren't case sensitive
+        let key = user.to_lowercase();
+        if let Some((exists, checked_at)) = self.user_exists_cache.lock().unwrap().get(&key) {
+            if checked_at.elapsed() < self.user_exists_ttl {
+                debug!("Using the cached existence of {}", user);
+                return Ok(*exists);
+            }
+        }
+
+        let exists = self.get_user_info(user).await?.is_some();
+
+        if !self.user_exists_ttl.is_zero() {
+            let mut cache = self.user_exists_cache.lock().unwrap();
+            let ttl = self.user_exists_ttl;
+            cache.retain(|_, (_, checked_at)| checked_at.elapsed() < ttl);
+            cache.insert(key, (exists, Instant::now()));
+        }
+
+        Ok(exists)
+    }
+
+    /// The user's Last.fm profile, or `None` if Last.fm doesn't know them. Any other error Last.fm
+    /// answers with is returned, so an outage isn't mistaken for a missing user
+    #[tracing::instrument(skip(self))]
+    pub async fn get_user_info(&self, user: &str) -> Result<Option<LastfmUserInfo>, LastFMError> {
+        let user = match self.fetch_user_info(user).await {
+            Ok(response) => Ok(Some(response.user)),
+            // error 6: the user doesn't exist
+            Err(e) if matches!(e.current_context(), LastFMError::InvalidParameters) => Ok(None),
+            Err(e) => Err(e),
+        }
+        .record("user.getinfo")?;
+
+        Ok(user.map(LastfmUserInfo::from))
+    }
+
+    /// Checks the client's API key is accepted by making a request with it. Last.fm needs some
+    /// method to call, so this looks up a user.
+    #[tracing::instrument(skip(self))]
+    pub async fn is_key_valid(&self, user: &str) -> Result<bool, LastFMError> {
+        match self.fetch_user_info(user).await {
+            Ok(_) => Ok(true),
+            // the key was accepted, the user just doesn't exist
+            Err(e) if matches!(e.current_context(), LastFMError::InvalidParameters) => Ok(true),
+            Err(e) if matches!(e.current_context(), LastFMError::InvalidApiKey) => Ok(false),
+            Err(e) => Err(e),
+        }
+        .record("user.getinfo")
+    }
+
+    async fn fetch_user_info(&self, user: &str) -> Result<UserInfoResponse, LastFMError> {
         let mut cloned_url = self.base_url.clone();
 
         let url = cloned_url
@@ -56,37 +437,177 @@ impl Client {
         debug!("Requesting user info from LastFM: {}", url.as_ref());
 
         let response = self
-            .client
-            .get(url.as_ref())
-            .send()
-            .await
-            .attach_printable("Couldn't send request")
-            .change_context(LastFMError::RequestError)?
-            .json::<UserInfoResponse>()
+            .get_with_retry(url.as_ref())
+            .await?
+            .json::<Value>()
             .await
             .attach_printable("Couldn't deserialise response")
             .change_context(LastFMError::ParseError)?;
 
         debug!("Response form lastFM: {:?}", response);
 
-        Ok(response.user.is_some())
+        parse_response(response)
     }
 
+    /// The first page of the user's recent tracks, in Last.fm's default page size
     #[tracing::instrument(skip(self))]
     pub async fn get_user_recent_tracks(
         &self,
         user: &str,
     ) -> Result<Vec<RecentTrack>, LastFMError> {
+        Ok(self.get_recent_tracks_page(user, None, None).await?.tracks)
+    }
+
+    /// A page of the user's recent tracks, newest first. `limit` is the page size (at most 200),
+    /// and `page` starts at 1. Either is left to Last.fm's default when `None`
+    #[tracing::instrument(skip(self))]
+    pub async fn get_recent_tracks_page(
+        &self,
+        user: &str,
+        limit: Option<u32>,
+        page: Option<u32>,
+    ) -> Result<RecentTracksPage, LastFMError> {
+        self.fetch_recent_tracks(user, limit, page, None, None)
+            .await
+            .record("user.getrecenttracks")
+    }
+
+    /// Every track the user scrobbled between `from` and `to` (inclusive), newest first. Walks
+    /// through as many pages as it takes, so a long window means a lot of requests
+    #[tracing::instrument(skip(self))]
+    pub async fn get_scrobbles_between(
+        &self,
+        user: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RecentTrack>, LastFMError> {
+        let mut scrobbles = Vec::new();
+        let mut page = 1;
+        loop {
+            let recent_tracks = self
+                .fetch_recent_tracks(
+                    user,
+                    Some(MAX_RECENT_TRACKS_LIMIT),
+                    Some(page),
+                    Some(from),
+                    Some(to),
+                )
+                .await
+                .record("user.getrecenttracks")?;
+
+            // the now playing track is on every page, whatever the window
+            scrobbles.extend(
+                recent_tracks
+                    .tracks
+                    .into_iter()
+                    .filter(|track| track.scrobbled_at.is_some()),
+            );
+
+            if recent_tracks.page >= recent_tracks.total_pages {
+                return Ok(scrobbles);
+            }
+            page = recent_tracks.page + 1;
+        }
+    }
+
+    async fn fetch_recent_tracks(
+        &self,
+        user: &str,
+        limit: Option<u32>,
+        page: Option<u32>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<RecentTracksPage, LastFMError> {
+        let mut cloned_url = self.base_url.clone();
+        let mut query = cloned_url.query_pairs_mut();
+        query
+            .append_pair("method", "user.getrecenttracks")
+            .append_pair("user", user);
+        if self.extended {
+            query.append_pair("extended", "1");
+        }
+        if let Some(limit) = limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+        if let Some(page) = page {
+            query.append_pair("page", &page.to_string());
+        }
+        if let Some(from) = from {
+            query.append_pair("from", &from.timestamp().to_string());
+        }
+        if let Some(to) = to {
+            query.append_pair("to", &to.timestamp().to_string());
+        }
+        let url = query
+            .append_pair("api_key", &self.key)
+            .append_pair("format", "json")
+            .finish();
+
+        debug!("Requesting recent tracks from LastFM: {}", url.as_ref());
+
+        let response = self
+            .get_with_retry(url.as_ref())
+            .await?
+            .json::<Value>()
+            .await
+            .attach_printable("Couldn't deserialise response")
+            .change_context(LastFMError::ParseError)?;
+
+        debug!("Response from LastFM: {:?}", response);
+
+        let parsed_response: RecentTracksResponse = parse_response(response)?;
+        let recent_tracks = parsed_response.recenttracks;
+        let tracks: Vec<RecentTrack> = recent_tracks.track.into_iter().map(Into::into).collect();
+
+        // treated as the only page if Last.fm leaves out the pagination
+        let (page, total_pages, total) = match recent_tracks.attr {
+            Some(attr) => (
+                attr.page.parse().unwrap_or(1),
+                attr.total_pages.parse().unwrap_or(1),
+                attr.total.parse().unwrap_or(tracks.len() as u64),
+            ),
+            None => (1, 1, tracks.len() as u64),
+        };
+
+        Ok(RecentTracksPage {
+            tracks,
+            page,
+            total_pages,
+            total,
+        })
+    }
+
+    /// The user's most played artists over the period, most played first
+    #[tracing::instrument(skip(self))]
+    pub async fn get_user_top_artists(
+        &self,
+        user: &str,
+        period: Period,
+        limit: u32,
+    ) -> Result<Vec<TopArtist>, LastFMError> {
+        self.fetch_top_artists(user, period, limit)
+            .await
+            .record("user.gettopartists")
+    }
+
+    async fn fetch_top_artists(
+        &self,
+        user: &str,
+        period: Period,
+        limit: u32,
+    ) -> Result<Vec<TopArtist>, LastFMError> {
         let mut cloned_url = self.base_url.clone();
         let url = cloned_url
             .query_pairs_mut()
-            .append_pair("method", "user.getrecenttracks")
+            .append_pair("method", "user.gettopartists")
             .append_pair("user", user)
+            .append_pair("period", period.as_str())
+            .append_pair("limit", &limit.to_string())
             .append_pair("api_key", &self.key)
             .append_pair("format", "json")
             .finish();
 
-        debug!("Requesting recent tracks from LastFM: {}", url.as_ref());
+        debug!("Requesting top artists from LastFM: {}", url.as_ref());
 
         let response = self
             .client
@@ -102,115 +623,679 @@ impl Client {
 
         debug!("Response from LastFM: {:?}", response);
 
-        let parsed_response: RecentTracksResponse = from_value(response)
-            .attach_printable("Couldn't parse response")
+        let parsed_response: TopArtistsResponse = parse_response(response)?;
+
+        Ok(parsed_response
+            .topartists
+            .artist
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// How long a track is, if Last.fm knows. Many tracks have a duration of zero, which is
+    /// treated as unknown
+    #[tracing::instrument(skip(self))]
+    pub async fn get_track_duration(
+        &self,
+        artist: &str,
+        track: &str,
+    ) -> Result<Option<Duration>, LastFMError> {
+        self.fetch_track_duration(artist, track)
+            .await
+            .record("track.getinfo")
+    }
+
+    async fn fetch_track_duration(
+        &self,
+        artist: &str,
+        track: &str,
+    ) -> Result<Option<Duration>, LastFMError> {
+        let mut cloned_url = self.base_url.clone();
+        let url = cloned_url
+            .query_pairs_mut()
+            .append_pair("method", "track.getinfo")
+            .append_pair("artist", artist)
+            .append_pair("track", track)
+            .append_pair("api_key", &self.key)
+            .append_pair("format", "json")
+            .finish();
+
+        debug!("Requesting track info from LastFM: {}", url.as_ref());
+
+        let response = self
+            .get_with_retry(url.as_ref())
+            .await?
+            .json::<Value>()
+            .await
+            .attach_printable("Couldn't deserialise response")
+            .change_context(LastFMError::ParseError)?;
+
+        debug!("Response from LastFM: {:?}", response);
+
+        let parsed_response: TrackInfoResponse = parse_response(response)?;
+
+        Ok(parsed_response
+            .track
+            .duration
+            .parse()
+            .ok()
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis))
+    }
+
+    /// The user's most recently loved tracks, at most `limit` of them
+    #[tracing::instrument(skip(self))]
+    pub async fn get_loved_tracks(
+        &self,
+        user: &str,
+        limit: u32,
+    ) -> Result<Vec<LovedTrack>, LastFMError> {
+        self.fetch_loved_tracks(user, limit)
+            .await
+            .record("user.getlovedtracks")
+    }
+
+    async fn fetch_loved_tracks(
+        &self,
+        user: &str,
+        limit: u32,
+    ) -> Result<Vec<LovedTrack>, LastFMError> {
+        let mut cloned_url = self.base_url.clone();
+        let url = cloned_url
+            .query_pairs_mut()
+            .append_pair("method", "user.getlovedtracks")
+            .append_pair("user", user)
+            .append_pair("limit", &limit.to_string())
+            .append_pair("api_key", &self.key)
+            .append_pair("format", "json")
+            .finish();
+
+        debug!("Requesting loved tracks from LastFM: {}", url.as_ref());
+
+        let response = self
+            .get_with_retry(url.as_ref())
+            .await?
+            .json::<Value>()
+            .await
+            .attach_printable("Couldn't deserialise response")
             .change_context(LastFMError::ParseError)?;
 
+        debug!("Response from LastFM: {:?}", response);
+
+        let parsed_response: LovedTracksResponse = parse_response(response)?;
+
         Ok(parsed_response
-            .recenttracks
+            .lovedtracks
             .track
             .into_iter()
-            .map(Into::into)
+            .map(|track| LovedTrack {
+                name: track.name,
+                artist: track.artist.name,
+                mbid: track.mbid,
+            })
             .collect())
     }
 
+    /// The track the user is currently playing, if any
+    #[tracing::instrument(skip(self))]
+    pub async fn get_now_playing(&self, user: &str) -> Result<Option<RecentTrack>, LastFMError> {
+        let tracks = self.get_user_recent_tracks(user).await?;
+
+        Ok(pick_now_playing(tracks))
+    }
+
     // A stream of the currently playing track
     //
     // # Returns
-    // returns a new track if a user is playing something new, else returns None if the user has stopped playing anything
+    // returns a new track if a user is playing something new, else returns None if the user has stopped playing anything.
+    // The same track is returned again if the user replays it (it gets scrobbled while still being the now playing track)
+    //
+    // The polling interval is clamped to at least `MIN_POLLING_INTERVAL`
+    //
+    // Errors are passed on and polling carries on, unless the error is fatal (see
+    // `LastFMError::is_fatal`), which ends the stream
     #[tracing::instrument(skip(self))]
     pub fn stream_now_playing<'a>(
         &'a self,
         user: &'a str,
         polling_interval: Duration,
     ) -> impl Stream<Item = Result<Option<RecentTrack>, LastFMError>> + 'a {
-        let mut last_playing: Option<RecentTrack> = None;
-        try_stream! {
+        self.stream_now_playing_with(user, polling_interval, NowPlayingTracker::default())
+    }
+
+    /// Like [`Client::stream_now_playing`], but with a configured tracker, e.g. one that counts
+    /// recent scrobbles as playing (see [`NowPlayingTracker::with_latest_scrobble_window`])
+    #[tracing::instrument(skip(self, tracker))]
+    pub fn stream_now_playing_with<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+        mut tracker: NowPlayingTracker,
+    ) -> impl Stream<Item = Result<Option<RecentTrack>, LastFMError>> + 'a {
+        let polling_interval = polling_interval.max(MIN_POLLING_INTERVAL);
+        stream! {
             loop {
                 // wait before the next poll
                 tokio::time::sleep(polling_interval).await;
 
                 debug!("Polling LastFM for now playing track for {user}");
-                let tracks = self.get_user_recent_tracks(user).await?;
-
-                let now_playing = tracks
-                    .into_iter()
-                    .find(|track| track.is_now_playing);
-
-                debug!("User {user} is now playing: {:?}", now_playing);
-
-                match (now_playing, last_playing.clone()) {
-                    // the user is not playing anything nor has played anything before
-                    (None, None) => {
-                        debug!("User {user} is not playing anything");
-                    },
-                    // The user has started to play their first song
-                    (Some(playing), None) => {
-                        debug!("User {user} is now playing their first song: {playing}");
-                        last_playing = Some(playing.clone());
-                        yield Some(playing);
-                    },
-                    // The user has stopped playing anything
-                    (None, Some(_)) => {
-                        debug!("User {user} has stopped playing anything");
-                        last_playing = None;
-                        yield None;
-                    },
-                    // The user is playing a new track
-                    (Some(playing), Some(last)) => {
-                        debug!("User {user} is now playing a new track: {playing}. Checking if it's different from the last track: {last}");
-                        // make sure the mbid is not empty before checking if they're different
-                        if playing.mbid != "" {
-                            if playing.mbid != last.mbid {
-                                last_playing = Some(playing.clone());
-                                yield Some(playing);
-                            }
-                        } else if playing.name != last.name {
-                            last_playing = Some(playing.clone());
-                            yield Some(playing);
+                match self.get_user_recent_tracks(user).await {
+                    Ok(tracks) => {
+                        if let Some(change) = tracker.update(tracks) {
+                            yield Ok(change);
+                        }
+                    }
+                    Err(e) => {
+                        let fatal = e.current_context().is_fatal();
+                        yield Err(e);
+                        if fatal {
+                            break;
                         }
-                    },
+                    }
                 }
+            }
+        }
+    }
+
+    /// Like [`Client::stream_now_playing`], but also reports each track once it's been scrobbled,
+    /// so finishing a track can be told apart from starting the next one.
+    ///
+    /// The polling interval is clamped to at least `MIN_POLLING_INTERVAL`, and errors are
+    /// handled the same way
+    #[tracing::instrument(skip(self))]
+    pub fn stream_events<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> impl Stream<Item = Result<PlaybackEvent, LastFMError>> + 'a {
+        let polling_interval = polling_interval.max(MIN_POLLING_INTERVAL);
+        let mut tracker = PlaybackTracker::default();
+        stream! {
+            loop {
+                // wait before the next poll
+                tokio::time::sleep(polling_interval).await;
 
-                continue;
+                debug!("Polling LastFM for playback events for {user}");
+                match self.get_user_recent_tracks(user).await {
+                    Ok(tracks) => {
+                        for event in tracker.update(tracks) {
+                            yield Ok(event);
+                        }
+                    }
+                    Err(e) => {
+                        let fatal = e.current_context().is_fatal();
+                        yield Err(e);
+                        if fatal {
+                            break;
+                        }
+                    }
+                }
             }
         }
     }
 }
 
-nest! {
-    #[derive(serde::Deserialize, Debug)]*
+/// Something that happened in a user's listening, from [`Client::stream_events`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlaybackEvent {
+    /// The user started playing a track, or replayed the same one
+    NowPlaying(RecentTrack),
+    /// A track finished and was scrobbled
+    Scrobbled(RecentTrack),
+    /// The user stopped playing anything
+    Stopped,
+}
+
+/// Works out [`PlaybackEvent`]s from successive polls of a user's recent tracks, the same way
+/// [`NowPlayingTracker`] does for the now playing track alone.
+#[derive(Debug, Default)]
+pub struct PlaybackTracker {
+    now_playing: NowPlayingTracker,
+    // when the most recently completed scrobble happened, as of the last poll
+    last_scrobbled_at: Option<DateTime<Utc>>,
+    // scrobbles from before the first poll are history, not events
+    polled: bool,
+}
+
+impl PlaybackTracker {
+    /// Feeds in the latest recent tracks, returning what happened since the last poll. Scrobbles
+    /// come first, oldest first, followed by any change to the now playing track.
+    pub fn update(&mut self, tracks: Vec<RecentTrack>) -> Vec<PlaybackEvent> {
+        let scrobbles = tracks.iter().filter(|track| !track.is_now_playing);
+
+        let mut events: Vec<_> = if self.polled {
+            scrobbles
+                .clone()
+                .filter(|track| track.scrobbled_at > self.last_scrobbled_at)
+                .rev()
+                .cloned()
+                .map(PlaybackEvent::Scrobbled)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        if let Some(latest) = scrobbles.filter_map(|track| track.scrobbled_at).max() {
+            self.last_scrobbled_at = self.last_scrobbled_at.max(Some(latest));
+        }
+        self.polled = true;
+
+        match self.now_playing.update(tracks) {
+            Some(Some(track)) => events.push(PlaybackEvent::NowPlaying(track)),
+            Some(None) => events.push(PlaybackEvent::Stopped),
+            None => {}
+        }
+
+        events
+    }
+}
+
+/// Works out when a user's now playing track changes from successive polls of their recent
+/// tracks. This is what [`Client::stream_now_playing`] uses, for callers that poll Last.fm
+/// themselves.
+#[derive(Debug, Default)]
+pub struct NowPlayingTracker {
+    last_playing: Option<RecentTrack>,
+    // when the most recently completed scrobble happened, as of the last poll
+    last_scrobbled_at: Option<DateTime<Utc>>,
+    // how recent the latest scrobble has to be to stand in for a missing now playing track
+    latest_scrobble_window: Option<Duration>,
+}
+
+impl NowPlayingTracker {
+    /// Treats the latest scrobble as the now playing track when no track is flagged as now
+    /// playing, as long as it was scrobbled within `window`. For users scrobbling from services
+    /// that never send now playing updates
+    pub fn with_latest_scrobble_window(mut self, window: Duration) -> Self {
+        self.latest_scrobble_window = Some(window);
+        self
+    }
+
+    /// Feeds in the latest recent tracks.
+    ///
+    /// Returns `Some(Some(track))` if the user started playing something new (or replayed the
+    /// same track), `Some(None)` if they stopped playing, and `None` if nothing changed.
+    pub fn update(&mut self, tracks: Vec<RecentTrack>) -> Option<Option<RecentTrack>> {
+        self.update_at(tracks, Utc::now())
+    }
+
+    fn update_at(
+        &mut self,
+        tracks: Vec<RecentTrack>,
+        now: DateTime<Utc>,
+    ) -> Option<Option<RecentTrack>> {
+        let latest_scrobble = tracks.iter().find(|track| !track.is_now_playing).cloned();
+        let now_playing = pick_now_playing(tracks).or_else(|| {
+            let window = TimeDelta::from_std(self.latest_scrobble_window?).ok()?;
+            latest_scrobble
+                .clone()
+                .filter(|scrobble| scrobble.scrobbled_at.is_some_and(|at| now - at <= window))
+        });
+
+        debug!("Now playing: {:?}", now_playing);
+
+        // a track that was scrobbled since the last poll while still being the now playing
+        // track means the user is playing it on loop
+        let replayed = |playing: &RecentTrack| {
+            latest_scrobble.as_ref().is_some_and(|scrobble| {
+                scrobble.is_same_track(playing) && scrobble.scrobbled_at > self.last_scrobbled_at
+            })
+        };
+
+        let change = match (now_playing, &self.last_playing) {
+            // the user is not playing anything nor has played anything before
+            (None, None) => None,
+            // The user has started to play their first song
+            (Some(playing), None) => {
+                debug!("Now playing their first song: {playing}");
+                Some(Some(playing))
+            }
+            // The user has stopped playing anything
+            (None, Some(_)) => {
+                debug!("Stopped playing anything");
+                Some(None)
+            }
+            // The user is playing a new track
+            (Some(playing), Some(last)) => {
+                if !playing.is_same_track(last) {
+                    debug!("Now playing a new track: {playing}");
+                    Some(Some(playing))
+                } else if replayed(&playing) {
+                    debug!("Playing {playing} on loop");
+                    Some(Some(playing))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(playing) = &change {
+            self.last_playing.clone_from(playing);
+        }
+        self.last_scrobbled_at = latest_scrobble.and_then(|scrobble| scrobble.scrobbled_at);
+
+        change
+    }
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
     /// Last.fm API response for the `user.getrecenttracks` method.
     /// Limited to only the fields we care about.
     struct RecentTracksResponse {
         recenttracks: struct RecentTracksInner {
+            /// Which page this is. Last.fm sends these numbers as strings
+            #[serde(rename = "@attr")]
+            attr: Option<struct PageAttributes {
+                #[serde(default)]
+                page: String,
+                #[serde(rename = "totalPages", default)]
+                total_pages: String,
+                #[serde(default)]
+                total: String,
+            }>,
             track: Vec<struct Track {
                 name: String,
                 mbid: String,
+                /// Extended responses send a richer object, with `name` in place of `#text`
                 artist: struct Artist {
-                    #[serde(rename = "#text")]
+                    #[serde(rename = "#text", alias = "name")]
                     text: String,
+                    #[serde(default)]
+                    mbid: String,
+                    /// Only present in extended responses
+                    #[serde(default)]
+                    image: Vec<Image>,
                 },
                 album: struct Album {
                     #[serde(rename = "#text")]
                     text: String,
                 },
+                /// The album art in a few sizes, smallest first
+                #[serde(default)]
+                image: Vec<struct Image {
+                    /// Last.fm sends an empty string for missing art, which shouldn't fail the
+                    /// whole response
+                    #[serde(rename = "#text", default, deserialize_with = "lenient_url")]
+                    url: Option<Url>,
+                    size: String,
+                }>,
+                /// `"1"` if the user loved the track. Only present in extended responses
+                loved: Option<String>,
+                /// Only present on tracks that have been scrobbled
+                date: Option<struct TrackDate {
+                    uts: String,
+                }>,
+                /// Last.fm puts more than just `nowplaying` in here (e.g. `rank` on some
+                /// methods), so only the `nowplaying` key is looked at.
                 #[serde(rename = "@attr")]
                 attr: Option<struct TrackAttributes {
-                    #[serde(rename = "nowplaying")]
-                    now_playing: Option<String>,
+                    #[serde(rename = "nowplaying", default, deserialize_with = "lenient_bool")]
+                    now_playing: bool,
                 }>,
             }>,
         },
     }
 }
 
+/// An error Last.fm answered with instead of the expected response
+#[derive(serde::Deserialize, Debug)]
+struct ErrorResponse {
+    error: u32,
+    message: String,
+}
+
+impl ErrorResponse {
+    fn into_report(self) -> Report<LastFMError> {
+        let context = match self.error {
+            6 => LastFMError::InvalidParameters,
+            8 => LastFMError::OperationFailed,
+            // 10 is an invalid API key, 26 a suspended one
+            10 | 26 => LastFMError::InvalidApiKey,
+            29 => LastFMError::RateLimited,
+            _ => LastFMError::ApiError,
+        };
+
+        Report::new(context)
+            .attach_printable(format!("Last.fm error {}: {}", self.error, self.message))
+    }
+}
+
+/// Either an error or the expected response. The error is tried first, since it'd otherwise be
+/// parsed as a response with every field missing
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum ApiResponse<T> {
+    Error(ErrorResponse),
+    Ok(T),
+}
+
+fn parse_response<T: serde::de::DeserializeOwned>(response: Value) -> Result<T, LastFMError> {
+    match from_value(response)
+        .attach_printable("Couldn't parse response")
+        .change_context(LastFMError::ParseError)?
+    {
+        ApiResponse::Error(error) => Err(error.into_report()),
+        ApiResponse::Ok(response) => Ok(response),
+    }
+}
+
+/// Signs request parameters: the md5 of every `name` and `value` sorted by name, followed by the
+/// shared secret
+fn sign(params: &[(&str, &str)], secret: &str) -> String {
+    let mut params = params.to_vec();
+    params.sort_unstable_by_key(|(name, _)| *name);
+
+    let mut hasher = Md5::new();
+    for (name, value) in params {
+        hasher.update(name);
+        hasher.update(value);
+    }
+    hasher.update(secret);
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// One page of a user's recent tracks, from [`Client::get_recent_tracks_page`]
+#[derive(Debug, Clone)]
+pub struct RecentTracksPage {
+    pub tracks: Vec<RecentTrack>,
+    /// Starts at 1
+    pub page: u32,
+    pub total_pages: u32,
+    /// How many tracks there are across every page
+    pub total: u64,
+}
+
+/// A track the user loved, from [`Client::get_loved_tracks`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LovedTrack {
+    pub name: String,
+    pub artist: String,
+    /// Empty if Last.fm doesn't know the track's MusicBrainz id
+    pub mbid: String,
+}
+
+impl LovedTrack {
+    /// Whether this is the same track, by mbid when both have one and by name otherwise
+    pub fn matches(&self, track: &RecentTrack) -> bool {
+        if !self.mbid.is_empty() && !track.mbid().is_empty() {
+            return self.mbid == track.mbid();
+        }
+
+        self.name.eq_ignore_ascii_case(track.name())
+            && self.artist.eq_ignore_ascii_case(track.artist())
+    }
+}
+
+/// A user's Last.fm profile, from [`Client::get_user_info`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastfmUserInfo {
+    pub name: String,
+    pub realname: Option<String>,
+    pub country: Option<String>,
+    /// How many tracks they've scrobbled
+    pub playcount: u64,
+    pub registered: Option<DateTime<Utc>>,
+    /// The largest profile picture
+    pub image_url: Option<Url>,
+}
+
+impl From<User> for LastfmUserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            name: user.name,
+            realname: Some(user.realname).filter(|realname| !realname.is_empty()),
+            // Last.fm sends "None" rather than leaving it out
+            country: Some(user.country).filter(|country| !country.is_empty() && country != "None"),
+            playcount: user.playcount.parse().unwrap_or_default(),
+            registered: user
+                .registered
+                .and_then(|registered| registered.unixtime.parse().ok())
+                .and_then(|unixtime| DateTime::from_timestamp(unixtime, 0)),
+            image_url: user.image.into_iter().rev().find_map(|image| image.url),
+        }
+    }
+}
+
+/// A user's permission for a client to act on their account
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Session {
+    /// The user's Last.fm username
+    pub name: String,
+    pub key: String,
+}
+
+/// How many scrobbles Last.fm took from a [`Client::scrobble`]. Scrobbles can be ignored e.g.
+/// for being too old or a duplicate
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrobbleCounts {
+    pub accepted: u32,
+    pub ignored: u32,
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
+    /// Last.fm API response for the `track.scrobble` method.
+    /// Limited to only the fields we care about.
+    struct ScrobbleResponse {
+        scrobbles: struct Scrobbles {
+            #[serde(rename = "@attr")]
+            attr: ScrobbleCounts,
+        },
+    }
+}
+
+/// Last.fm API response for the `auth.getSession` method
+#[derive(serde::Deserialize, Debug)]
+struct SessionResponse {
+    session: Session,
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
+    /// Last.fm API response for the `user.gettopartists` method.
+    /// Limited to only the fields we care about.
+    struct TopArtistsResponse {
+        topartists: struct TopArtistsInner {
+            artist: Vec<struct TopArtistEntry {
+                name: String,
+                mbid: String,
+                playcount: String,
+            }>,
+        },
+    }
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
+    /// Last.fm API response for the `track.getinfo` method.
+    /// Limited to only the fields we care about.
+    struct TrackInfoResponse {
+        track: struct TrackInfo {
+            /// In milliseconds, or "0" when unknown
+            #[serde(default)]
+            duration: String,
+        },
+    }
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
+    /// Last.fm API response for the `user.getlovedtracks` method.
+    /// Limited to only the fields we care about.
+    struct LovedTracksResponse {
+        lovedtracks: struct LovedTracksInner {
+            #[serde(default)]
+            track: Vec<struct LovedTrackEntry {
+                name: String,
+                #[serde(default)]
+                mbid: String,
+                artist: struct LovedTrackArtist {
+                    name: String,
+                },
+            }>,
+        },
+    }
+}
+
 nest! {
     #[derive(serde::Deserialize, Debug)]*
     /// Last.fm API response for the `user.getinfo` method.
     /// Limited to only the fields we care about.
     struct UserInfoResponse {
-        user: Option<struct User {}>,
+        user: struct User {
+            name: String,
+            #[serde(default)]
+            realname: String,
+            #[serde(default)]
+            country: String,
+            #[serde(default)]
+            playcount: String,
+            #[serde(default)]
+            image: Vec<Image>,
+            registered: Option<struct Registered {
+                unixtime: String,
+            }>,
+        },
+    }
+}
+
+/// Deserializes a URL, treating empty or malformed ones as missing
+fn lenient_url<'de, D>(deserializer: D) -> std::result::Result<Option<Url>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let url: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(url.and_then(|url| Url::parse(&url).ok()))
+}
+
+/// Deserializes a flag Last.fm sends in any of `"true"`, `"1"`, `true` or `1`. Anything else is
+/// false
+fn lenient_bool<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(i64),
+        Text(String),
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Some(Flag::Bool(flag)) => flag,
+        Some(Flag::Number(number)) => number == 1,
+        Some(Flag::Text(text)) => matches!(text.trim(), "true" | "1"),
+        None => false,
+    })
+}
+
+impl TrackAttributes {
+    fn is_now_playing(&self) -> bool {
+        self.now_playing
     }
 }
 
@@ -220,8 +1305,15 @@ pub struct RecentTrack {
     mbid: String,
     name: String,
     artist: String,
+    artist_mbid: String,
     album: String,
     is_now_playing: bool,
+    scrobbled_at: Option<DateTime<Utc>>,
+    is_loved: bool,
+    // every size of album art Last.fm has for the track
+    images: HashMap<ImageSize, Url>,
+    // every size of image Last.fm has for the artist. Only in extended responses
+    artist_images: HashMap<ImageSize, Url>,
 }
 
 impl fmt::Display for RecentTrack {
@@ -231,6 +1323,48 @@ impl fmt::Display for RecentTrack {
 }
 
 impl RecentTrack {
+    #[cfg(test)]
+    pub(crate) fn new(name: &str, artist: &str, album: &str) -> Self {
+        Self {
+            mbid: String::new(),
+            name: name.to_owned(),
+            artist: artist.to_owned(),
+            artist_mbid: String::new(),
+            album: album.to_owned(),
+            is_now_playing: true,
+            scrobbled_at: None,
+            is_loved: false,
+            images: HashMap::new(),
+            artist_images: HashMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_loved(self, is_loved: bool) -> Self {
+        Self { is_loved, ..self }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_image(mut self, size: ImageSize, url: Url) -> Self {
+        self.images.insert(size, url);
+        self
+    }
+
+    /// Whether the user loved the track on Last.fm
+    pub fn is_loved(&self) -> bool {
+        self.is_loved
+    }
+
+    /// The URL of the largest album art Last.fm has for the track
+    pub fn image_url(&self) -> Option<&Url> {
+        largest_image(&self.images)
+    }
+
+    /// The URL of the album art in the given size, if Last.fm has it in that size
+    pub fn image_url_for(&self, size: ImageSize) -> Option<&Url> {
+        self.images.get(&size)
+    }
+
     pub fn mbid(&self) -> &str {
         &self.mbid
     }
@@ -243,6 +1377,16 @@ impl RecentTrack {
         &self.artist
     }
 
+    /// The artist's MusicBrainz id. Empty if Last.fm doesn't know it
+    pub fn artist_mbid(&self) -> &str {
+        &self.artist_mbid
+    }
+
+    /// The URL of the largest image Last.fm has for the artist. Only fetched with extended data
+    pub fn artist_image_url(&self) -> Option<&Url> {
+        largest_image(&self.artist_images)
+    }
+
     pub fn album(&self) -> &str {
         &self.album
     }
@@ -250,6 +1394,39 @@ impl RecentTrack {
     pub fn is_now_playing(&self) -> bool {
         self.is_now_playing
     }
+
+    /// When the track was scrobbled. Tracks that are still playing usually don't have this
+    pub fn scrobbled_at(&self) -> Option<DateTime<Utc>> {
+        self.scrobbled_at
+    }
+
+    /// Whether both are the same song, ignoring when they were played
+    pub fn is_same_track(&self, other: &RecentTrack) -> bool {
+        // make sure the mbid is not empty before comparing with it
+        if !self.mbid.is_empty() {
+            self.mbid == other.mbid
+        } else {
+            self.name == other.name
+        }
+    }
+}
+
+/// Picks a single now playing track out of the recent tracks.
+///
+/// Users scrobbling from several devices can have more than one track flagged as now playing, so
+/// the one with the latest timestamp wins. Last.fm lists the newest tracks first, so ties (or no
+/// timestamps at all) go to whichever came first.
+fn pick_now_playing(tracks: Vec<RecentTrack>) -> Option<RecentTrack> {
+    tracks
+        .into_iter()
+        .filter(|track| track.is_now_playing)
+        .enumerate()
+        .max_by(|(a_index, a), (b_index, b)| {
+            a.scrobbled_at
+                .cmp(&b.scrobbled_at)
+                .then(b_index.cmp(a_index))
+        })
+        .map(|(_, track)| track)
 }
 
 impl From<Track> for RecentTrack {
@@ -258,21 +1435,139 @@ impl From<Track> for RecentTrack {
             name: track.name,
             mbid: track.mbid,
             artist: track.artist.text,
+            artist_mbid: track.artist.mbid,
             album: track.album.text,
-            is_now_playing: track.attr.map_or(false, |attr| {
-                attr.now_playing.unwrap_or_else(|| "false".to_string()) == "true"
-            }),
+            is_now_playing: track
+                .attr
+                .as_ref()
+                .is_some_and(TrackAttributes::is_now_playing),
+            scrobbled_at: track
+                .date
+                .and_then(|date| date.uts.parse().ok())
+                .and_then(|uts| DateTime::from_timestamp(uts, 0)),
+            is_loved: track.loved.as_deref() == Some("1"),
+            images: images(track.image),
+            artist_images: images(track.artist.image),
+        }
+    }
+}
+
+/// The URL of each image in a size we know, skipping missing ones
+fn images(images: Vec<Image>) -> HashMap<ImageSize, Url> {
+    images
+        .into_iter()
+        .filter_map(|image| Some((ImageSize::from_lastfm(&image.size)?, image.url?)))
+        .collect()
+}
+
+fn largest_image(images: &HashMap<ImageSize, Url>) -> Option<&Url> {
+    images
+        .iter()
+        .max_by_key(|(size, _)| **size)
+        .map(|(_, url)| url)
+}
+
+/// The sizes Last.fm has images in, smallest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ImageSize {
+    Small,
+    Medium,
+    Large,
+    ExtraLarge,
+    Mega,
+}
+
+impl ImageSize {
+    /// The size from Last.fm's `size` value, if it's one we know
+    fn from_lastfm(size: &str) -> Option<Self> {
+        match size {
+            "small" => Some(ImageSize::Small),
+            "medium" => Some(ImageSize::Medium),
+            "large" => Some(ImageSize::Large),
+            "extralarge" => Some(ImageSize::ExtraLarge),
+            "mega" => Some(ImageSize::Mega),
+            _ => None,
+        }
+    }
+}
+
+/// The time range top charts are calculated over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Overall,
+    SevenDay,
+    OneMonth,
+    ThreeMonth,
+    SixMonth,
+    TwelveMonth,
+}
+
+impl Period {
+    /// The `period` value Last.fm expects
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Overall => "overall",
+            Period::SevenDay => "7day",
+            Period::OneMonth => "1month",
+            Period::ThreeMonth => "3month",
+            Period::SixMonth => "6month",
+            Period::TwelveMonth => "12month",
+        }
+    }
+}
+
+impl serde::Serialize for Period {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Parsed artist from the `user.gettopartists` method.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TopArtist {
+    name: String,
+    playcount: u64,
+    mbid: String,
+}
+
+impl TopArtist {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn playcount(&self) -> u64 {
+        self.playcount
+    }
+
+    pub fn mbid(&self) -> &str {
+        &self.mbid
+    }
+}
+
+impl From<TopArtistEntry> for TopArtist {
+    fn from(artist: TopArtistEntry) -> Self {
+        Self {
+            name: artist.name,
+            playcount: artist.playcount.parse().unwrap_or_default(),
+            mbid: artist.mbid,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use dotenvy_macro::dotenv;
-
     use super::*;
 
-    const API_KEY: &str = dotenv!("LASTFM_API_KEY");
+    // every test talks to a local mock server (see `mock_server`), never the real API
+    const API_KEY: &str = "test-api-key";
+
+    /// A client sending its requests to `base_url`
+    fn mock_client(base_url: Url) -> Client {
+        Client::with_base_url(API_KEY.to_owned(), reqwest::Client::new(), base_url)
+    }
 
     #[tokio::test]
     async fn can_create_client() {
@@ -281,21 +1576,852 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn can_get_user_recent_tracks() {
-        let client = Client::new(API_KEY.to_owned(), reqwest::Client::new());
-        let tracks = client.get_user_recent_tracks("rj").await.unwrap();
-        assert!(!tracks.is_empty());
+    async fn now_playing_track_is_detected() {
+        let client = mock_client(
+            mock_server(
+                r##"{"recenttracks":{"track":[{"artist":{"mbid":"","#text":"Artist"},"image":[],"mbid":"","album":{"mbid":"","#text":"Album"},"name":"Playing","@attr":{"nowplaying":"true"},"url":"https://www.last.fm/music/Artist/_/Playing"},{"artist":{"mbid":"","#text":"Artist"},"image":[],"mbid":"","album":{"mbid":"","#text":"Album"},"name":"Played","url":"https://www.last.fm/music/Artist/_/Played","date":{"uts":"1700000000","#text":"14 Nov 2023, 22:13"}}],"@attr":{"user":"rj","totalPages":"1","page":"1","perPage":"2","total":"2"}}}"##,
+            )
+            .await,
+        );
+
+        let track = client.get_now_playing("rj").await.unwrap().unwrap();
+        assert_eq!(track.name(), "Playing");
+        assert!(track.is_now_playing());
+    }
+
+    #[tokio::test]
+    async fn stopped_playback_has_no_now_playing() {
+        let client = mock_client(
+            mock_server(
+                r##"{"recenttracks":{"track":[{"artist":{"mbid":"","#text":"Artist"},"image":[],"mbid":"","album":{"mbid":"","#text":"Album"},"name":"Played","url":"https://www.last.fm/music/Artist/_/Played","date":{"uts":"1700000000","#text":"14 Nov 2023, 22:13"}}],"@attr":{"user":"rj","totalPages":"1","page":"1","perPage":"1","total":"1"}}}"##,
+            )
+            .await,
+        );
+
+        assert!(client.get_now_playing("rj").await.unwrap().is_none());
     }
 
     #[tokio::test]
     async fn errors_on_nonexistent_user() {
+        let client = mock_client(
+            mock_server_responses(&[(
+                "404 Not Found",
+                r#"{"message":"User not found","error":6}"#,
+            )])
+            .await,
+        );
+
+        let tracks = client.get_user_recent_tracks("nobody").await;
+        assert!(matches!(
+            tracks.unwrap_err().current_context(),
+            LastFMError::InvalidParameters
+        ));
+    }
+
+    #[tokio::test]
+    async fn can_get_user_top_artists() {
+        let client = mock_client(
+            mock_server(
+                r##"{"topartists":{"artist":[{"name":"First","mbid":"","playcount":"20","@attr":{"rank":"1"}},{"name":"Second","mbid":"","playcount":"10","@attr":{"rank":"2"}}],"@attr":{"user":"rj","page":"1"}}}"##,
+            )
+            .await,
+        );
+
+        let artists = client
+            .get_user_top_artists("rj", Period::Overall, 2)
+            .await
+            .unwrap();
+        assert_eq!(artists.len(), 2);
+        assert_eq!(artists[1].name(), "Second");
+    }
+
+    #[test]
+    fn top_artists_are_parsed() {
+        let response: TopArtistsResponse = parse_response(serde_json::json!({
+            "topartists": {
+                "artist": [{
+                    "name": "Artist",
+                    "mbid": "an-mbid",
+                    "playcount": "1234",
+                    "url": "https://www.last.fm/music/Artist",
+                    "@attr": { "rank": "1" },
+                }],
+                "@attr": { "user": "rj", "page": "1" },
+            }
+        }))
+        .unwrap();
+
+        let artists: Vec<TopArtist> = response
+            .topartists
+            .artist
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        assert_eq!(artists.len(), 1);
+        assert_eq!(artists[0].name(), "Artist");
+        assert_eq!(artists[0].playcount(), 1234);
+        assert_eq!(artists[0].mbid(), "an-mbid");
+    }
+
+    #[test]
+    fn periods_serialize_to_api_values() {
+        assert_eq!(
+            serde_json::to_value(Period::SevenDay).unwrap(),
+            Value::from("7day")
+        );
+        assert_eq!(Period::TwelveMonth.as_str(), "12month");
+    }
+
+    /// Serves a single request with the given JSON body, returning the server's API URL
+    async fn mock_server(body: &'static str) -> Url {
+        mock_server_responses(&[("200 OK", body)]).await
+    }
+
+    /// Serves one request per response, in order, each on its own connection
+    async fn mock_server_responses(responses: &'static [(&'static str, &'static str)]) -> Url {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/2.0/", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                socket.read(&mut request).await.unwrap();
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn recent_tracks_pages_are_parsed() {
+        let base_url = mock_server(
+            r##"{"recenttracks":{"@attr":{"user":"rj","page":"2","perPage":"1","totalPages":"30","total":"30"},"track":[{"name":"Song","mbid":"","artist":{"name":"Artist"},"album":{"#text":"Album"},"date":{"uts":"1700000000"}}]}}"##,
+        )
+        .await;
+        let client = mock_client(base_url);
+
+        let page = client
+            .get_recent_tracks_page("rj", Some(1), Some(2))
+            .await
+            .unwrap();
+        assert_eq!(page.page, 2);
+        assert_eq!(page.total_pages, 30);
+        assert_eq!(page.total, 30);
+        assert_eq!(page.tracks.len(), 1);
+        assert_eq!(page.tracks[0].name(), "Song");
+    }
+
+    #[tokio::test]
+    async fn scrobbles_between_walk_every_page() {
+        let base_url = mock_server_responses(&[
+            (
+                "200 OK",
+                r##"{"recenttracks":{"@attr":{"user":"rj","page":"1","perPage":"200","totalPages":"2","total":"2"},"track":[{"name":"Playing","mbid":"","artist":{"name":"Artist"},"album":{"#text":"Album"},"@attr":{"nowplaying":"true"}},{"name":"Newer","mbid":"","artist":{"name":"Artist"},"album":{"#text":"Album"},"date":{"uts":"1700000200"}}]}}"##,
+            ),
+            (
+                "200 OK",
+                r##"{"recenttracks":{"@attr":{"user":"rj","page":"2","perPage":"200","totalPages":"2","total":"2"},"track":[{"name":"Older","mbid":"","artist":{"name":"Artist"},"album":{"#text":"Album"},"date":{"uts":"1700000100"}}]}}"##,
+            ),
+        ])
+        .await;
+        let client = mock_client(base_url);
+
+        let scrobbles = client
+            .get_scrobbles_between(
+                "rj",
+                DateTime::from_timestamp(1700000000, 0).unwrap(),
+                DateTime::from_timestamp(1700000300, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            scrobbles.iter().map(RecentTrack::name).collect::<Vec<_>>(),
+            vec!["Newer", "Older"]
+        );
+        assert_eq!(
+            scrobbles[1].scrobbled_at(),
+            DateTime::from_timestamp(1700000100, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_image_urls_dont_fail_the_response() {
+        let base_url = mock_server(
+            r##"{"recenttracks":{"track":[{"artist":{"mbid":"","#text":"Artist"},"streamable":"0","image":[{"size":"small","#text":""},{"size":"medium","#text":""},{"size":"large","#text":""},{"size":"extralarge","#text":""}],"mbid":"","album":{"mbid":"","#text":"Album"},"name":"No Art","@attr":{"nowplaying":"true"},"url":"https://www.last.fm/music/Artist/_/No+Art"},{"artist":{"mbid":"","#text":"Artist"},"streamable":"0","image":[{"size":"small","#text":"https://lastfm.freetls.fastly.net/i/u/34s/art.png"},{"size":"medium","#text":"https://lastfm.freetls.fastly.net/i/u/64s/art.png"},{"size":"large","#text":""},{"size":"extralarge","#text":""}],"mbid":"","album":{"mbid":"","#text":"Album"},"name":"Some Art","url":"https://www.last.fm/music/Artist/_/Some+Art","date":{"uts":"1700000000","#text":"14 Nov 2023, 22:13"}}],"@attr":{"user":"rj","totalPages":"1","page":"1","perPage":"2","total":"2"}}}"##,
+        )
+        .await;
+        let client = mock_client(base_url);
+
+        let tracks = client.get_user_recent_tracks("rj").await.unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].image_url(), None);
+        // falls back to the largest size that has a URL
+        assert_eq!(
+            tracks[1].image_url().map(Url::as_str),
+            Some("https://lastfm.freetls.fastly.net/i/u/64s/art.png")
+        );
+    }
+
+    #[tokio::test]
+    async fn loved_tracks_are_parsed() {
+        let base_url = mock_server(
+            r##"{"lovedtracks":{"track":[{"artist":{"url":"https://www.last.fm/music/Artist","name":"Artist","mbid":""},"date":{"uts":"1700000000","#text":"14 Nov 2023, 22:13"},"mbid":"loved-mbid","url":"https://www.last.fm/music/Artist/_/Song","name":"Song","image":[{"size":"small","#text":""}],"streamable":{"fulltrack":"0","#text":"0"}}],"@attr":{"user":"rj","totalPages":"1","page":"1","perPage":"50","total":"1"}}}"##,
+        )
+        .await;
+        let client = mock_client(base_url);
+
+        let loved = client.get_loved_tracks("rj", 50).await.unwrap();
+        assert_eq!(
+            loved,
+            vec![LovedTrack {
+                name: "Song".to_owned(),
+                artist: "Artist".to_owned(),
+                mbid: "loved-mbid".to_owned(),
+            }]
+        );
+
+        assert!(loved[0].matches(&RecentTrack::new("song", "artist", "Album")));
+        assert!(!loved[0].matches(&RecentTrack::new("Other", "Artist", "Album")));
+    }
+
+    #[tokio::test]
+    async fn track_durations_are_parsed() {
+        let base_url = mock_server_responses(&[
+            ("200 OK", r#"{"track":{"name":"Song","duration":"215000"}}"#),
+            ("200 OK", r#"{"track":{"name":"Song","duration":"0"}}"#),
+        ])
+        .await;
+        let client = mock_client(base_url);
+
+        assert_eq!(
+            client.get_track_duration("Artist", "Song").await.unwrap(),
+            Some(Duration::from_secs(215))
+        );
+        assert_eq!(
+            client.get_track_duration("Artist", "Song").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn user_info_is_parsed() {
+        let base_url = mock_server_responses(&[
+            (
+                "200 OK",
+                r##"{"user":{"name":"RJ","realname":"Richard Jones","country":"United Kingdom","playcount":"150316","image":[{"size":"small","#text":"https://lastfm.freetls.fastly.net/i/u/34s/rj.png"},{"size":"extralarge","#text":"https://lastfm.freetls.fastly.net/i/u/300x300/rj.png"}],"registered":{"unixtime":"1037793040","#text":1037793040}}}"##,
+            ),
+            (
+                "200 OK",
+                r##"{"user":{"name":"quiet","realname":"","country":"None","playcount":"0","image":[{"size":"small","#text":""}]}}"##,
+            ),
+            ("200 OK", r#"{"error":6,"message":"User not found"}"#),
+        ])
+        .await;
+        let client = mock_client(base_url);
+
+        assert_eq!(
+            client.get_user_info("rj").await.unwrap(),
+            Some(LastfmUserInfo {
+                name: "RJ".to_owned(),
+                realname: Some("Richard Jones".to_owned()),
+                country: Some("United Kingdom".to_owned()),
+                playcount: 150_316,
+                registered: DateTime::from_timestamp(1_037_793_040, 0),
+                image_url: Some(
+                    Url::parse("https://lastfm.freetls.fastly.net/i/u/300x300/rj.png").unwrap()
+                ),
+            })
+        );
+
+        let quiet = client.get_user_info("quiet").await.unwrap().unwrap();
+        assert_eq!(quiet.realname, None);
+        assert_eq!(quiet.country, None);
+        assert_eq!(quiet.image_url, None);
+
+        assert_eq!(client.get_user_info("nobody").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn streams_keep_polling_after_errors() {
+        use futures::StreamExt;
+
+        let base_url = mock_server_responses(&[
+            ("503 Service Unavailable", "{}"),
+            (
+                "200 OK",
+                r##"{"recenttracks":{"track":[{"name":"Song","mbid":"","artist":{"#text":"Artist"},"album":{"#text":"Album"},"@attr":{"nowplaying":"true"}}]}}"##,
+            ),
+            ("200 OK", r#"{"error":6,"message":"User not found"}"#),
+        ])
+        .await;
+        let client = mock_client(base_url).with_retry(RequestRetry {
+            retries: 0,
+            delay: Duration::from_millis(1),
+        });
+
+        let stream = client.stream_now_playing("rj", Duration::ZERO);
+        futures::pin_mut!(stream);
+
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Some(RecentTrack::new("Song", "Artist", "Album"))
+        );
+        // the user not existing can't get better by polling again
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn user_existence_is_cached() {
+        // only one response, so a second request would fail
+        let base_url = mock_server_responses(&[("200 OK", r#"{"user":{"name":"RJ"}}"#)]).await;
+        let client = mock_client(base_url).with_retry(RequestRetry {
+            retries: 0,
+            delay: Duration::from_millis(1),
+        });
+
+        assert!(client.does_user_exist("RJ").await.unwrap());
+        assert!(client.does_user_exist("rj").await.unwrap());
+        // clients with another key share what's been checked
+        assert!(client
+            .with_key("user-key".to_owned())
+            .does_user_exist("rj")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn user_existence_is_checked_again_after_the_ttl() {
+        let base_url = mock_server_responses(&[
+            ("200 OK", r#"{"user":{"name":"rj"}}"#),
+            ("200 OK", r#"{"error":6,"message":"User not found"}"#),
+        ])
+        .await;
+        let client = mock_client(base_url).with_user_exists_ttl(Duration::ZERO);
+
+        assert!(client.does_user_exist("rj").await.unwrap());
+        assert!(!client.does_user_exist("rj").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn outages_arent_mistaken_for_missing_users() {
+        let base_url = mock_server_responses(&[
+            (
+                "200 OK",
+                r#"{"error":11,"message":"Service Offline - This service is temporarily offline. Try again later."}"#,
+            ),
+            ("200 OK", r#"{"user":{"name":"rj"}}"#),
+        ])
+        .await;
+        let client = mock_client(base_url);
+
+        let error = client.does_user_exist("rj").await.unwrap_err();
+        assert!(matches!(error.current_context(), LastFMError::ApiError));
+        // the error wasn't cached as the user not existing
+        assert!(client.does_user_exist("rj").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let base_url = mock_server_responses(&[
+            ("503 Service Unavailable", "{}"),
+            ("503 Service Unavailable", "{}"),
+            ("200 OK", r#"{"user":{"name":"rj"}}"#),
+        ])
+        .await;
+        let client = mock_client(base_url).with_retry(RequestRetry {
+            retries: 3,
+            delay: Duration::from_millis(1),
+        });
+
+        assert!(client.does_user_exist("rj").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_base_url() {
+        let base_url = mock_server(
+            r##"{"recenttracks":{"track":[{"name":"Song","mbid":"","artist":{"#text":"Artist"},"album":{"#text":"Album"},"@attr":{"nowplaying":"true"}}]}}"##,
+        )
+        .await;
+        let client = mock_client(base_url);
+
+        let playing = client.get_now_playing("rj").await.unwrap().unwrap();
+        assert_eq!(playing.name(), "Song");
+        assert_eq!(playing.artist(), "Artist");
+    }
+
+    #[tokio::test]
+    async fn scrobbles_report_counts() {
+        let base_url = mock_server(
+            r##"{"scrobbles":{"@attr":{"accepted":1,"ignored":0},"scrobble":{"track":{"#text":"Song"}}}}"##,
+        )
+        .await;
+        let client = mock_client(base_url).with_secret("secret".to_owned());
+
+        let counts = client
+            .scrobble("session-key", "Song", "Artist", 1_700_000_000)
+            .await
+            .unwrap();
+        assert_eq!(
+            counts,
+            ScrobbleCounts {
+                accepted: 1,
+                ignored: 0
+            }
+        );
+    }
+
+    #[test]
+    fn error_responses_are_mapped() {
+        let error = |code: u32| {
+            parse_response::<RecentTracksResponse>(serde_json::json!({
+                "error": code,
+                "message": "Something went wrong",
+            }))
+            .unwrap_err()
+        };
+
+        assert!(matches!(
+            error(6).current_context(),
+            LastFMError::InvalidParameters
+        ));
+        assert!(matches!(
+            error(8).current_context(),
+            LastFMError::OperationFailed
+        ));
+        assert!(matches!(
+            error(29).current_context(),
+            LastFMError::RateLimited
+        ));
+        assert!(matches!(
+            error(26).current_context(),
+            LastFMError::InvalidApiKey
+        ));
+        assert!(matches!(error(11).current_context(), LastFMError::ApiError));
+        assert!(format!("{:?}", error(29)).contains("Last.fm error 29: Something went wrong"));
+    }
+
+    #[tokio::test]
+    async fn validates_api_keys() {
+        let client = mock_client(
+            mock_server_responses(&[
+                ("200 OK", r#"{"user":{"name":"RJ"}}"#),
+                (
+                    "403 Forbidden",
+                    r#"{"message":"Invalid API key - You must be granted a valid key by last.fm","error":10}"#,
+                ),
+            ])
+            .await,
+        );
+        assert!(client.is_key_valid("rj").await.unwrap());
+
+        let client = client.with_key("not-a-real-api-key".to_owned());
+        assert!(!client.is_key_valid("rj").await.unwrap());
+    }
+
+    #[test]
+    fn signatures_match_lastfm_docs() {
+        let params = [
+            ("token", "a-token"),
+            ("method", "auth.getSession"),
+            ("api_key", "an-api-key"),
+        ];
+
+        // md5("api_keyan-api-keymethodauth.getSessiontokena-tokena-secret")
+        assert_eq!(
+            sign(&params, "a-secret"),
+            "d49d295bbe4c60e8bd3588701ae83367"
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_calls_need_a_secret() {
         let client = Client::new(API_KEY.to_owned(), reqwest::Client::new());
+        let result = client.love_track("sk", "Artist", "Track").await;
+        assert!(matches!(
+            result.unwrap_err().current_context(),
+            LastFMError::MissingSecret
+        ));
+    }
+
+    fn track_with_attr(attr: Value) -> RecentTrack {
+        let track: Track = from_value(serde_json::json!({
+            "name": "Song",
+            "mbid": "",
+            "artist": { "#text": "Artist" },
+            "album": { "#text": "Album" },
+            "@attr": attr,
+        }))
+        .unwrap();
+
+        track.into()
+    }
+
+    #[test]
+    fn now_playing_attr_is_detected() {
+        let track = track_with_attr(serde_json::json!({ "nowplaying": "true" }));
+        assert!(track.is_now_playing());
+    }
+
+    #[test]
+    fn every_now_playing_form_is_accepted() {
+        for now_playing in [
+            serde_json::json!("true"),
+            serde_json::json!("1"),
+            serde_json::json!(true),
+            serde_json::json!(1),
+        ] {
+            let track = track_with_attr(serde_json::json!({ "nowplaying": now_playing }));
+            assert!(track.is_now_playing(), "{now_playing} wasn't now playing");
+        }
+
+        for not_playing in [
+            serde_json::json!("false"),
+            serde_json::json!("0"),
+            serde_json::json!(false),
+            serde_json::json!(0),
+            serde_json::json!(null),
+        ] {
+            let track = track_with_attr(serde_json::json!({ "nowplaying": not_playing }));
+            assert!(!track.is_now_playing(), "{not_playing} was now playing");
+        }
+    }
+
+    fn playing_at(name: &str, uts: Option<i64>) -> RecentTrack {
+        RecentTrack {
+            scrobbled_at: uts.and_then(|uts| DateTime::from_timestamp(uts, 0)),
+            ..RecentTrack::new(name, "Artist", "Album")
+        }
+    }
+
+    #[test]
+    fn latest_of_multiple_now_playing_is_picked() {
+        let tracks = vec![
+            playing_at("Phone", Some(1_700_000_000)),
+            playing_at("Desktop", Some(1_700_000_100)),
+            RecentTrack {
+                is_now_playing: false,
+                ..playing_at("Finished", Some(1_700_000_200))
+            },
+        ];
+
+        assert_eq!(pick_now_playing(tracks).unwrap().name(), "Desktop");
+    }
+
+    #[test]
+    fn first_now_playing_is_picked_without_timestamps() {
+        let tracks = vec![playing_at("Newest", None), playing_at("Older", None)];
+
+        assert_eq!(pick_now_playing(tracks).unwrap().name(), "Newest");
+    }
+
+    #[test]
+    fn unrelated_attrs_are_not_now_playing() {
+        let track = track_with_attr(serde_json::json!({ "rank": "1", "page": "1" }));
+        assert!(!track.is_now_playing());
+    }
+
+    fn scrobbled_at(name: &str, uts: i64) -> RecentTrack {
+        RecentTrack {
+            is_now_playing: false,
+            ..playing_at(name, Some(uts))
+        }
+    }
+
+    #[test]
+    fn playback_tracker_reports_new_scrobbles() {
+        let mut tracker = PlaybackTracker::default();
+
+        // the first poll only reports what's playing, not the history
+        assert_eq!(
+            tracker.update(vec![playing_at("Second", None), scrobbled_at("First", 100)]),
+            vec![PlaybackEvent::NowPlaying(playing_at("Second", None))]
+        );
+
+        assert_eq!(
+            tracker.update(vec![
+                playing_at("Fourth", None),
+                scrobbled_at("Third", 300),
+                scrobbled_at("Second", 200),
+                scrobbled_at("First", 100),
+            ]),
+            vec![
+                PlaybackEvent::Scrobbled(scrobbled_at("Second", 200)),
+                PlaybackEvent::Scrobbled(scrobbled_at("Third", 300)),
+                PlaybackEvent::NowPlaying(playing_at("Fourth", None)),
+            ]
+        );
+
+        assert_eq!(
+            tracker.update(vec![
+                scrobbled_at("Fourth", 400),
+                scrobbled_at("Third", 300)
+            ]),
+            vec![
+                PlaybackEvent::Scrobbled(scrobbled_at("Fourth", 400)),
+                PlaybackEvent::Stopped,
+            ]
+        );
+        assert_eq!(tracker.update(vec![scrobbled_at("Fourth", 400)]), vec![]);
+    }
+
+    #[test]
+    fn tracker_reports_changes_only() {
+        let mut tracker = NowPlayingTracker::default();
+
+        assert_eq!(tracker.update(vec![]), None);
+        assert_eq!(
+            tracker.update(vec![playing_at("Song", None)]),
+            Some(Some(playing_at("Song", None)))
+        );
+        assert_eq!(tracker.update(vec![playing_at("Song", None)]), None);
+        assert_eq!(
+            tracker.update(vec![playing_at("Next", None)]),
+            Some(Some(playing_at("Next", None)))
+        );
+        assert_eq!(tracker.update(vec![]), Some(None));
+        assert_eq!(tracker.update(vec![]), None);
+    }
+
+    #[test]
+    fn latest_scrobble_stands_in_for_now_playing_when_opted_in() {
+        let at = |uts| DateTime::from_timestamp(uts, 0).unwrap();
+
+        let mut strict = NowPlayingTracker::default();
+        assert_eq!(
+            strict.update_at(vec![scrobbled_at("Song", 1000)], at(1060)),
+            None
+        );
+
+        let mut tracker =
+            NowPlayingTracker::default().with_latest_scrobble_window(Duration::from_secs(300));
+        assert_eq!(
+            tracker.update_at(vec![scrobbled_at("Song", 1000)], at(1060)),
+            Some(Some(scrobbled_at("Song", 1000)))
+        );
+        assert_eq!(
+            tracker.update_at(vec![scrobbled_at("Song", 1000)], at(1120)),
+            None
+        );
+        assert_eq!(
+            tracker.update_at(
+                vec![scrobbled_at("Next", 1200), scrobbled_at("Song", 1000)],
+                at(1210)
+            ),
+            Some(Some(scrobbled_at("Next", 1200)))
+        );
+        // too long ago to still be playing
+        assert_eq!(
+            tracker.update_at(vec![scrobbled_at("Next", 1200)], at(1600)),
+            Some(None)
+        );
+        // a track flagged as now playing still wins
+        assert_eq!(
+            tracker.update_at(
+                vec![playing_at("Live", None), scrobbled_at("Next", 1200)],
+                at(1610)
+            ),
+            Some(Some(playing_at("Live", None)))
+        );
+    }
+
+    #[test]
+    fn tracker_reports_looped_track() {
+        let mut tracker = NowPlayingTracker::default();
+
+        let first = vec![
+            playing_at("Song", None),
+            scrobbled_at("Other", 1_700_000_000),
+        ];
+        assert!(tracker.update(first.clone()).is_some());
+        assert_eq!(tracker.update(first), None);
+
+        // the same song got scrobbled while it's still playing
+        let looped = vec![
+            playing_at("Song", None),
+            scrobbled_at("Song", 1_700_000_200),
+        ];
+        assert_eq!(
+            tracker.update(looped.clone()),
+            Some(Some(playing_at("Song", None)))
+        );
+        assert_eq!(tracker.update(looped), None);
+    }
+
+    /// Recent tracks from a mocked `user.getrecenttracks` response
+    fn recent_tracks(response: Value) -> Vec<RecentTrack> {
+        parse_response::<RecentTracksResponse>(response)
+            .unwrap()
+            .recenttracks
+            .track
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    fn mbid_track(mbid: &str, name: &str, uts: Option<&str>) -> Value {
+        let mut track = serde_json::json!({
+            "name": name,
+            "mbid": mbid,
+            "artist": { "#text": "Artist" },
+            "album": { "#text": "Album" },
+        });
+        match uts {
+            Some(uts) => track["date"] = serde_json::json!({ "uts": uts }),
+            None => track["@attr"] = serde_json::json!({ "nowplaying": "true" }),
+        }
+        track
+    }
+
+    #[test]
+    fn tracker_reports_track_looped_by_mbid() {
+        let mut tracker = NowPlayingTracker::default();
+        let poll = |scrobbles: Vec<Value>| {
+            let mut tracks = vec![mbid_track("song-mbid", "Song", None)];
+            tracks.extend(scrobbles);
+            recent_tracks(serde_json::json!({ "recenttracks": { "track": tracks } }))
+        };
+
+        let first = poll(vec![mbid_track("other-mbid", "Other", Some("1700000000"))]);
+        let playing = tracker.update(first.clone()).unwrap().unwrap();
+        assert_eq!(playing.mbid(), "song-mbid");
+        assert_eq!(tracker.update(first), None);
+
+        // scrobbled once as it finished, and started again
+        let looped = poll(vec![mbid_track("song-mbid", "Song", Some("1700000200"))]);
+        assert_eq!(tracker.update(looped.clone()), Some(Some(playing.clone())));
+        assert_eq!(tracker.update(looped), None);
+
+        // and again
+        let looped_again = poll(vec![
+            mbid_track("song-mbid", "Song", Some("1700000400")),
+            mbid_track("song-mbid", "Song", Some("1700000200")),
+        ]);
+        assert_eq!(tracker.update(looped_again), Some(Some(playing)));
+
+        // a different song with the same name is still a new song
+        let new_song = recent_tracks(serde_json::json!({ "recenttracks": { "track": [
+            mbid_track("cover-mbid", "Song", None),
+        ] } }));
+        assert_eq!(
+            tracker.update(new_song).unwrap().unwrap().mbid(),
+            "cover-mbid"
+        );
+    }
+
+    fn track_with_images(images: Value) -> RecentTrack {
+        let track: Track = from_value(serde_json::json!({
+            "name": "Song",
+            "mbid": "",
+            "artist": { "#text": "Artist" },
+            "album": { "#text": "Album" },
+            "image": images,
+        }))
+        .unwrap();
+
+        track.into()
+    }
+
+    #[test]
+    fn empty_image_url_is_skipped() {
+        let track = track_with_images(serde_json::json!([
+            { "#text": "https://lastfm.freetls.fastly.net/i/u/34s/art.png", "size": "small" },
+            { "#text": "", "size": "extralarge" },
+        ]));
+
+        assert_eq!(
+            track.image_url().map(Url::as_str),
+            Some("https://lastfm.freetls.fastly.net/i/u/34s/art.png")
+        );
+    }
+
+    #[test]
+    fn every_available_size_is_kept() {
+        let track = track_with_images(serde_json::json!([
+            { "#text": "https://lastfm.freetls.fastly.net/i/u/34s/art.png", "size": "small" },
+            { "#text": "https://lastfm.freetls.fastly.net/i/u/174s/art.png", "size": "large" },
+            { "#text": "", "size": "extralarge" },
+            { "#text": "https://lastfm.freetls.fastly.net/i/u/unknown/art.png", "size": "" },
+        ]));
+
+        assert_eq!(
+            track.image_url_for(ImageSize::Small).map(Url::as_str),
+            Some("https://lastfm.freetls.fastly.net/i/u/34s/art.png")
+        );
+        assert_eq!(track.image_url_for(ImageSize::Medium), None);
+        assert_eq!(track.image_url_for(ImageSize::ExtraLarge), None);
+        // the largest size there is
+        assert_eq!(track.image_url(), track.image_url_for(ImageSize::Large));
+    }
+
+    #[test]
+    fn missing_art_has_no_image() {
+        let track = track_with_images(serde_json::json!([
+            { "#text": "", "size": "small" },
+            { "#text": "not a url", "size": "large" },
+        ]));
+        assert_eq!(track.image_url(), None);
+
+        // tracks without any image field still parse
+        assert_eq!(track_with_attr(serde_json::json!({})).image_url(), None);
+    }
+
+    #[test]
+    fn extended_tracks_are_parsed() {
+        let track: Track = from_value(serde_json::json!({
+            "name": "Song",
+            "mbid": "",
+            "artist": {
+                "name": "Artist",
+                "mbid": "0383dadf-2a4e-4d10-a46a-e9e041da8eb3",
+                "url": "https://www.last.fm/music/Artist",
+                "image": [
+                    { "#text": "https://lastfm.freetls.fastly.net/i/u/34s/small.png", "size": "small" },
+                    { "#text": "https://lastfm.freetls.fastly.net/i/u/300x300/large.png", "size": "extralarge" },
+                ],
+            },
+            "album": { "#text": "Album" },
+            "loved": "1",
+        }))
+        .unwrap();
+        let track: RecentTrack = track.into();
+
+        assert_eq!(track.artist(), "Artist");
+        assert_eq!(track.artist_mbid(), "0383dadf-2a4e-4d10-a46a-e9e041da8eb3");
+        assert_eq!(
+            track.artist_image_url().map(Url::as_str),
+            Some("https://lastfm.freetls.fastly.net/i/u/300x300/large.png")
+        );
+        assert!(track.is_loved());
+        assert!(!track_with_attr(serde_json::json!({})).is_loved());
+    }
 
-        // if somebody spites me and registers this username, you're quite mean :(
-        let tracks = client
-            .get_user_recent_tracks("asdklqweyhtuiowhfasdlfjasdiofho")
-            .await;
+    #[test]
+    fn plain_artists_have_no_extended_details() {
+        let track: Track = from_value(serde_json::json!({
+            "name": "Song",
+            "mbid": "",
+            "artist": { "#text": "Artist", "mbid": "" },
+            "album": { "#text": "Album" },
+        }))
+        .unwrap();
+        let track: RecentTrack = track.into();
 
-        assert!(tracks.is_err());
+        assert_eq!(track.artist(), "Artist");
+        assert_eq!(track.artist_mbid(), "");
+        assert_eq!(track.artist_image_url(), None);
+        assert!(!track.is_loved());
     }
 }
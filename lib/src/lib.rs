@@ -1,2 +1,5 @@
 pub mod lastfm;
 pub mod slack;
+pub mod source;
+pub mod spotify;
+pub mod status;
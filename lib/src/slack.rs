@@ -1,23 +1,131 @@
 use std::{
     error::Error,
     fmt::{self, Debug},
-    sync::Arc,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, Utc};
-use error_stack::{Result, ResultExt};
+use chrono::{DateTime, TimeDelta, Utc};
+use error_stack::{Report, Result, ResultExt};
 use slack_morphism::prelude::*;
-use tracing::debug;
+use tracing::{debug, warn};
+
+use crate::source::NowPlaying;
+
+/// The shortest status expiration we'll send Slack, so a status doesn't vanish the moment it's set
+const MIN_EXPIRATION_MARGIN_SECS: i64 = 5;
+/// How long a whole status update may take unless configured otherwise
+pub const DEFAULT_STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a user's Do Not Disturb state is reused for before asking Slack again
+const DND_CACHE_DURATION: Duration = Duration::from_secs(60);
+/// How long to wait before retrying a rate limited status update if Slack doesn't say
+const DEFAULT_RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+/// The longest a rate limited status update waits to be retried, so a huge `Retry-After` can't
+/// stall an updater
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(30);
+
+/// A Slack HTTP client. Its connection pool can be shared by any number of [`Client`]s, whatever
+/// team they're for, so creating one per user doesn't mean a new TLS handshake each time
+pub type SharedClient = Arc<SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>>>;
+
+/// Creates a [`SharedClient`] that rate limits its requests the way Slack asks
+pub fn new_shared_client() -> Result<SharedClient, SlackError> {
+    let connector = SlackClientHyperConnector::new()
+        .attach_printable("Failed to create HTTP connector for slack")
+        .change_context(SlackError::IoError)?
+        .with_rate_control(SlackApiRateControlConfig::new());
+
+    Ok(Arc::new(SlackClient::new(connector)))
+}
 
 pub struct Client {
-    client: Arc<SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>>>,
+    client: SharedClient,
     token: SlackApiToken,
+    respect_dnd: bool,
+    status_timeout: Duration,
+    // when the dnd state was fetched and whether it was active
+    dnd_cache: Mutex<Option<(Instant, bool)>>,
+}
+
+/// A status's text, emoji and expiration, as Slack reports them
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserStatus {
+    pub text: String,
+    pub emoji: String,
+    /// When Slack clears the status by itself, if ever. Statuses saved before this was stored
+    /// don't have it
+    #[serde(default)]
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+impl UserStatus {
+    pub fn of(profile: &SlackUserProfile) -> Self {
+        Self {
+            text: profile.status_text.clone().unwrap_or_default(),
+            emoji: profile
+                .status_emoji
+                .as_ref()
+                .map(|emoji| emoji.0.clone())
+                .unwrap_or_default(),
+            // Slack uses 0 for a status that doesn't expire
+            expiration: profile
+                .status_expiration
+                .as_ref()
+                .map(|expiration| expiration.0)
+                .filter(|expiration| expiration.timestamp() > 0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.emoji.is_empty()
+    }
+
+    /// Whether this shows the same as `other`, whenever either expires
+    pub fn shows_same(&self, other: &UserStatus) -> bool {
+        self.text == other.text && self.emoji == other.emoji
+    }
+
+    /// Whether Slack would have cleared the status by `now`
+    pub fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expiration.is_some_and(|expiration| expiration <= now)
+    }
+}
+
+/// Whether Slack shows a user as active or away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlackPresence {
+    Active,
+    Away,
+}
+
+impl SlackPresence {
+    /// The value `users.setPresence` expects. "auto" lets Slack decide from the user's activity
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Active => "auto",
+            Self::Away => "away",
+        }
+    }
+}
+
+/// A status that was set, and the one it replaced
+#[derive(Debug)]
+pub struct StatusUpdate {
+    pub previous: UserStatus,
+    pub profile: SlackUserProfile,
 }
 
 #[derive(Debug)]
 pub enum SlackError {
     ClientError,
     IoError,
+    MessageNotFound,
+    MissingScope,
+    /// Slack answered with a 429, and how long it asked us to wait if it said
+    RateLimited(Option<Duration>),
+    Timeout,
+    TokenExpired,
 }
 
 impl fmt::Display for SlackError {
@@ -25,49 +133,87 @@ impl fmt::Display for SlackError {
         match self {
             Self::ClientError => f.write_str("Slack client error"),
             Self::IoError => f.write_str("IO error"),
+            Self::MessageNotFound => f.write_str("Slack message not found"),
+            Self::MissingScope => f.write_str("The Slack token is missing a required scope"),
+            Self::RateLimited(_) => f.write_str("Slack rate limited the request"),
+            Self::Timeout => f.write_str("Slack took too long to respond"),
+            Self::TokenExpired => f.write_str("The Slack token has expired"),
         }
     }
 }
 impl Error for SlackError {}
 
 impl Client {
-    #[tracing::instrument]
+    /// A client for the token, using `shared` for its requests. Without one, it gets a connection
+    /// pool of its own
+    #[tracing::instrument(skip(shared))]
     pub fn new(
         token: impl Into<SlackApiTokenValue> + Debug,
         team_id: impl Into<SlackTeamId> + Debug,
+        shared: Option<SharedClient>,
     ) -> Result<Self, SlackError> {
-        debug!("Creating slack client");
-        let client = SlackClient::new(
-            SlackClientHyperConnector::new()
-                .attach_printable("Failed to create HTTP connector for slack")
-                .change_context(SlackError::IoError)?
-                .with_rate_control(SlackApiRateControlConfig::new()),
-        );
-        let token: SlackApiToken = SlackApiToken::new(token.into()).with_team_id(team_id.into());
-        debug!("Slack client created");
+        let client = match shared {
+            Some(shared) => shared,
+            None => {
+                debug!("Creating slack client");
+                new_shared_client()?
+            }
+        };
 
-        Ok(Self {
-            client: client.into(),
-            token,
-        })
+        Ok(Self::from_client(client, token, team_id))
     }
 
     #[tracing::instrument(skip(client))]
     pub fn from_client(
-        client: Arc<SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>>>,
+        client: SharedClient,
         token: impl Into<SlackApiTokenValue> + Debug,
         team_id: impl Into<SlackTeamId> + Debug,
     ) -> Self {
         Self {
             client,
             token: SlackApiToken::new(token.into()).with_team_id(team_id.into()),
+            respect_dnd: false,
+            status_timeout: DEFAULT_STATUS_TIMEOUT,
+            dnd_cache: Mutex::new(None),
+        }
+    }
+
+    /// The same client with another token for the same team, e.g. once a rotating token was
+    /// refreshed
+    pub fn with_token(&self, token: impl Into<SlackApiTokenValue> + Debug) -> Self {
+        Self {
+            client: self.client.clone(),
+            token: SlackApiToken {
+                token_value: token.into(),
+                ..self.token.clone()
+            },
+            respect_dnd: self.respect_dnd,
+            status_timeout: self.status_timeout,
+            dnd_cache: Mutex::new(*self.dnd_cache.lock().unwrap()),
         }
     }
 
+    /// Skip status updates while the user has Do Not Disturb on. Needs the `dnd:read` scope
+    pub fn with_respect_dnd(mut self, respect_dnd: bool) -> Self {
+        self.respect_dnd = respect_dnd;
+        self
+    }
+
     pub fn client(&self) -> &SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>> {
         &self.client
     }
 
+    /// The longest a whole status update (including its profile lookup) may take before giving up
+    /// with [`SlackError::Timeout`]
+    pub fn with_status_timeout(mut self, status_timeout: Duration) -> Self {
+        self.status_timeout = status_timeout;
+        self
+    }
+
+    /// Updates the user's status, returning the updated profile and the status it replaced.
+    ///
+    /// Returns `None` without touching the status if the client respects Do Not Disturb and the
+    /// user currently has it on.
     #[tracing::instrument(skip(self))]
     pub async fn update_user_status(
         &self,
@@ -75,7 +221,35 @@ impl Client {
         status_text: Option<impl Into<String> + Debug>,
         status_emoji: Option<impl Into<SlackEmoji> + Debug>,
         status_duration: Option<DateTime<Utc>>,
-    ) -> Result<SlackUserProfile, SlackError> {
+    ) -> Result<Option<StatusUpdate>, SlackError> {
+        let status_text: Option<String> = status_text.map(Into::into);
+        let status_emoji: Option<SlackEmoji> = status_emoji.map(Into::into);
+
+        retry_rate_limited(|| {
+            with_deadline(
+                self.status_timeout,
+                self.set_user_status(
+                    user_id.clone(),
+                    status_text.clone(),
+                    status_emoji.clone(),
+                    status_duration,
+                ),
+            )
+        })
+        .await
+    }
+
+    async fn set_user_status(
+        &self,
+        user_id: SlackUserId,
+        status_text: Option<impl Into<String> + Debug>,
+        status_emoji: Option<impl Into<SlackEmoji> + Debug>,
+        status_duration: Option<DateTime<Utc>>,
+    ) -> Result<Option<StatusUpdate>, SlackError> {
+        if self.skip_for_dnd(&user_id).await? {
+            return Ok(None);
+        }
+
         let session = self.client.open_session(&self.token);
 
         let user_request = SlackApiUsersProfileGetRequest::new().with_user(user_id);
@@ -83,16 +257,16 @@ impl Client {
         let user = session
             .users_profile_get(&user_request)
             .await
-            .attach_printable("Failed to get user profile")
-            .change_context(SlackError::ClientError)?;
+            .map_err(|e| client_error(e, "Failed to get user profile"))?;
         debug!("User profile: {:?}", user);
+        let previous = UserStatus::of(&user.profile);
 
         let user_update_request = SlackApiUsersProfileSetRequest::new(
             user.profile
                 .opt_status_emoji(status_emoji.map(Into::into))
-                .opt_status_text(status_text.map(Into::into))
+                .opt_status_text(status_text.map(|text| escape(&Into::<String>::into(text))))
                 .opt_status_expiration(
-                    status_duration.map(|duration| SlackDateTime::new(duration)),
+                    clamp_expiration(status_duration, Utc::now()).map(SlackDateTime::new),
                 ),
         );
 
@@ -101,11 +275,719 @@ impl Client {
         let updated = session
             .users_profile_set(&user_update_request)
             .await
-            .attach_printable("Failed to update user profile")
-            .change_context(SlackError::ClientError)?;
+            .map_err(|e| client_error(e, "Failed to update user profile"))?;
 
         debug!("Updated user profile to {:?}", updated.profile);
 
-        Ok(updated.profile)
+        Ok(Some(StatusUpdate {
+            previous,
+            profile: updated.profile,
+        }))
+    }
+
+    /// Sets the user's status without looking up their profile first, so it's a single call to
+    /// Slack. Use [`Client::update_user_status`] when the status being replaced is needed.
+    ///
+    /// Returns `None` without touching the status if the client respects Do Not Disturb and the
+    /// user currently has it on.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_status(
+        &self,
+        user_id: SlackUserId,
+        text: &str,
+        emoji: &str,
+        expiration: Option<DateTime<Utc>>,
+    ) -> Result<Option<SlackUserProfile>, SlackError> {
+        retry_rate_limited(|| {
+            with_deadline(
+                self.status_timeout,
+                self.set_status_only(user_id.clone(), text, emoji, expiration),
+            )
+        })
+        .await
+    }
+
+    async fn set_status_only(
+        &self,
+        user_id: SlackUserId,
+        text: &str,
+        emoji: &str,
+        expiration: Option<DateTime<Utc>>,
+    ) -> Result<Option<SlackUserProfile>, SlackError> {
+        if self.skip_for_dnd(&user_id).await? {
+            return Ok(None);
+        }
+
+        let session = self.client.open_session(&self.token);
+
+        // only the status fields are sent, so the rest of the profile is left alone
+        let user_update_request = SlackApiUsersProfileSetRequest::new(
+            SlackUserProfile::new()
+                .with_status_text(escape(text))
+                .with_status_emoji(SlackEmoji(emoji.to_owned()))
+                .with_status_expiration(SlackDateTime::new(
+                    clamp_expiration(expiration, Utc::now()).unwrap_or(DateTime::UNIX_EPOCH),
+                )),
+        );
+
+        debug!("Setting user status: {:?}", user_update_request);
+
+        let updated = session
+            .users_profile_set(&user_update_request)
+            .await
+            .map_err(|e| client_error(e, "Failed to update user profile"))?;
+
+        Ok(Some(updated.profile))
+    }
+
+    /// Whether a status update should be skipped because the user has Do Not Disturb on
+    async fn skip_for_dnd(&self, user_id: &SlackUserId) -> Result<bool, SlackError> {
+        if !self.respect_dnd {
+            return Ok(false);
+        }
+
+        match self.is_in_dnd(user_id).await {
+            Ok(true) => {
+                debug!("User is in Do Not Disturb, not updating their status");
+                Ok(true)
+            }
+            Ok(false) => Ok(false),
+            // missing dnd:read shouldn't stop statuses from being set
+            Err(e) if matches!(e.current_context(), SlackError::MissingScope) => {
+                warn!("Can't check Do Not Disturb without the dnd:read scope");
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Puts back a status saved from [`StatusUpdate::previous`] with its expiration, but only if
+    /// the user's status is still `expected` (the one we set). Returns whether the status was
+    /// restored, so a status the user changed themselves is never overwritten. A status that has
+    /// expired since it was saved shouldn't be put back (see [`UserStatus::has_expired`]).
+    #[tracing::instrument(skip(self))]
+    pub async fn restore_user_status(
+        &self,
+        user_id: SlackUserId,
+        status: &UserStatus,
+        expected: &UserStatus,
+    ) -> Result<bool, SlackError> {
+        with_deadline(
+            self.status_timeout,
+            self.put_back_status(user_id, status, expected),
+        )
+        .await
+    }
+
+    async fn put_back_status(
+        &self,
+        user_id: SlackUserId,
+        status: &UserStatus,
+        expected: &UserStatus,
+    ) -> Result<bool, SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        let user = session
+            .users_profile_get(&SlackApiUsersProfileGetRequest::new().with_user(user_id))
+            .await
+            .map_err(|e| client_error(e, "Failed to get user profile"))?;
+
+        let current = UserStatus::of(&user.profile);
+        if !current.shows_same(expected) {
+            debug!(
+                "Status changed to {:?} since it was set, not restoring it",
+                current
+            );
+            return Ok(false);
+        }
+
+        // the saved status came from Slack, so it's already escaped
+        let user_update_request = SlackApiUsersProfileSetRequest::new(
+            user.profile
+                .with_status_text(status.text.clone())
+                .with_status_emoji(SlackEmoji(status.emoji.clone()))
+                .with_status_expiration(SlackDateTime::new(
+                    status.expiration.unwrap_or(DateTime::UNIX_EPOCH),
+                )),
+        );
+
+        session
+            .users_profile_set(&user_update_request)
+            .await
+            .map_err(|e| client_error(e, "Failed to restore user profile"))?;
+
+        Ok(true)
+    }
+
+    /// Looks up a user. Needs the `users:read` scope
+    #[tracing::instrument(skip(self))]
+    pub async fn user_info(&self, user_id: SlackUserId) -> Result<SlackUser, SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        let response = session
+            .users_info(&SlackApiUsersInfoRequest::new(user_id))
+            .await
+            .map_err(|e| client_error(e, "Failed to get user info"))?;
+
+        Ok(response.user)
+    }
+
+    /// Whether the user currently has Do Not Disturb (or a snooze) on. The result is cached
+    /// briefly so checking it doesn't add an API call to every status update
+    #[tracing::instrument(skip(self))]
+    pub async fn is_in_dnd(&self, user_id: &SlackUserId) -> Result<bool, SlackError> {
+        if let Some((fetched_at, active)) = *self.dnd_cache.lock().unwrap() {
+            if fetched_at.elapsed() < DND_CACHE_DURATION {
+                return Ok(active);
+            }
+        }
+
+        let session = self.client.open_session(&self.token);
+
+        // slack-morphism doesn't wrap dnd.info
+        let params = vec![("user", Some(&user_id.0))];
+        let info: DndInfo = session
+            .http_session_api
+            .http_get("dnd.info", &params, None)
+            .await
+            .map_err(|e| client_error(e, "Failed to get Do Not Disturb info"))?;
+
+        let active = info.is_active(Utc::now().timestamp());
+        *self.dnd_cache.lock().unwrap() = Some((Instant::now(), active));
+
+        Ok(active)
+    }
+
+    #[tracing::instrument(skip(self, content))]
+    pub async fn post_message(
+        &self,
+        channel: SlackChannelId,
+        content: SlackMessageContent,
+    ) -> Result<SlackTs, SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        let response = session
+            .chat_post_message(&SlackApiChatPostMessageRequest::new(channel, content))
+            .await
+            .attach_printable("Failed to post message")
+            .change_context(SlackError::ClientError)?;
+
+        debug!("Posted message {:?}", response.ts);
+
+        Ok(response.ts)
+    }
+
+    /// Posts a track with its album art, for mirroring someone's music to a channel. Slack won't
+    /// take an image URL as a status emoji, so this is where the art gets shown
+    #[tracing::instrument(skip(self))]
+    pub async fn post_now_playing_message(
+        &self,
+        channel: SlackChannelId,
+        track: &NowPlaying,
+    ) -> Result<SlackTs, SlackError> {
+        self.post_message(channel, now_playing_content(track)).await
+    }
+
+    /// Replaces the content of a message we posted earlier.
+    ///
+    /// Returns [`SlackError::MessageNotFound`] if the message has since been deleted, so the
+    /// caller can decide whether to post it again.
+    #[tracing::instrument(skip(self, content))]
+    pub async fn update_message(
+        &self,
+        channel: SlackChannelId,
+        ts: SlackTs,
+        content: SlackMessageContent,
+    ) -> Result<SlackTs, SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        match session
+            .chat_update(&SlackApiChatUpdateRequest::new(
+                channel,
+                content,
+                ts.clone(),
+            ))
+            .await
+        {
+            Ok(response) => Ok(response.ts),
+            Err(SlackClientError::ApiError(err)) if err.code == "message_not_found" => {
+                Err(Report::new(SlackError::MessageNotFound)
+                    .attach_printable(format!("Message {} no longer exists", ts)))
+            }
+            Err(err) => Err(Report::new(err)
+                .attach_printable("Failed to update message")
+                .change_context(SlackError::ClientError)),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn pin_message(
+        &self,
+        channel: SlackChannelId,
+        ts: SlackTs,
+    ) -> Result<(), SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        session
+            .pins_add(&SlackApiPinsAddRequest::new(channel, ts))
+            .await
+            .attach_printable("Failed to pin message")
+            .change_context(SlackError::ClientError)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn add_reaction(
+        &self,
+        channel: SlackChannelId,
+        ts: SlackTs,
+        name: impl Into<SlackReactionName> + Debug,
+    ) -> Result<(), SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        session
+            .reactions_add(&SlackApiReactionsAddRequest::new(channel, name.into(), ts))
+            .await
+            .map_err(|e| client_error(e, "Failed to add reaction"))?;
+
+        Ok(())
+    }
+
+    /// Sets the token's user as active or away. Needs the `users:write` scope
+    #[tracing::instrument(skip(self))]
+    pub async fn set_presence(&self, presence: SlackPresence) -> Result<(), SlackError> {
+        let session = self.client.open_session(&self.token);
+
+        session
+            .users_set_presence(&SlackApiUsersSetPresenceRequest::new(
+                presence.as_str().into(),
+            ))
+            .await
+            .map_err(|e| client_error(e, "Failed to set presence"))?;
+
+        Ok(())
+    }
+
+    /// Revokes the client's token, e.g. after the app was uninstalled from a workspace
+    #[tracing::instrument(skip(self))]
+    pub async fn revoke_token(&self) -> Result<(), SlackError> {
+        #[derive(serde::Deserialize)]
+        struct AuthRevokeResponse {
+            revoked: bool,
+        }
+
+        let session = self.client.open_session(&self.token);
+
+        // slack-morphism doesn't wrap auth.revoke, but it takes no parameters
+        let params: Vec<(&str, Option<&str>)> = Vec::new();
+        let response: AuthRevokeResponse = session
+            .http_session_api
+            .http_get("auth.revoke", &params, None)
+            .await
+            .map_err(|e| client_error(e, "Failed to revoke token"))?;
+
+        debug!("Revoked token: {}", response.revoked);
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DndInfo {
+    dnd_enabled: bool,
+    #[serde(default)]
+    next_dnd_start_ts: i64,
+    #[serde(default)]
+    next_dnd_end_ts: i64,
+    // only included when asking about the token's own user
+    #[serde(default)]
+    snooze_enabled: bool,
+}
+
+impl DndInfo {
+    /// `dnd_enabled` only means a schedule is set up, so check we're inside it
+    fn is_active(&self, now: i64) -> bool {
+        self.snooze_enabled
+            || (self.dnd_enabled && self.next_dnd_start_ts <= now && now < self.next_dnd_end_ts)
+    }
+}
+
+/// The track, artist and album, with the album art alongside if the source has it
+pub fn now_playing_content(track: &NowPlaying) -> SlackMessageContent {
+    let mut text = format!("*{}*\n{}", escape(track.name()), escape(track.artist()));
+    if !track.album().is_empty() {
+        text.push_str(&format!("\n_{}_", escape(track.album())));
+    }
+
+    let mut section = SlackSectionBlock::new().with_text(SlackBlockMarkDownText::new(text).into());
+    if let Some(image_url) = track.image_url() {
+        section = section.with_accessory(
+            SlackBlockImageElement::new(
+                SlackImageUrlOrFile::ImageUrl {
+                    image_url: image_url.clone(),
+                },
+                format!("Album art for {}", track),
+            )
+            .into(),
+        );
+    }
+
+    SlackMessageContent::new()
+        .with_text(track.to_string())
+        .with_blocks(vec![section.into()])
+}
+
+/// Gives up on a request that hangs, so a stuck connection can't block its caller forever
+async fn with_deadline<T>(
+    deadline: Duration,
+    request: impl Future<Output = Result<T, SlackError>>,
+) -> Result<T, SlackError> {
+    tokio::time::timeout(deadline, request)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Report::new(SlackError::Timeout)
+                .attach_printable(format!("No response within {:?}", deadline)))
+        })
+}
+
+/// Runs a request, and if Slack rate limits it, waits as long as Slack asked and tries once more
+async fn retry_rate_limited<T, F, Fut>(mut request: F) -> Result<T, SlackError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SlackError>>,
+{
+    let result = request().await;
+
+    let retry_after = match &result {
+        Err(e) => match e.current_context() {
+            SlackError::RateLimited(retry_after) => *retry_after,
+            _ => return result,
+        },
+        Ok(_) => return result,
+    };
+
+    let delay = rate_limit_delay(retry_after);
+    warn!("Rate limited by Slack, retrying in {:?}", delay);
+    tokio::time::sleep(delay).await;
+
+    request().await
+}
+
+/// How long to wait out a rate limit, given Slack's `Retry-After`
+fn rate_limit_delay(retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or(DEFAULT_RATE_LIMIT_DELAY)
+        .min(MAX_RATE_LIMIT_DELAY)
+}
+
+/// Wraps a slack-morphism error, keeping `missing_scope` errors distinguishable so callers can
+/// skip optional features the app wasn't granted, `token_expired` ones so rotated tokens can be
+/// refreshed, and rate limits so they can be waited out
+fn client_error(err: SlackClientError, message: &'static str) -> Report<SlackError> {
+    let context = match &err {
+        SlackClientError::RateLimitError(rate_err) => SlackError::RateLimited(rate_err.retry_after),
+        SlackClientError::ApiError(api_err) if api_err.code == "missing_scope" => {
+            SlackError::MissingScope
+        }
+        SlackClientError::ApiError(api_err) if api_err.code == "token_expired" => {
+            SlackError::TokenExpired
+        }
+        _ => SlackError::ClientError,
+    };
+
+    Report::new(err)
+        .attach_printable(message)
+        .change_context(context)
+}
+
+/// Whether a user is a bot or app user rather than a person. These can't have a status set
+pub fn is_bot(user: &SlackUser) -> bool {
+    // slackbot isn't flagged as a bot
+    user.flags.is_bot == Some(true)
+        || user.flags.is_app_user == Some(true)
+        || user.id.0 == "USLACKBOT"
+}
+
+/// Escapes the characters Slack treats as markup in text (`&`, `<` and `>`), so track names like
+/// `<3 & Stuff` show up literally instead of being read as links or mentions.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Makes sure an expiration isn't in the past (or so close that Slack would clear the status
+/// straight away), which can happen with clock skew or slow requests.
+///
+/// Expirations that have already passed are dropped, and ones that are about to are pushed back
+/// by a small margin.
+fn clamp_expiration(
+    expiration: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    expiration
+        .filter(|expiration| *expiration > now)
+        .map(|expiration| expiration.max(now + TimeDelta::seconds(MIN_EXPIRATION_MARGIN_SECS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lastfm::{ImageSize, RecentTrack};
+
+    #[test]
+    fn rate_limit_delay_follows_retry_after() {
+        assert_eq!(
+            rate_limit_delay(Some(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(rate_limit_delay(None), DEFAULT_RATE_LIMIT_DELAY);
+        assert_eq!(
+            rate_limit_delay(Some(Duration::from_secs(600))),
+            MAX_RATE_LIMIT_DELAY
+        );
+    }
+
+    #[test]
+    fn rate_limit_errors_keep_their_delay() {
+        let err = SlackClientError::RateLimitError(
+            SlackRateLimitError::new().with_retry_after(Duration::from_secs(5)),
+        );
+
+        assert!(matches!(
+            client_error(err, "Failed to update user profile").current_context(),
+            SlackError::RateLimited(Some(delay)) if *delay == Duration::from_secs(5)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried_once() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = retry_rate_limited(|| async {
+            let attempt = attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if attempt == 0 {
+                Err(Report::new(SlackError::RateLimited(Some(
+                    Duration::from_millis(1),
+                ))))
+            } else {
+                Ok(attempt)
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn now_playing_messages_show_the_album_art() {
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", "Album").with_image(
+            ImageSize::ExtraLarge,
+            url::Url::parse("https://lastfm.freetls.fastly.net/i/u/300x300/art.png").unwrap(),
+        ));
+
+        let content = now_playing_content(&track);
+        let Some([SlackBlock::Section(section)]) = content.blocks.as_deref() else {
+            panic!("Expected a single section, got {:?}", content.blocks);
+        };
+
+        assert_eq!(
+            section.text,
+            Some(SlackBlockMarkDownText::new("*Song*\nArtist\n_Album_".to_owned()).into())
+        );
+        assert!(matches!(
+            &section.accessory,
+            Some(SlackSectionBlockElement::Image(image))
+                if image.image_url_or_file.image_url().map(url::Url::as_str)
+                    == Some("https://lastfm.freetls.fastly.net/i/u/300x300/art.png")
+        ));
+    }
+
+    #[test]
+    fn now_playing_messages_without_art_have_no_image() {
+        let content = now_playing_content(&RecentTrack::new("Song", "Artist", "").into());
+        let Some([SlackBlock::Section(section)]) = content.blocks.as_deref() else {
+            panic!("Expected a single section, got {:?}", content.blocks);
+        };
+
+        assert_eq!(
+            section.text,
+            Some(SlackBlockMarkDownText::new("*Song*\nArtist".to_owned()).into())
+        );
+        assert!(section.accessory.is_none());
+    }
+
+    #[test]
+    fn past_expiration_is_dropped() {
+        let now = Utc::now();
+        assert_eq!(
+            clamp_expiration(Some(now - TimeDelta::seconds(1)), now),
+            None
+        );
+        assert_eq!(clamp_expiration(Some(now), now), None);
+    }
+
+    #[test]
+    fn imminent_expiration_is_clamped() {
+        let now = Utc::now();
+        assert_eq!(
+            clamp_expiration(Some(now + TimeDelta::seconds(1)), now),
+            Some(now + TimeDelta::seconds(MIN_EXPIRATION_MARGIN_SECS))
+        );
+    }
+
+    #[test]
+    fn future_expiration_is_kept() {
+        let now = Utc::now();
+        let expiration = now + TimeDelta::minutes(3);
+        assert_eq!(clamp_expiration(Some(expiration), now), Some(expiration));
+        assert_eq!(clamp_expiration(None, now), None);
+    }
+
+    #[test]
+    fn angle_brackets_are_escaped() {
+        assert_eq!(escape("<3 - Artist"), "&lt;3 - Artist");
+        assert_eq!(escape("<@U123> <!here>"), "&lt;@U123&gt; &lt;!here&gt;");
+    }
+
+    #[test]
+    fn ampersands_are_escaped() {
+        assert_eq!(escape("Simon & Garfunkel"), "Simon &amp; Garfunkel");
+        // already escaped text is escaped again so it still shows up literally
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+    }
+
+    #[test]
+    fn plain_text_is_unchanged() {
+        assert_eq!(escape("Song - Artist"), "Song - Artist");
+        assert_eq!(escape(""), "");
+    }
+
+    fn dnd_info(dnd_enabled: bool, start: i64, end: i64, snooze_enabled: bool) -> DndInfo {
+        DndInfo {
+            dnd_enabled,
+            next_dnd_start_ts: start,
+            next_dnd_end_ts: end,
+            snooze_enabled,
+        }
+    }
+
+    #[test]
+    fn dnd_is_active_inside_schedule() {
+        assert!(dnd_info(true, 100, 200, false).is_active(150));
+        assert!(!dnd_info(true, 100, 200, false).is_active(250));
+        assert!(!dnd_info(true, 100, 200, false).is_active(50));
+    }
+
+    #[test]
+    fn snooze_is_always_active() {
+        assert!(dnd_info(false, 0, 0, true).is_active(150));
+        assert!(!dnd_info(false, 100, 200, false).is_active(150));
+    }
+
+    #[tokio::test]
+    async fn hung_request_times_out() {
+        // a Slack endpoint that never answers
+        let request = std::future::pending::<Result<(), SlackError>>();
+
+        let err = with_deadline(Duration::from_millis(10), request)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.current_context(), SlackError::Timeout));
+    }
+
+    #[tokio::test]
+    async fn slow_request_within_deadline_succeeds() {
+        let request = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok(42)
+        };
+
+        assert_eq!(
+            with_deadline(Duration::from_secs(5), request)
+                .await
+                .unwrap(),
+            42
+        );
+    }
+
+    fn user(fields: serde_json::Value) -> SlackUser {
+        let mut user = serde_json::json!({ "id": "U012AB3CD" });
+        user.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(user).unwrap()
+    }
+
+    #[test]
+    fn bots_are_detected() {
+        assert!(is_bot(&user(serde_json::json!({ "is_bot": true }))));
+        assert!(is_bot(&user(serde_json::json!({ "is_app_user": true }))));
+        assert!(is_bot(&user(serde_json::json!({ "id": "USLACKBOT" }))));
+    }
+
+    #[test]
+    fn people_arent_bots() {
+        assert!(!is_bot(&user(serde_json::json!({ "is_bot": false }))));
+        assert!(!is_bot(&user(serde_json::json!({}))));
+    }
+
+    #[tokio::test]
+    async fn clients_for_different_teams_share_a_pool() {
+        let shared = new_shared_client().unwrap();
+        let first = Client::new("xoxp-first", "T0001", Some(shared.clone())).unwrap();
+        let second = Client::new("xoxp-second", "T0002", Some(shared)).unwrap();
+        assert!(std::ptr::eq(first.client(), second.client()));
+
+        let own = Client::new("xoxp-own", "T0003", None).unwrap();
+        assert!(!std::ptr::eq(first.client(), own.client()));
+    }
+
+    #[test]
+    fn statuses_keep_their_expiration() {
+        let expiration = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let profile = SlackUserProfile::new()
+            .with_status_text("In a meeting".to_owned())
+            .with_status_emoji(SlackEmoji(":calendar:".to_owned()))
+            .with_status_expiration(SlackDateTime::new(expiration));
+
+        let status = UserStatus::of(&profile);
+        assert_eq!(status.expiration, Some(expiration));
+        assert!(!status.has_expired(expiration - TimeDelta::minutes(1)));
+        assert!(status.has_expired(expiration));
+
+        let forever = UserStatus::of(
+            &profile.with_status_expiration(SlackDateTime::new(DateTime::UNIX_EPOCH)),
+        );
+        assert_eq!(forever.expiration, None);
+        assert!(!forever.has_expired(expiration));
+        assert!(forever.shows_same(&status));
+    }
+
+    #[tokio::test]
+    async fn refreshed_clients_keep_their_team_and_settings() {
+        let client = Client::new("xoxe.xoxp-old", "T0001", None)
+            .unwrap()
+            .with_respect_dnd(true)
+            .with_status_timeout(Duration::from_secs(5));
+
+        let refreshed = client.with_token("xoxe.xoxp-new");
+        assert_eq!(refreshed.token.token_value.0, "xoxe.xoxp-new");
+        assert_eq!(
+            refreshed.token.team_id,
+            Some(SlackTeamId::new("T0001".to_owned()))
+        );
+        assert!(refreshed.respect_dnd);
+        assert_eq!(refreshed.status_timeout, Duration::from_secs(5));
+        assert!(std::ptr::eq(client.client(), refreshed.client()));
     }
 }
@@ -0,0 +1,148 @@
+use std::{fmt, time::Duration};
+
+use error_stack::{Context, Result};
+use futures::{stream::BoxStream, StreamExt};
+use url::Url;
+
+use crate::lastfm::{self, RecentTrack};
+
+/// Somewhere a user's listening can be followed from, e.g. Last.fm or Spotify
+pub trait MusicSource: Send + Sync {
+    type Error: Context;
+
+    /// A stream of the user's now playing track, polled every `polling_interval`.
+    ///
+    /// Yields the new track when the user starts playing something (or replays the same track),
+    /// and `None` once they stop. Errors are passed on and polling carries on, unless the source
+    /// can't recover from them, which ends the stream
+    fn stream_now_playing<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> BoxStream<'a, Result<Option<NowPlaying>, Self::Error>>;
+}
+
+/// Follows a Last.fm user by their username (see [`lastfm::Client::stream_now_playing`])
+impl MusicSource for lastfm::Client {
+    type Error = lastfm::LastFMError;
+
+    fn stream_now_playing<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> BoxStream<'a, Result<Option<NowPlaying>, Self::Error>> {
+        lastfm::Client::stream_now_playing(self, user, polling_interval)
+            .map(|change| change.map(|track| track.map(NowPlaying::from)))
+            .boxed()
+    }
+}
+
+/// A track someone is listening to, whichever [`MusicSource`] it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NowPlaying {
+    name: String,
+    artist: String,
+    album: String,
+    image_url: Option<Url>,
+    is_loved: bool,
+    duration: Option<Duration>,
+}
+
+impl fmt::Display for NowPlaying {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - {}", self.name, self.artist)
+    }
+}
+
+impl NowPlaying {
+    pub fn new(name: String, artist: String, album: String) -> Self {
+        Self {
+            name,
+            artist,
+            album,
+            image_url: None,
+            is_loved: false,
+            duration: None,
+        }
+    }
+
+    pub fn with_image_url(mut self, image_url: Option<Url>) -> Self {
+        self.image_url = image_url;
+        self
+    }
+
+    pub fn with_loved(mut self, is_loved: bool) -> Self {
+        self.is_loved = is_loved;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Option<Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    pub fn album(&self) -> &str {
+        &self.album
+    }
+
+    /// The URL of the largest album art the source has for the track
+    pub fn image_url(&self) -> Option<&Url> {
+        self.image_url.as_ref()
+    }
+
+    /// Whether the user loved (or liked) the track
+    pub fn is_loved(&self) -> bool {
+        self.is_loved
+    }
+
+    /// How long the track is, if the source says. Last.fm doesn't, so it has to be looked up
+    /// with [`lastfm::Client::get_track_duration`]
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
+
+impl From<RecentTrack> for NowPlaying {
+    fn from(track: RecentTrack) -> Self {
+        let image_url = track.image_url().cloned();
+        let is_loved = track.is_loved();
+
+        NowPlaying::new(
+            track.name().to_owned(),
+            track.artist().to_owned(),
+            track.album().to_owned(),
+        )
+        .with_image_url(image_url)
+        .with_loved(is_loved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lastfm::ImageSize;
+
+    #[test]
+    fn recent_tracks_keep_their_details() {
+        let image_url =
+            Url::parse("https://lastfm.freetls.fastly.net/i/u/300x300/art.png").unwrap();
+        let track = RecentTrack::new("Song", "Artist", "Album")
+            .with_loved(true)
+            .with_image(ImageSize::ExtraLarge, image_url.clone());
+
+        let now_playing = NowPlaying::from(track);
+        assert_eq!(now_playing.to_string(), "Song - Artist");
+        assert_eq!(now_playing.album(), "Album");
+        assert_eq!(now_playing.image_url(), Some(&image_url));
+        assert!(now_playing.is_loved());
+        assert_eq!(now_playing.duration(), None);
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_stream::stream;
+use error_stack::{Report, Result, ResultExt};
+use futures::{stream::BoxStream, StreamExt};
+use nestify::nest;
+use reqwest::StatusCode;
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    lastfm::MIN_POLLING_INTERVAL,
+    source::{MusicSource, NowPlaying},
+};
+
+pub const API_BASE: &str = "https://api.spotify.com/v1/";
+/// Where users authorize SlackFM and tokens are issued
+pub const ACCOUNTS_BASE: &str = "https://accounts.spotify.com/";
+/// All SlackFM needs to see what a user is playing
+pub const SCOPE: &str = "user-read-currently-playing";
+
+/// How long before an access token expires a new one is fetched
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Follows users' Spotify playback through the "currently playing" endpoint.
+///
+/// Users are identified by the refresh token they got from [`SpotifyClient::connect`], which is
+/// traded for short-lived access tokens as they're needed
+pub struct SpotifyClient {
+    client_id: String,
+    client_secret: String,
+    client: reqwest::Client,
+    api_base: Url,
+    accounts_base: Url,
+    /// Access tokens by the refresh token the user connected with
+    access_tokens: Mutex<HashMap<String, AccessToken>>,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+    /// Spotify can hand out a new refresh token when refreshing, which replaces the old one
+    refresh_token: String,
+}
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    RequestError,
+    ParseError,
+    /// The user's refresh token was revoked or is invalid, so they need to connect again
+    Unauthorized,
+    /// Too many requests were made with the app's credentials
+    RateLimited,
+    /// Spotify answered with any other error
+    ApiError,
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpotifyError::RequestError => f.write_str("An error occurred while making the request"),
+            SpotifyError::ParseError => f.write_str("An error occurred while parsing the response"),
+            SpotifyError::Unauthorized => f.write_str("Spotify didn't accept the user's token"),
+            SpotifyError::RateLimited => f.write_str("Spotify's rate limit was exceeded"),
+            SpotifyError::ApiError => f.write_str("Spotify returned an error"),
+        }
+    }
+}
+
+impl Error for SpotifyError {}
+
+impl SpotifyError {
+    /// Whether trying again can't help until the user connects again
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, SpotifyError::Unauthorized)
+    }
+}
+
+impl SpotifyClient {
+    pub fn new(client_id: String, client_secret: String, client: reqwest::Client) -> Self {
+        Self::with_base_urls(
+            client_id,
+            client_secret,
+            client,
+            Url::parse(API_BASE).unwrap(),
+            Url::parse(ACCOUNTS_BASE).unwrap(),
+        )
+    }
+
+    /// A client for APIs at different addresses than Spotify's, e.g. a mock server in tests
+    pub fn with_base_urls(
+        client_id: String,
+        client_secret: String,
+        client: reqwest::Client,
+        api_base: Url,
+        accounts_base: Url,
+    ) -> Self {
+        Self {
+            client_id,
+            client_secret,
+            client,
+            api_base,
+            accounts_base,
+            access_tokens: Mutex::default(),
+        }
+    }
+
+    /// Where to send a user so they can let SlackFM see what they're playing. Spotify redirects
+    /// them back to `redirect_uri` with a `code` to pass to [`SpotifyClient::connect`] and the
+    /// given `state`
+    pub fn authorize_url(&self, redirect_uri: &Url, state: &str) -> Url {
+        let mut url = self.accounts_base.join("authorize").unwrap();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("response_type", "code")
+            .append_pair("redirect_uri", redirect_uri.as_str())
+            .append_pair("scope", SCOPE)
+            .append_pair("state", state);
+        url
+    }
+
+    /// Exchanges the code from the authorize redirect for the user's refresh token, which
+    /// identifies them from then on
+    #[tracing::instrument(skip(self, code))]
+    pub async fn connect(&self, code: &str, redirect_uri: &Url) -> Result<String, SpotifyError> {
+        let response = self
+            .request_token(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri.as_str()),
+            ])
+            .await?;
+
+        let refresh_token = response
+            .refresh_token
+            .ok_or(SpotifyError::ParseError)
+            .attach_printable("Spotify didn't send a refresh token")?;
+
+        self.access_tokens.lock().unwrap().insert(
+            refresh_token.clone(),
+            AccessToken {
+                token: response.access_token,
+                expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+                refresh_token: refresh_token.clone(),
+            },
+        );
+
+        Ok(refresh_token)
+    }
+
+    /// The refresh token to store for a user who connected with `refresh_token`. Spotify can
+    /// rotate it when handing out access tokens, after which the one they connected with stops
+    /// working, so this has to be stored in its place to follow them after a restart
+    pub fn current_refresh_token(&self, refresh_token: &str) -> String {
+        self.access_tokens
+            .lock()
+            .unwrap()
+            .get(refresh_token)
+            .map_or_else(
+                || refresh_token.to_owned(),
+                |cached| cached.refresh_token.clone(),
+            )
+    }
+
+    /// The track the user is currently playing, if any
+    #[tracing::instrument(skip(self, refresh_token))]
+    pub async fn get_now_playing(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<NowPlaying>, SpotifyError> {
+        Ok(self
+            .get_playback(refresh_token)
+            .await?
+            .map(|playback| playback.track))
+    }
+
+    async fn get_playback(&self, refresh_token: &str) -> Result<Option<Playback>, SpotifyError> {
+        let access_token = self.access_token(refresh_token).await?;
+        let response = match self.fetch_currently_playing(&access_token).await {
+            // the token can be revoked before it expires, so get a new one and try once more
+            Err(e) if matches!(e.current_context(), SpotifyError::Unauthorized) => {
+                if let Some(cached) = self.access_tokens.lock().unwrap().get_mut(refresh_token) {
+                    cached.expires_at = Instant::now();
+                }
+                let access_token = self.access_token(refresh_token).await?;
+                self.fetch_currently_playing(&access_token).await?
+            }
+            response => response?,
+        };
+
+        Ok(response.and_then(Playback::from_response))
+    }
+
+    async fn fetch_currently_playing(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<CurrentlyPlayingResponse>, SpotifyError> {
+        let response = self
+            .client
+            .get(self.api_base.join("me/player/currently-playing").unwrap())
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .change_context(SpotifyError::RequestError)?;
+
+        match response.status() {
+            // nothing is playing, or the user has a private session on
+            StatusCode::NO_CONTENT => return Ok(None),
+            StatusCode::UNAUTHORIZED => {
+                return Err(Report::new(SpotifyError::Unauthorized)
+                    .attach_printable("The access token was rejected"))
+            }
+            StatusCode::TOO_MANY_REQUESTS => return Err(Report::new(SpotifyError::RateLimited)),
+            status if !status.is_success() => {
+                return Err(Report::new(SpotifyError::ApiError)
+                    .attach_printable(format!("Spotify responded with {}", status)))
+            }
+            _ => {}
+        }
+
+        response
+            .json()
+            .await
+            .attach_printable("Couldn't parse the currently playing response")
+            .change_context(SpotifyError::ParseError)
+    }
+
+    /// An access token for the user, refreshed if the last one is about to expire
+    async fn access_token(&self, refresh_token: &str) -> Result<String, SpotifyError> {
+        let current_refresh_token = {
+            let access_tokens = self.access_tokens.lock().unwrap();
+            match access_tokens.get(refresh_token) {
+                Some(cached) if cached.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN => {
+                    return Ok(cached.token.clone());
+                }
+                Some(cached) => cached.refresh_token.clone(),
+                None => refresh_token.to_owned(),
+            }
+        };
+
+        debug!("Refreshing a Spotify access token");
+        let response = self
+            .request_token(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", current_refresh_token.as_str()),
+            ])
+            .await?;
+
+        self.access_tokens.lock().unwrap().insert(
+            refresh_token.to_owned(),
+            AccessToken {
+                token: response.access_token.clone(),
+                expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+                refresh_token: response.refresh_token.unwrap_or(current_refresh_token),
+            },
+        );
+
+        Ok(response.access_token)
+    }
+
+    async fn request_token(&self, form: &[(&str, &str)]) -> Result<TokenResponse, SpotifyError> {
+        let response = self
+            .client
+            .post(self.accounts_base.join("api/token").unwrap())
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(form)
+            .send()
+            .await
+            .change_context(SpotifyError::RequestError)?;
+
+        match response.status() {
+            // an invalid or revoked code or refresh token
+            StatusCode::BAD_REQUEST => {
+                let error = response.text().await.unwrap_or_default();
+                return Err(Report::new(SpotifyError::Unauthorized).attach_printable(error));
+            }
+            StatusCode::TOO_MANY_REQUESTS => return Err(Report::new(SpotifyError::RateLimited)),
+            status if !status.is_success() => {
+                return Err(Report::new(SpotifyError::ApiError)
+                    .attach_printable(format!("Spotify responded with {}", status)))
+            }
+            _ => {}
+        }
+
+        response
+            .json()
+            .await
+            .attach_printable("Couldn't parse the token response")
+            .change_context(SpotifyError::ParseError)
+    }
+}
+
+/// Follows a Spotify user by the refresh token they connected with
+impl MusicSource for SpotifyClient {
+    type Error = SpotifyError;
+
+    fn stream_now_playing<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> BoxStream<'a, Result<Option<NowPlaying>, Self::Error>> {
+        let polling_interval = polling_interval.max(MIN_POLLING_INTERVAL);
+        let mut tracker = NowPlayingTracker::default();
+        stream! {
+            loop {
+                // wait before the next poll
+                tokio::time::sleep(polling_interval).await;
+
+                debug!("Polling Spotify for now playing track");
+                match self.get_playback(user).await {
+                    Ok(playback) => {
+                        if let Some(change) = tracker.update(playback) {
+                            yield Ok(change);
+                        }
+                    }
+                    Err(e) => {
+                        let fatal = e.current_context().is_fatal();
+                        yield Err(e);
+                        if fatal {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// What the user is playing, with what's needed to tell a replay from the same track carrying on
+#[derive(Debug, Clone)]
+struct Playback {
+    id: String,
+    progress: Duration,
+    track: NowPlaying,
+}
+
+impl Playback {
+    /// Paused playback, podcasts and ads don't count as playing a track
+    fn from_response(response: CurrentlyPlayingResponse) -> Option<Self> {
+        if !response.is_playing || response.currently_playing_type != "track" {
+            return None;
+        }
+        let item = response.item?;
+
+        let artist = item
+            .artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Spotify lists the largest image first
+        let image_url = item
+            .album
+            .images
+            .into_iter()
+            .find_map(|image| Url::parse(&image.url).ok());
+
+        Some(Self {
+            id: item.id.unwrap_or_else(|| item.name.clone()),
+            progress: Duration::from_millis(response.progress_ms.unwrap_or_default()),
+            track: NowPlaying::new(item.name, artist, item.album.name)
+                .with_image_url(image_url)
+                .with_duration(Some(Duration::from_millis(item.duration_ms))),
+        })
+    }
+}
+
+/// Works out when a user's now playing track changes from successive polls of their playback,
+/// like [`crate::lastfm::NowPlayingTracker`] does for Last.fm
+#[derive(Debug, Default)]
+struct NowPlayingTracker {
+    last_playing: Option<Playback>,
+}
+
+impl NowPlayingTracker {
+    /// Feeds in the latest playback.
+    ///
+    /// Returns `Some(Some(track))` if the user started playing something new (or replayed the
+    /// same track), `Some(None)` if they stopped playing, and `None` if nothing changed.
+    fn update(&mut self, playback: Option<Playback>) -> Option<Option<NowPlaying>> {
+        let change = match (&playback, &self.last_playing) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                debug!("Stopped playing anything");
+                Some(None)
+            }
+            (Some(playing), None) => Some(Some(playing.track.clone())),
+            (Some(playing), Some(last)) => {
+                // the same track going back to the start means it's on loop
+                if playing.id != last.id || playing.progress < last.progress {
+                    debug!("Now playing: {}", playing.track);
+                    Some(Some(playing.track.clone()))
+                } else {
+                    None
+                }
+            }
+        };
+
+        self.last_playing = playback;
+        change
+    }
+}
+
+nest! {
+    #[derive(serde::Deserialize, Debug)]*
+    /// Spotify API response for the `/me/player/currently-playing` endpoint.
+    /// Limited to only the fields we care about.
+    struct CurrentlyPlayingResponse {
+        is_playing: bool,
+        progress_ms: Option<u64>,
+        /// `track`, `episode`, `ad` or `unknown`
+        currently_playing_type: String,
+        /// Missing for ads and, without asking for them, podcast episodes
+        item: Option<struct Item {
+            /// Local files don't have an id
+            id: Option<String>,
+            name: String,
+            duration_ms: u64,
+            artists: Vec<struct Artist {
+                name: String,
+            }>,
+            album: struct Album {
+                name: String,
+                #[serde(default)]
+                images: Vec<struct Image {
+                    url: String,
+                }>,
+            },
+        }>,
+    }
+}
+
+/// Spotify's response when issuing an access token
+#[derive(serde::Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    /// How many seconds the access token lasts
+    expires_in: u64,
+    /// Always sent for a new authorization, and only sometimes when refreshing
+    refresh_token: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYING: &str = r#"{"timestamp":1700000000000,"progress_ms":42000,"is_playing":true,"currently_playing_type":"track","item":{"id":"4u7EnebtmKWzUH433cf5Qv","name":"Bohemian Rhapsody","duration_ms":354320,"artists":[{"name":"Queen"}],"album":{"name":"A Night at the Opera","images":[{"url":"https://i.scdn.co/image/large","height":640,"width":640},{"url":"https://i.scdn.co/image/small","height":64,"width":64}]}}}"#;
+
+    fn mock_client(base_url: Url) -> SpotifyClient {
+        SpotifyClient::with_base_urls(
+            "client-id".to_owned(),
+            "client-secret".to_owned(),
+            reqwest::Client::new(),
+            base_url.clone(),
+            base_url,
+        )
+    }
+
+    /// Serves one request per response, in order, each on its own connection
+    async fn mock_server(responses: &'static [(&'static str, &'static str)]) -> Url {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                // token requests have a body, which can come after the headers
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while !is_complete(&request) {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        url
+    }
+
+    /// Whether the request's headers and as much body as they announced have arrived
+    fn is_complete(request: &[u8]) -> bool {
+        let request = String::from_utf8_lossy(request);
+        let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let content_length = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, length)| length.trim().parse().ok())
+            .unwrap_or(0);
+
+        body.len() >= content_length
+    }
+
+    fn playback(id: &str, progress_secs: u64) -> Option<Playback> {
+        Some(Playback {
+            id: id.to_owned(),
+            progress: Duration::from_secs(progress_secs),
+            track: NowPlaying::new(id.to_owned(), "Artist".to_owned(), "Album".to_owned()),
+        })
+    }
+
+    #[tokio::test]
+    async fn currently_playing_track_is_parsed() {
+        let client = mock_client(
+            mock_server(&[
+                (
+                    "200 OK",
+                    r#"{"access_token":"access","token_type":"Bearer","expires_in":3600}"#,
+                ),
+                ("200 OK", PLAYING),
+            ])
+            .await,
+        );
+
+        let track = client.get_now_playing("refresh").await.unwrap().unwrap();
+        assert_eq!(track.to_string(), "Bohemian Rhapsody - Queen");
+        assert_eq!(track.album(), "A Night at the Opera");
+        assert_eq!(
+            track.image_url().map(Url::as_str),
+            Some("https://i.scdn.co/image/large")
+        );
+        assert_eq!(track.duration(), Some(Duration::from_millis(354320)));
+    }
+
+    #[tokio::test]
+    async fn nothing_playing_is_none() {
+        let client = mock_client(
+            mock_server(&[
+                (
+                    "200 OK",
+                    r#"{"access_token":"access","token_type":"Bearer","expires_in":3600}"#,
+                ),
+                ("204 No Content", ""),
+            ])
+            .await,
+        );
+
+        assert!(client.get_now_playing("refresh").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rotated_refresh_tokens_are_kept() {
+        let client = mock_client(
+            mock_server(&[
+                (
+                    "200 OK",
+                    r#"{"access_token":"access","token_type":"Bearer","expires_in":3600,"refresh_token":"rotated"}"#,
+                ),
+                ("204 No Content", ""),
+            ])
+            .await,
+        );
+        assert_eq!(client.current_refresh_token("refresh"), "refresh");
+
+        client.get_now_playing("refresh").await.unwrap();
+        assert_eq!(client.current_refresh_token("refresh"), "rotated");
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_fatal() {
+        let client = mock_client(
+            mock_server(&[(
+                "400 Bad Request",
+                r#"{"error":"invalid_grant","error_description":"Refresh token revoked"}"#,
+            )])
+            .await,
+        );
+
+        let error = client.get_now_playing("refresh").await.unwrap_err();
+        assert!(error.current_context().is_fatal());
+    }
+
+    #[test]
+    fn paused_and_non_track_playback_isnt_playing() {
+        let paused: CurrentlyPlayingResponse =
+            serde_json::from_str(&PLAYING.replace(r#""is_playing":true"#, r#""is_playing":false"#))
+                .unwrap();
+        assert!(Playback::from_response(paused).is_none());
+
+        let ad: CurrentlyPlayingResponse = serde_json::from_str(
+            r#"{"progress_ms":1000,"is_playing":true,"currently_playing_type":"ad","item":null}"#,
+        )
+        .unwrap();
+        assert!(Playback::from_response(ad).is_none());
+    }
+
+    #[test]
+    fn tracker_reports_changes_and_replays() {
+        let mut tracker = NowPlayingTracker::default();
+
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(
+            tracker.update(playback("a", 10)).unwrap().unwrap().name(),
+            "a"
+        );
+        // still playing
+        assert_eq!(tracker.update(playback("a", 20)), None);
+        // back at the start
+        assert_eq!(
+            tracker.update(playback("a", 1)).unwrap().unwrap().name(),
+            "a"
+        );
+        assert_eq!(
+            tracker.update(playback("b", 1)).unwrap().unwrap().name(),
+            "b"
+        );
+        assert_eq!(tracker.update(None), Some(None));
+    }
+}
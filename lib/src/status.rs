@@ -0,0 +1,350 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::source::NowPlaying;
+
+/// How long past a track's end its status is kept unless configured otherwise
+pub const DEFAULT_EXPIRY_PADDING_SECS: i64 = 5;
+
+/// The longest status text Slack accepts
+pub const MAX_STATUS_LENGTH: usize = 100;
+/// What a status template can fill in from the track
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &["{track}", "{artist}", "{album}"];
+
+/// What to do with a scrobble that has no track name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyNameBehavior {
+    /// Leave the current status alone
+    #[default]
+    Skip,
+    /// Use the album name in place of the track name
+    UseAlbum,
+}
+
+#[derive(Debug)]
+pub struct ParseEmptyNameBehaviorError(String);
+
+impl fmt::Display for ParseEmptyNameBehaviorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown empty name behavior {:?}, expected \"skip\" or \"album\"",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseEmptyNameBehaviorError {}
+
+impl FromStr for EmptyNameBehavior {
+    type Err = ParseEmptyNameBehaviorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "album" => Ok(Self::UseAlbum),
+            other => Err(ParseEmptyNameBehaviorError(other.to_owned())),
+        }
+    }
+}
+
+/// Why a status template can't be used
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    NoPlaceholder,
+    /// The template is too long with a typical track filled in
+    TooLong(usize),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoPlaceholder => write!(
+                f,
+                "The template needs at least one of {}",
+                TEMPLATE_PLACEHOLDERS.join(", ")
+            ),
+            Self::TooLong(length) => write!(
+                f,
+                "The template is {} characters long with a track filled in, but Slack only allows {}",
+                length, MAX_STATUS_LENGTH
+            ),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+/// Checks a status template has something to fill in, and that a typical track fits in a status
+pub fn validate_template(template: &str) -> Result<(), TemplateError> {
+    if !TEMPLATE_PLACEHOLDERS
+        .iter()
+        .any(|placeholder| template.contains(placeholder))
+    {
+        return Err(TemplateError::NoPlaceholder);
+    }
+
+    let sample = render_template(
+        template,
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    let length = sample.chars().count();
+    if length > MAX_STATUS_LENGTH {
+        return Err(TemplateError::TooLong(length));
+    }
+
+    Ok(())
+}
+
+fn render_template(template: &str, name: &str, artist: &str, album: &str) -> String {
+    template
+        .replace("{track}", name)
+        .replace("{artist}", artist)
+        .replace("{album}", album)
+}
+
+/// Shortens a status to [`MAX_STATUS_LENGTH`] characters, ending it with `…` if anything was cut.
+///
+/// Cuts between grapheme clusters so accents and emoji aren't split in half.
+pub fn truncate_status(text: &str) -> String {
+    if text.chars().count() <= MAX_STATUS_LENGTH {
+        return text.to_owned();
+    }
+
+    let mut truncated = String::new();
+    let mut length = 0;
+    for grapheme in text.graphemes(true) {
+        let grapheme_length = grapheme.chars().count();
+        // leave room for the ellipsis
+        if length + grapheme_length > MAX_STATUS_LENGTH - 1 {
+            break;
+        }
+        truncated.push_str(grapheme);
+        length += grapheme_length;
+    }
+
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// The track's name, or what to show in its place. `None` if the status shouldn't be updated
+fn track_name(track: &NowPlaying, empty_name: EmptyNameBehavior) -> Option<&str> {
+    match (track.name().trim(), empty_name) {
+        ("", EmptyNameBehavior::Skip) => None,
+        ("", EmptyNameBehavior::UseAlbum) if track.album().trim().is_empty() => None,
+        ("", EmptyNameBehavior::UseAlbum) => Some(track.album()),
+        _ => Some(track.name()),
+    }
+}
+
+/// Formats the status text for a track from a user's template (see [`validate_template`]),
+/// truncated to fit in a status.
+///
+/// Returns `None` if the status shouldn't be updated at all.
+pub fn templated_status_text(
+    track: &NowPlaying,
+    empty_name: EmptyNameBehavior,
+    template: &str,
+) -> Option<String> {
+    let name = track_name(track, empty_name)?;
+
+    Some(truncate_status(&render_template(
+        template,
+        name,
+        track.artist(),
+        track.album(),
+    )))
+}
+
+/// Formats the status text for a track, with the album in brackets if `show_album` is set,
+/// truncated to fit in a status.
+///
+/// Returns `None` if the status shouldn't be updated at all.
+pub fn status_text(
+    track: &NowPlaying,
+    empty_name: EmptyNameBehavior,
+    show_album: bool,
+) -> Option<String> {
+    let name = track_name(track, empty_name)?;
+
+    // an empty album (or one already used as the name) is left out rather than shown as "()"
+    let album = Some(track.album().trim())
+        .filter(|album| show_album && !album.is_empty() && *album != name.trim());
+
+    let text = match album {
+        Some(album) => format!("{} - {} ({})", name, track.artist(), album),
+        None => format!("{} - {}", name, track.artist()),
+    };
+
+    Some(truncate_status(&text))
+}
+
+/// The status emoji for a track: `loved_emoji` if the user loved it, `emoji` otherwise
+pub fn status_emoji<'a>(track: &NowPlaying, emoji: &'a str, loved_emoji: &'a str) -> &'a str {
+    if track.is_loved() {
+        loved_emoji
+    } else {
+        emoji
+    }
+}
+
+/// When a status for a track of the given length should expire.
+///
+/// Last.fm doesn't tell us when a now playing track started, so we only find out about it up to a
+/// poll interval late. The padding is added on top so the status doesn't disappear a few seconds
+/// before the song actually ends. Returns `None` (no expiration) when the length isn't known.
+pub fn status_expiry(
+    now: DateTime<Utc>,
+    track_length: Option<TimeDelta>,
+    padding: TimeDelta,
+) -> Option<DateTime<Utc>> {
+    track_length
+        .filter(|length| *length > TimeDelta::zero())
+        .map(|length| now + length + padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lastfm::RecentTrack;
+
+    #[test]
+    fn formats_name_and_artist() {
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", "Album"));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::Skip, false).as_deref(),
+            Some("Song - Artist")
+        );
+    }
+
+    #[test]
+    fn empty_name_is_skipped() {
+        let track = NowPlaying::from(RecentTrack::new("", "Artist", "Album"));
+        assert_eq!(status_text(&track, EmptyNameBehavior::Skip, false), None);
+    }
+
+    #[test]
+    fn empty_name_falls_back_to_album() {
+        let track = NowPlaying::from(RecentTrack::new(" ", "Artist", "Album"));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::UseAlbum, false).as_deref(),
+            Some("Album - Artist")
+        );
+
+        let track = NowPlaying::from(RecentTrack::new("", "Artist", ""));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::UseAlbum, false),
+            None
+        );
+    }
+
+    #[test]
+    fn album_is_shown_when_enabled() {
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", "Album"));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::Skip, true).as_deref(),
+            Some("Song - Artist (Album)")
+        );
+
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", ""));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::Skip, true).as_deref(),
+            Some("Song - Artist")
+        );
+    }
+
+    #[test]
+    fn album_isnt_repeated_as_name() {
+        let track = NowPlaying::from(RecentTrack::new("", "Artist", "Album"));
+        assert_eq!(
+            status_text(&track, EmptyNameBehavior::UseAlbum, true).as_deref(),
+            Some("Album - Artist")
+        );
+    }
+
+    #[test]
+    fn templates_are_filled_in() {
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", "Album"));
+        assert_eq!(
+            templated_status_text(
+                &track,
+                EmptyNameBehavior::Skip,
+                "{artist}: {track} on {album}"
+            )
+            .as_deref(),
+            Some("Artist: Song on Album")
+        );
+
+        let track = NowPlaying::from(RecentTrack::new("", "Artist", "Album"));
+        assert_eq!(
+            templated_status_text(&track, EmptyNameBehavior::Skip, "{track}"),
+            None
+        );
+    }
+
+    #[test]
+    fn templates_are_validated() {
+        assert_eq!(validate_template("{track} by {artist}"), Ok(()));
+        assert_eq!(
+            validate_template("Listening to music"),
+            Err(TemplateError::NoPlaceholder)
+        );
+        assert!(matches!(
+            validate_template(&format!("{{track}} {}", "a".repeat(90))),
+            Err(TemplateError::TooLong(_))
+        ));
+    }
+
+    #[test]
+    fn long_statuses_are_truncated() {
+        let track = NowPlaying::from(RecentTrack::new(&"a".repeat(150), "Artist", "Album"));
+        let text = status_text(&track, EmptyNameBehavior::Skip, false).unwrap();
+        assert_eq!(text.chars().count(), MAX_STATUS_LENGTH);
+        assert_eq!(text, format!("{}…", "a".repeat(MAX_STATUS_LENGTH - 1)));
+
+        let text = templated_status_text(&track, EmptyNameBehavior::Skip, "{track}").unwrap();
+        assert_eq!(text.chars().count(), MAX_STATUS_LENGTH);
+        assert!(text.ends_with('…'));
+
+        // combining accents stay with their letter
+        let accented = "e\u{301}".repeat(75);
+        let text = truncate_status(&accented);
+        assert!(text.chars().count() <= MAX_STATUS_LENGTH);
+        assert_eq!(text, format!("{}…", "e\u{301}".repeat(49)));
+
+        assert_eq!(truncate_status("Song - Artist"), "Song - Artist");
+    }
+
+    #[test]
+    fn loved_tracks_get_their_own_emoji() {
+        let track = NowPlaying::from(RecentTrack::new("Song", "Artist", "Album"));
+        assert_eq!(status_emoji(&track, ":music:", ":heart:"), ":music:");
+
+        let loved = track.with_loved(true);
+        assert_eq!(status_emoji(&loved, ":music:", ":heart:"), ":heart:");
+    }
+
+    #[test]
+    fn expiry_is_padded() {
+        let now = Utc::now();
+        assert_eq!(
+            status_expiry(now, Some(TimeDelta::seconds(180)), TimeDelta::seconds(5)),
+            Some(now + TimeDelta::seconds(185))
+        );
+    }
+
+    #[test]
+    fn unknown_length_never_expires() {
+        let now = Utc::now();
+        assert_eq!(status_expiry(now, None, TimeDelta::seconds(5)), None);
+        assert_eq!(
+            status_expiry(now, Some(TimeDelta::zero()), TimeDelta::seconds(5)),
+            None
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    Json,
+};
+use futures::{stream, StreamExt};
+use serde::Serialize;
+use slack_morphism::prelude::*;
+use slackfm::slack;
+use tracing::{error, info};
+
+use crate::{
+    db::{LockExt, UserData, UserSettings},
+    env,
+    history::StatusChange,
+    AppState,
+};
+
+/// How many statuses are cleared at once
+const CONCURRENT_CLEARS: usize = 10;
+
+/// Guards the admin endpoints behind `ADMIN_TOKEN`, which has to be passed as a bearer token.
+///
+/// The endpoints don't exist at all if no admin token is configured.
+pub struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(admin_token) = env::admin_token() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), admin_token.as_bytes()) => {
+                Ok(AdminAuth)
+            }
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The team a user belongs to. Users from before team ids were stored are in the configured team
+pub fn team_of(user: &std::sync::Mutex<UserData>) -> String {
+    user.lock_or_recover()
+        .team_id()
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(env::slack_team_id)
+}
+
+/// Lists the slack user ids of every user, grouped by team id
+pub async fn list_teams(
+    _: AdminAuth,
+    State(state): State<AppState>,
+) -> Json<BTreeMap<String, Vec<String>>> {
+    let db = state.db.read().await;
+
+    let mut teams: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (user_id, user) in db.users() {
+        teams
+            .entry(team_of(&user))
+            .or_default()
+            .push(user_id.clone());
+    }
+
+    Json(teams)
+}
+
+#[derive(Serialize)]
+pub struct UserInfo {
+    lastfm_username: String,
+    team_id: String,
+    /// Whether the user finished authorizing SlackFM
+    connected: bool,
+    /// Whether a task is currently polling Last.fm for the user
+    updating: bool,
+    settings: UserSettings,
+    /// The last status SlackFM set for the user since the server started
+    last_status: Option<StatusChange>,
+}
+
+/// Looks up a single user's connection state and settings (but never their tokens) for support
+pub async fn user_info(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<Json<UserInfo>, StatusCode> {
+    let user = state
+        .db
+        .read()
+        .await
+        .user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let updating = state
+        .tasks
+        .lock()
+        .await
+        .contains_key(&SlackUserId::new(user_id.clone()));
+
+    let team_id = team_of(&user);
+    let user = user.lock_or_recover();
+
+    Ok(Json(UserInfo {
+        lastfm_username: user.lastfm_username().to_owned(),
+        team_id,
+        connected: user.slack_token().is_some(),
+        updating,
+        settings: user.settings().clone(),
+        last_status: state.history.latest(&user_id),
+    }))
+}
+
+#[derive(Serialize, Default)]
+pub struct RevokeSummary {
+    revoked: usize,
+    removed: usize,
+    failed: usize,
+}
+
+/// Revokes the tokens of every user in a team and removes them, e.g. after SlackFM was
+/// uninstalled from that workspace
+pub async fn revoke_team(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(team_id): Path<String>,
+) -> Json<RevokeSummary> {
+    info!("Revoking all users in team {}", team_id);
+
+    // collected up front so the database isn't locked while talking to Slack, which may save a
+    // refreshed token
+    let team_users: Vec<(String, Arc<Mutex<UserData>>)> = state
+        .db
+        .read()
+        .await
+        .users()
+        .filter(|(_, user)| team_of(user) == team_id)
+        .map(|(user_id, user)| (user_id.clone(), user))
+        .collect();
+
+    let mut summary = RevokeSummary::default();
+
+    for (user_id, user) in team_users {
+        let token = user.lock_or_recover().slack_token().map(ToOwned::to_owned);
+        if let Some(token) = token {
+            let slack_user_id = SlackUserId::new(user_id.clone());
+            let mut client = Arc::new(slack::Client::from_client(
+                state.slack_client.clone(),
+                token,
+                team_id.clone(),
+            ));
+
+            match crate::with_user_client(
+                &state,
+                &slack_user_id,
+                &user,
+                &mut client,
+                |client| async move { client.revoke_token().await },
+            )
+            .await
+            {
+                Ok(()) => summary.revoked += 1,
+                Err(e) => {
+                    // the token is most likely dead already if the app was uninstalled, so the
+                    // user is removed either way
+                    error!("Error revoking token for {}: {:?}", user_id, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        if let Some(abort_handle) = state
+            .tasks
+            .lock()
+            .await
+            .remove(&SlackUserId::new(user_id.clone()))
+        {
+            abort_handle.abort();
+        }
+        state.history.remove_user(&user_id);
+
+        match state.db.write().await.remove_user(&user_id) {
+            Ok(_) => summary.removed += 1,
+            Err(e) => error!("Error removing {} from the database: {:?}", user_id, e),
+        }
+    }
+
+    Json(summary)
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct ClearSummary {
+    cleared: usize,
+    /// Users showing a status of their own, which was left alone
+    skipped: usize,
+    failed: usize,
+}
+
+/// Takes SlackFM's status off every connected user without disconnecting them, e.g. before
+/// maintenance or after a bad status went out. Their status is set again the next time their
+/// track changes
+pub async fn clear_all(_: AdminAuth, State(state): State<AppState>) -> Json<ClearSummary> {
+    info!("Clearing every connected user's status");
+
+    Json(clear_statuses(&state).await)
+}
+
+/// Takes SlackFM's status off every connected user who isn't paused, a few at a time. Statuses it
+/// replaced are put back, and statuses users set themselves are left alone
+pub async fn clear_statuses(state: &AppState) -> ClearSummary {
+    // collected up front so the database isn't locked while talking to Slack
+    let users: Vec<(String, Arc<Mutex<UserData>>, String)> = {
+        let db = state.db.read().await;
+        db.users()
+            .filter_map(|(user_id, user)| {
+                let token = {
+                    let user = user.lock_or_recover();
+                    // paused users already had their status put back
+                    if user.is_paused() {
+                        return None;
+                    }
+                    user.slack_token().map(ToOwned::to_owned)?
+                };
+                Some((user_id.clone(), user, token))
+            })
+            .collect()
+    };
+
+    let results: Vec<Option<bool>> = stream::iter(users)
+        .map(|(user_id, user, token)| async move {
+            let slack_user_id = SlackUserId::new(user_id);
+            let mut client = Arc::new(slack::Client::from_client(
+                state.slack_client.clone(),
+                token,
+                team_of(&user),
+            ));
+
+            match crate::take_down_status(state, &mut client, &slack_user_id, &user).await {
+                Ok(cleared) => Some(cleared),
+                Err(e) => {
+                    error!("Error clearing status for {}: {:?}", slack_user_id, e);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(CONCURRENT_CLEARS)
+        .collect()
+        .await;
+
+    // the statuses that were taken down are forgotten
+    if let Err(e) = state.db.read().await.save_all() {
+        error!("Error saving the cleared statuses: {:?}", e);
+    }
+
+    let cleared = results
+        .iter()
+        .filter(|result| **result == Some(true))
+        .count();
+    let skipped = results
+        .iter()
+        .filter(|result| **result == Some(false))
+        .count();
+    ClearSummary {
+        cleared,
+        skipped,
+        failed: results.len() - cleared - skipped,
+    }
+}
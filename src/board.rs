@@ -0,0 +1,113 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use error_stack::Result;
+use slack_morphism::prelude::*;
+use slackfm::slack::{self, SlackError};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// A single (pinned) message in a shared channel listing everyone who is currently listening to
+/// something.
+pub struct NowPlayingBoard {
+    slack_client: Arc<slack::Client>,
+    channel: SlackChannelId,
+    state: Mutex<BoardState>,
+}
+
+#[derive(Default)]
+struct BoardState {
+    message_ts: Option<SlackTs>,
+    // keyed by slack user id so the listing has a stable order
+    playing: BTreeMap<String, String>,
+}
+
+impl NowPlayingBoard {
+    pub fn new(slack_client: Arc<slack::Client>, channel: SlackChannelId) -> Self {
+        Self {
+            slack_client,
+            channel,
+            state: Mutex::new(BoardState::default()),
+        }
+    }
+
+    /// Updates what a user is playing (or removes them if `track` is `None`) and re-renders the
+    /// shared message, reposting it if it was deleted.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_playing(
+        &self,
+        user_id: &SlackUserId,
+        track: Option<String>,
+    ) -> Result<(), SlackError> {
+        // the lock is held across the slack calls so concurrent updates can't post two messages
+        let mut state = self.state.lock().await;
+
+        match track {
+            Some(track) => {
+                // replaying the same song shouldn't touch the message again
+                if state.playing.insert(user_id.to_string(), track.clone()) == Some(track) {
+                    return Ok(());
+                }
+            }
+            None => {
+                if state.playing.remove(&user_id.to_string()).is_none() {
+                    // nothing changed, no need to touch the message
+                    return Ok(());
+                }
+            }
+        }
+
+        let content = render(&state.playing);
+
+        let ts = match state.message_ts.clone() {
+            Some(ts) => match self
+                .slack_client
+                .update_message(self.channel.clone(), ts, content.clone())
+                .await
+            {
+                Ok(ts) => ts,
+                Err(e) if matches!(e.current_context(), SlackError::MessageNotFound) => {
+                    info!("Now playing message was deleted, reposting it");
+                    self.post(content).await?
+                }
+                Err(e) => return Err(e),
+            },
+            None => self.post(content).await?,
+        };
+
+        state.message_ts = Some(ts);
+
+        Ok(())
+    }
+
+    async fn post(&self, content: SlackMessageContent) -> Result<SlackTs, SlackError> {
+        let ts = self
+            .slack_client
+            .post_message(self.channel.clone(), content)
+            .await?;
+
+        // pinning is a nicety, the message is still useful if we're missing pins:write
+        if let Err(e) = self
+            .slack_client
+            .pin_message(self.channel.clone(), ts.clone())
+            .await
+        {
+            warn!("Couldn't pin the now playing message: {:?}", e);
+        }
+
+        Ok(ts)
+    }
+}
+
+fn render(playing: &BTreeMap<String, String>) -> SlackMessageContent {
+    let text = if playing.is_empty() {
+        "Nobody is listening to anything right now".to_owned()
+    } else {
+        playing
+            .iter()
+            .map(|(user_id, track)| format!(":music: <@{}>: {}", user_id, slack::escape(track)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    SlackMessageContent::new().with_text(text)
+}
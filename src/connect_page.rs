@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use oauth2::CsrfToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    db::{LockExt, UserData},
+    oauth::{authorize_url, create_oauth_client, user_scopes},
+    AppState,
+};
+
+/// The most web connections waiting on Slack at once, so the form can't be used to fill memory
+const MAX_PENDING_CONNECTIONS: usize = 1000;
+
+/// Users who started connecting from the web page, by their CSRF state. They're only kept in
+/// memory until Slack tells us their user id, so anyone can submit the form without it writing
+/// to the database
+#[derive(Default)]
+pub struct PendingConnections {
+    users: Mutex<HashMap<String, Arc<Mutex<UserData>>>>,
+}
+
+impl PendingConnections {
+    /// Remembers a connection, unless too many are already waiting. Expired ones are dropped first
+    fn add(&self, csrf_state: String, user: UserData, now: DateTime<Utc>) -> bool {
+        let mut users = self.users.lock_or_recover();
+        users.retain(|_, user| !user.lock_or_recover().csrf_expired(now));
+
+        if users.len() >= MAX_PENDING_CONNECTIONS {
+            return false;
+        }
+        users.insert(csrf_state, Arc::new(Mutex::new(user)));
+        true
+    }
+
+    /// The connection started with the given CSRF state, unless it expired
+    pub fn get(&self, csrf_state: &str, now: DateTime<Utc>) -> Option<Arc<Mutex<UserData>>> {
+        self.users
+            .lock_or_recover()
+            .get(csrf_state)
+            .filter(|user| !user.lock_or_recover().csrf_expired(now))
+            .cloned()
+    }
+
+    /// Forgets a connection once Slack finished it
+    pub fn remove(&self, csrf_state: &str) {
+        self.users.lock_or_recover().remove(csrf_state);
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ConnectForm {
+    lastfm_username: String,
+}
+
+/// `GET /connect`: a form to connect without using the slash command
+pub async fn page() -> Html<String> {
+    Html(render(None))
+}
+
+/// `POST /connect`: starts the same OAuth flow as /connect once the username checks out
+pub async fn submit(State(state): State<AppState>, Form(form): Form<ConnectForm>) -> Response {
+    info!("Received connect form");
+
+    let lastfm_username = form.lastfm_username.trim();
+    if lastfm_username.is_empty() {
+        return Html(render(Some("Please enter your Last.fm username"))).into_response();
+    }
+
+    match state.lastfm_client.does_user_exist(lastfm_username).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Html(render(Some(&format!(
+                "The Last.fm user {} doesn't exist. Make sure you're using the username from the URL (https://www.last.fm/user/<username>)",
+                lastfm_username
+            ))))
+            .into_response()
+        }
+        Err(e) => {
+            error!("Error checking if {} exists: {:?}", lastfm_username, e);
+            return Html(render(Some("Couldn't reach Last.fm. Please try again later")))
+                .into_response();
+        }
+    }
+
+    let oauth_client = create_oauth_client(&state.secrets.slack_client_secret);
+    let csrf_token = CsrfToken::new_random();
+    let auth_url = authorize_url(
+        &oauth_client,
+        csrf_token.clone(),
+        &user_scopes(state.respect_dnd, false),
+    );
+
+    let added = state.pending_connections.add(
+        csrf_token.secret().clone(),
+        UserData::new(lastfm_username.to_owned(), csrf_token),
+        Utc::now(),
+    );
+    if !added {
+        warn!("Too many web connections are waiting on Slack, turning one away");
+        return Html(render(Some(
+            "Too many people are connecting right now. Please try again in a few minutes",
+        )))
+        .into_response();
+    }
+
+    Redirect::to(auth_url.as_str()).into_response()
+}
+
+fn render(error: Option<&str>) -> String {
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape_html(error)))
+        .unwrap_or_default();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Connect SlackFM</title></head>
+<body>
+<h1>Connect SlackFM</h1>
+{}
+<form method="post" action="/connect">
+<label>Last.fm username <input name="lastfm_username" required></label>
+<button type="submit">Connect with Slack</button>
+</form>
+</body>
+</html>"#,
+        error
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(csrf_state: &str) -> UserData {
+        UserData::new("alice".to_owned(), CsrfToken::new(csrf_state.to_owned()))
+    }
+
+    #[test]
+    fn pending_connections_are_capped_until_they_expire() {
+        let connections = PendingConnections::default();
+        let now = Utc::now();
+
+        for i in 0..MAX_PENDING_CONNECTIONS {
+            let csrf_state = format!("state-{}", i);
+            assert!(connections.add(csrf_state.clone(), pending(&csrf_state), now));
+        }
+        assert!(!connections.add("one-too-many".to_owned(), pending("one-too-many"), now));
+        assert!(connections.get("state-0", now).is_some());
+
+        connections.remove("state-0");
+        assert!(connections.get("state-0", now).is_none());
+        assert!(connections.add("one-more".to_owned(), pending("one-more"), now));
+
+        let later = now + chrono::TimeDelta::minutes(crate::db::CSRF_TTL_MINUTES + 1);
+        assert!(connections.get("one-more", later).is_none());
+        assert!(connections.add("after".to_owned(), pending("after"), later));
+        assert_eq!(connections.users.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn errors_are_escaped() {
+        let page = render(Some("The Last.fm user <script> doesn't exist"));
+        assert!(page.contains("The Last.fm user &lt;script&gt; doesn't exist"));
+        assert!(!page.contains("<script>"));
+    }
+}
@@ -6,15 +6,120 @@ require_envs! {
     lastfm_key, "LASTFM_API_KEY", String,
     "Please set your last.fm API key in the environment variable LASTFM_API_KEY";
 
+    lastfm_api_base?, "LASTFM_API_BASE", String,
+    "Optionally set the Last.fm API URL in LASTFM_API_BASE, e.g. to use a mock server. Defaults to https://ws.audioscrobbler.com/2.0/";
+
+    lastfm_shared_secret?, "LASTFM_SHARED_SECRET", String,
+    "Optionally set your last.fm API key's shared secret in LASTFM_SHARED_SECRET to enable /love and /unlove";
+
+    spotify_client_id?, "SPOTIFY_CLIENT_ID", String,
+    "Optionally set your Spotify app's client id in SPOTIFY_CLIENT_ID (along with SPOTIFY_CLIENT_SECRET) to let users follow Spotify instead of Last.fm with /spotify. The app needs https://slackfm.wobbl.in/spotify/auth as a redirect URI";
+
+    spotify_client_secret?, "SPOTIFY_CLIENT_SECRET", String,
+    "Optionally set your Spotify app's client secret in SPOTIFY_CLIENT_SECRET (along with SPOTIFY_CLIENT_ID) to enable /spotify";
+
+    log_format?, "LOG_FORMAT", String,
+    "Optionally set how logs are written in LOG_FORMAT (pretty, or json for log aggregators). Defaults to pretty";
+
+    bind_addr?, "BIND_ADDR", String,
+    "Optionally set the address and port to listen on in BIND_ADDR, e.g. 0.0.0.0:8080. Defaults to 127.0.0.1:5127";
+
     slack_team_id, "SLACK_TEAM_ID", String,
     "Please set your slack team id in the environment variable SLACK_TEAM_ID";
 
     slack_client_id, "SLACK_CLIENT_ID", String,
     "Please set your slack client id in the environment variable SLACK_CLIENT_ID";
 
-    slack_client_secret, "SLACK_CLIENT_SECRET", String,
-    "Please set your slack client secret in the environment variable SLACK_CLIENT_SECRET";
+    slack_client_secret?, "SLACK_CLIENT_SECRET", String,
+    "Please set your slack client secret in the environment variable SLACK_CLIENT_SECRET (unless it's loaded from a secrets provider)";
+
+    slack_signing_secret?, "SLACK_SIGNING_SECRET", String,
+    "Please set your slack signing secret in the environment variable SLACK_SIGNING_SECRET (unless it's loaded from a secrets provider)";
+
+    db_key?, "DB_KEY", String,
+    "Optionally set the database encryption key in DB_KEY. Defaults to the slack signing secret";
+
+    db_age_identity?, "DB_AGE_IDENTITY", String,
+    "Optionally set the path of an age identity file (from age-keygen) to encrypt the database with in DB_AGE_IDENTITY, instead of DB_KEY. A database encrypted with DB_KEY is still read, and re-encrypted on the next save";
+
+    db_save_retries?, "DB_SAVE_RETRIES", u32,
+    "Optionally set how many times a failed database write is retried in DB_SAVE_RETRIES. Defaults to 2";
+
+    db_save_retry_delay_ms?, "DB_SAVE_RETRY_DELAY_MS", u64,
+    "Optionally set how many milliseconds to wait between database save retries in DB_SAVE_RETRY_DELAY_MS. Defaults to 100";
+
+    db_flush_seconds?, "DB_FLUSH_SECONDS", u64,
+    "Optionally set how many seconds changes are batched for before the database is written in DB_FLUSH_SECONDS. 0 writes every change straight away, retrying failed writes every 5 seconds. Defaults to 5";
+
+    db_backend?, "DB_BACKEND", String,
+    "Optionally set where users are stored in DB_BACKEND (json, or sqlite when built with the sqlite feature). Defaults to json";
+
+    db_read_only?, "DB_READ_ONLY", bool,
+    "Optionally set DB_READ_ONLY to true to run as a replica that only runs updaters, reloading a database file another instance writes. Only one instance may write the file. Defaults to false";
+
+    updater_shards?, "UPDATER_SHARDS", u32,
+    "Optionally set how many instances (the writer and its replicas) share the updaters in UPDATER_SHARDS. Each user is only updated by one of them. Defaults to 1";
+
+    updater_shard?, "UPDATER_SHARD", u32,
+    "Optionally set which of the UPDATER_SHARDS instances this is in UPDATER_SHARD, counting from 0. Every instance needs a different one. Defaults to 0";
+
+    secrets_provider?, "SECRETS_PROVIDER", String,
+    "Optionally set where secrets are loaded from in SECRETS_PROVIDER (env or vault). Defaults to env";
+
+    vault_addr?, "VAULT_ADDR", String,
+    "Set the Vault server address in VAULT_ADDR when using the vault secrets provider";
+
+    vault_token?, "VAULT_TOKEN", String,
+    "Set the Vault token in VAULT_TOKEN when using the vault secrets provider";
+
+    vault_secret_path?, "VAULT_SECRET_PATH", String,
+    "Set the path of the KV v2 secret (e.g. secret/data/slackfm) in VAULT_SECRET_PATH when using the vault secrets provider";
+
+    now_playing_channel?, "NOW_PLAYING_CHANNEL", String,
+    "Optionally set a channel id in NOW_PLAYING_CHANNEL to keep a shared now playing message in (requires SLACK_BOT_TOKEN)";
+
+    slack_bot_token?, "SLACK_BOT_TOKEN", String,
+    "Optionally set a bot token with chat:write and pins:write in SLACK_BOT_TOKEN for posting to channels";
+
+    empty_name_behavior?, "EMPTY_NAME_BEHAVIOR", String,
+    "Optionally set what to do with scrobbles without a track name in EMPTY_NAME_BEHAVIOR (skip or album). Defaults to skip";
+
+    now_playing_emoji?, "NOW_PLAYING_EMOJI", String,
+    "Optionally set the status emoji used while listening in NOW_PLAYING_EMOJI. Defaults to :music:";
+
+    loved_emoji?, "LOVED_EMOJI", String,
+    "Optionally set the status emoji used while listening to a loved track in LOVED_EMOJI. Defaults to :heart:";
+
+    idle_emoji?, "IDLE_EMOJI", String,
+    "Optionally set the status emoji shown after a user stops listening in IDLE_EMOJI (e.g. :zzz:). Users can pick their own with /idle. Defaults to blank";
+
+    idle_text?, "IDLE_TEXT", String,
+    "Optionally set the status text shown after a user stops listening in IDLE_TEXT. Defaults to blank";
+
+    stop_grace_seconds?, "STOP_GRACE_SECONDS", u64,
+    "Optionally set how many seconds to wait after a user stops playing before clearing their status in STOP_GRACE_SECONDS. Defaults to 0";
+
+    debounce_seconds?, "DEBOUNCE_SECONDS", u64,
+    "Optionally set how many seconds a new track has to keep playing before it's shown in DEBOUNCE_SECONDS, so skipping through songs doesn't update the status for each one. Defaults to 0";
+
+    latest_scrobble_window_seconds?, "LATEST_SCROBBLE_WINDOW_SECONDS", u64,
+    "Optionally set how many seconds a scrobble counts as playing for users who turned on /latest in LATEST_SCROBBLE_WINDOW_SECONDS. Defaults to 600";
+
+    expiry_padding_seconds?, "EXPIRY_PADDING_SECONDS", u64,
+    "Optionally set how many seconds past the end of a track its status is kept in EXPIRY_PADDING_SECONDS, to make up for polling lag. Defaults to 5";
+
+    slack_timeout_seconds?, "SLACK_TIMEOUT_SECONDS", u64,
+    "Optionally set how many seconds a status update may take before it's abandoned in SLACK_TIMEOUT_SECONDS. Defaults to 30";
+
+    respect_dnd?, "RESPECT_DND", bool,
+    "Optionally set RESPECT_DND to true to leave statuses alone while a user has Do Not Disturb on (users need to grant dnd:read). Defaults to false";
+
+    lang?, "LANG", String,
+    "Optionally set the language replies default to in LANG (en or es, e.g. es_ES.UTF-8). Defaults to English";
+
+    connected_reaction?, "CONNECTED_REACTION", String,
+    "Optionally set the emoji the bot reacts to its connection confirmation with in CONNECTED_REACTION (requires SLACK_BOT_TOKEN). Defaults to white_check_mark";
 
-    slack_signing_secret, "SLACK_SIGNING_SECRET", String,
-    "Please set your slack signing secret in the environment variable SLACK_SIGNING_SECRET";
+    admin_token?, "ADMIN_TOKEN", String,
+    "Optionally set a token for the /admin endpoints in ADMIN_TOKEN, passed as a bearer token. The endpoints are disabled without it";
 }
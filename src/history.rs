@@ -0,0 +1,63 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// How many status changes are kept for each user
+const MAX_ENTRIES: usize = 50;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StatusChange {
+    pub at: DateTime<Utc>,
+    pub text: String,
+    pub emoji: String,
+}
+
+/// An in-memory log of the latest status changes SlackFM made for each user
+#[derive(Default)]
+pub struct StatusHistory {
+    entries: Mutex<HashMap<String, VecDeque<StatusChange>>>,
+}
+
+impl StatusHistory {
+    pub fn record(&self, user_id: &str, text: impl Into<String>, emoji: impl Into<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        let user_entries = entries.entry(user_id.to_owned()).or_default();
+
+        if user_entries.len() == MAX_ENTRIES {
+            user_entries.pop_front();
+        }
+
+        user_entries.push_back(StatusChange {
+            at: Utc::now(),
+            text: text.into(),
+            emoji: emoji.into(),
+        });
+    }
+
+    /// The user's status changes, oldest first
+    pub fn for_user(&self, user_id: &str) -> Vec<StatusChange> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The last status change made for the user
+    pub fn latest(&self, user_id: &str) -> Option<StatusChange> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .and_then(|entries| entries.back().cloned())
+    }
+
+    pub fn remove_user(&self, user_id: &str) {
+        self.entries.lock().unwrap().remove(user_id);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{error::Error, fmt};
+
+use chrono::{DateTime, Utc};
+use error_stack::{Report, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a link token lets its user do, so a token handed out for one link can't be used on another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// Reading their status history
+    Log,
+    /// Letting SlackFM love tracks on their Last.fm account
+    LastfmAuth,
+    /// Letting SlackFM see what they're playing on Spotify
+    SpotifyAuth,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Log => "log",
+            Purpose::LastfmAuth => "lastfm",
+            Purpose::SpotifyAuth => "spotify",
+        }
+    }
+}
+
+/// Short-lived tokens that let a user access their own data through a link, in the form
+/// `<purpose>.<slack user id>.<expiry unix timestamp>.<hex hmac of the first three parts>`
+#[derive(Debug)]
+pub enum LinkTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    WrongPurpose,
+}
+
+impl fmt::Display for LinkTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkTokenError::Malformed => f.write_str("The link token is malformed"),
+            LinkTokenError::InvalidSignature => {
+                f.write_str("The link token has been tampered with")
+            }
+            LinkTokenError::Expired => f.write_str("The link token has expired"),
+            LinkTokenError::WrongPurpose => {
+                f.write_str("The link token was issued for another link")
+            }
+        }
+    }
+}
+
+impl Error for LinkTokenError {}
+
+fn mac(key: &str, payload: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+pub fn sign(key: &str, purpose: Purpose, user_id: &str, expires_at: DateTime<Utc>) -> String {
+    let payload = format!(
+        "{}.{}.{}",
+        purpose.as_str(),
+        user_id,
+        expires_at.timestamp()
+    );
+    let signature = hex::encode(mac(key, &payload).finalize().into_bytes());
+
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks the token's signature, purpose and expiry, returning the slack user id it was issued for
+pub fn verify(
+    key: &str,
+    purpose: Purpose,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Result<String, LinkTokenError> {
+    let (payload, signature) = token
+        .rsplit_once('.')
+        .ok_or_else(|| Report::new(LinkTokenError::Malformed))?;
+    let (token_purpose, rest) = payload
+        .split_once('.')
+        .ok_or_else(|| Report::new(LinkTokenError::Malformed))?;
+    let (user_id, expires_at) = rest
+        .split_once('.')
+        .ok_or_else(|| Report::new(LinkTokenError::Malformed))?;
+
+    let signature = hex::decode(signature).map_err(|e| {
+        Report::new(LinkTokenError::Malformed).attach_printable(format!("Bad signature: {}", e))
+    })?;
+
+    mac(key, payload)
+        .verify_slice(&signature)
+        .map_err(|_| Report::new(LinkTokenError::InvalidSignature))?;
+
+    if token_purpose != purpose.as_str() {
+        return Err(Report::new(LinkTokenError::WrongPurpose)
+            .attach_printable(format!("Issued for {}", token_purpose)));
+    }
+
+    let expires_at: i64 = expires_at.parse().map_err(|e| {
+        Report::new(LinkTokenError::Malformed).attach_printable(format!("Bad expiry: {}", e))
+    })?;
+
+    if now.timestamp() > expires_at {
+        return Err(Report::new(LinkTokenError::Expired));
+    }
+
+    Ok(user_id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    const KEY: &str = "signing-secret";
+
+    #[test]
+    fn valid_token_verifies() {
+        let now = Utc::now();
+        let token = sign(KEY, Purpose::Log, "U123", now + Duration::minutes(15));
+
+        assert_eq!(verify(KEY, Purpose::Log, &token, now).unwrap(), "U123");
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let now = Utc::now();
+        let token = sign(KEY, Purpose::Log, "U123", now - Duration::minutes(1));
+
+        let err = verify(KEY, Purpose::Log, &token, now).unwrap_err();
+        assert!(matches!(err.current_context(), LinkTokenError::Expired));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let now = Utc::now();
+        let token = sign(KEY, Purpose::Log, "U123", now + Duration::minutes(15));
+        let tampered = token.replacen("U123", "U456", 1);
+
+        let err = verify(KEY, Purpose::Log, &tampered, now).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            LinkTokenError::InvalidSignature
+        ));
+
+        let err = verify("another-key", Purpose::Log, &token, now).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            LinkTokenError::InvalidSignature
+        ));
+    }
+
+    #[test]
+    fn token_for_another_link_is_rejected() {
+        let now = Utc::now();
+        let token = sign(KEY, Purpose::Log, "U123", now + Duration::minutes(15));
+
+        let err = verify(KEY, Purpose::SpotifyAuth, &token, now).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            LinkTokenError::WrongPurpose
+        ));
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        let err = verify(KEY, Purpose::Log, "not a token", Utc::now()).unwrap_err();
+        assert!(matches!(err.current_context(), LinkTokenError::Malformed));
+    }
+}
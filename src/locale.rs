@@ -0,0 +1,726 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// The languages SlackFM can reply in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+/// Declares [`Message`] along with a list of its variants, so the tests can't miss a new one
+macro_rules! messages {
+    ($($variant:ident),* $(,)?) => {
+        /// A reply that doesn't need any values filled in
+        #[derive(Debug, Clone, Copy)]
+        pub enum Message {
+            $($variant),*
+        }
+
+        impl Message {
+            #[cfg(test)]
+            const ALL: &'static [Message] = &[$(Message::$variant),*];
+        }
+    };
+}
+
+messages! {
+    UnknownCommand,
+    UnknownSender,
+    NotInDatabase,
+    NotConnected,
+    LastfmUnreachable,
+    SaveFailed,
+    NotPlaying,
+    Disconnected,
+    DisconnectedStatusKept,
+    DisconnectFailed,
+    DefaultStatusCleared,
+    EmojiUsage,
+    StatusEmojiDefault,
+    IdleUsage,
+    IdleEmojiDefault,
+    ShowAlbumUsage,
+    ShowAlbumOn,
+    ShowAlbumOff,
+    PresenceUsage,
+    PresenceNeedsReauth,
+    PresenceOn,
+    PresenceOff,
+    LatestUsage,
+    LatestOff,
+    MirrorUsage,
+    MirrorUnavailable,
+    MirrorOff,
+    AlreadyPaused,
+    PauseFailed,
+    Paused,
+    NotPaused,
+    ResumeFailed,
+    Resumed,
+    TemplateUsage,
+    TemplateOn,
+    TemplateOff,
+    LangUsage,
+    LastfmUsage,
+    StatusUpdating,
+    StatusNotUpdating,
+    NoStatusYet,
+    IntervalUsage,
+    ApiKeyUsage,
+    ApiKeyRejected,
+    ApiKeyOwn,
+    ApiKeyServer,
+    CountingTopMusic,
+    LoveUnavailable,
+    LoveFailed,
+    LoveWrongAccount,
+    LoveAllowed,
+    SpotifyUnavailable,
+    SpotifyOff,
+    SpotifyDenied,
+    SpotifyOn,
+    ReauthFailed,
+    ConnectUsage,
+    BotsCantConnect,
+    UsernameUpdated,
+    ConnectFailed,
+}
+
+#[derive(Debug)]
+pub struct ParseLocaleError(String);
+
+impl fmt::Display for ParseLocaleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown locale {:?}, expected \"en\" or \"es\"", self.0)
+    }
+}
+
+impl Error for ParseLocaleError {}
+
+/// Accepts both plain codes (`es`) and `LANG` style values (`es_ES.UTF-8`)
+impl FromStr for Locale {
+    type Err = ParseLocaleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let language = s
+            .split(['_', '-', '.'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match language.as_str() {
+            "en" => Ok(Self::En),
+            "es" => Ok(Self::Es),
+            _ => Err(ParseLocaleError(s.to_owned())),
+        }
+    }
+}
+
+impl Locale {
+    /// The locale from a `LANG` style value, falling back to English for anything unknown
+    pub fn from_lang(lang: Option<&str>) -> Self {
+        lang.and_then(|lang| lang.parse().ok()).unwrap_or_default()
+    }
+
+    pub fn text(self, message: Message) -> &'static str {
+        match (self, message) {
+            (Self::En, Message::UnknownCommand) => "Received unknown command",
+            (Self::Es, Message::UnknownCommand) => "Comando desconocido",
+            (Self::En, Message::UnknownSender) => {
+                "Couldn't tell who sent this command. Please try again, or contact the app's admin if this keeps happening"
+            }
+            (Self::Es, Message::UnknownSender) => {
+                "No se pudo saber quién envió este comando. Inténtalo de nuevo, o contacta con el administrador de la app si sigue pasando"
+            }
+            (Self::En, Message::NotInDatabase) => {
+                "You were not found in the database! Please run /connect"
+            }
+            (Self::Es, Message::NotInDatabase) => "¡No estás en la base de datos! Ejecuta /connect",
+            (Self::En, Message::NotConnected) => {
+                "You aren't connected. Run /connect <lastfm username> to get started"
+            }
+            (Self::Es, Message::NotConnected) => {
+                "No estás conectado. Ejecuta /connect <usuario de lastfm> para empezar"
+            }
+            (Self::En, Message::LastfmUnreachable) => {
+                "Couldn't reach Last.fm. Please try again later"
+            }
+            (Self::Es, Message::LastfmUnreachable) => {
+                "No se pudo conectar con Last.fm. Inténtalo de nuevo más tarde"
+            }
+            (Self::En, Message::SaveFailed) => {
+                "Error saving your setting. A report has been logged on the server"
+            }
+            (Self::Es, Message::SaveFailed) => {
+                "Error al guardar tu ajuste. Se ha registrado un informe en el servidor"
+            }
+            (Self::En, Message::NotPlaying) => "You're not playing anything right now",
+            (Self::Es, Message::NotPlaying) => "No estás escuchando nada ahora mismo",
+            (Self::En, Message::Disconnected) => "Disconnected lastfm user",
+            (Self::Es, Message::Disconnected) => "Usuario de lastfm desconectado",
+            (Self::En, Message::DisconnectedStatusKept) => {
+                "Disconnected lastfm user, but your status couldn't be cleared. You may want to clear it yourself"
+            }
+            (Self::Es, Message::DisconnectedStatusKept) => {
+                "Usuario de lastfm desconectado, pero no se pudo borrar tu estado. Puede que quieras borrarlo tú mismo"
+            }
+            (Self::En, Message::DisconnectFailed) => {
+                "Error disconnecting your user. A report has been logged on the server"
+            }
+            (Self::Es, Message::DisconnectFailed) => {
+                "Error al desconectar tu usuario. Se ha registrado un informe en el servidor"
+            }
+            (Self::En, Message::DefaultStatusCleared) => {
+                "Cleared your default status. Your status will be blanked when you stop listening"
+            }
+            (Self::Es, Message::DefaultStatusCleared) => {
+                "Estado predeterminado borrado. Tu estado se vaciará cuando dejes de escuchar"
+            }
+            (Self::En, Message::EmojiUsage) => {
+                "Please use /emoji with a single emoji like :headphones:, or /emoji default to use the server's emoji"
+            }
+            (Self::Es, Message::EmojiUsage) => {
+                "Usa /emoji con un solo emoji como :headphones:, o /emoji default para usar el emoji del servidor"
+            }
+            (Self::En, Message::StatusEmojiDefault) => {
+                "Your status emoji will be the server's default"
+            }
+            (Self::Es, Message::StatusEmojiDefault) => {
+                "Tu emoji de estado será el predeterminado del servidor"
+            }
+            (Self::En, Message::IdleUsage) => {
+                "Please use /idle :emoji:, or /idle default to use the server's idle emoji"
+            }
+            (Self::Es, Message::IdleUsage) => {
+                "Usa /idle :emoji:, o /idle default para usar el emoji de inactividad del servidor"
+            }
+            (Self::En, Message::IdleEmojiDefault) => "Your idle emoji will be the server's default",
+            (Self::Es, Message::IdleEmojiDefault) => {
+                "Tu emoji de inactividad será el predeterminado del servidor"
+            }
+            (Self::En, Message::ShowAlbumUsage) => "Please use /showalbum on or /showalbum off",
+            (Self::Es, Message::ShowAlbumUsage) => "Usa /showalbum on o /showalbum off",
+            (Self::En, Message::ShowAlbumOn) => {
+                "Your status will include the album from the next track on"
+            }
+            (Self::Es, Message::ShowAlbumOn) => {
+                "Tu estado incluirá el álbum a partir de la próxima canción"
+            }
+            (Self::En, Message::ShowAlbumOff) => {
+                "Your status will leave out the album from the next track on"
+            }
+            (Self::Es, Message::ShowAlbumOff) => {
+                "Tu estado no incluirá el álbum a partir de la próxima canción"
+            }
+            (Self::En, Message::PresenceUsage) => "Please use /presence on or /presence off",
+            (Self::Es, Message::PresenceUsage) => "Usa /presence on o /presence off",
+            (Self::En, Message::PresenceNeedsReauth) => {
+                "SlackFM needs permission to set your presence first. Run /reauth to grant it, and you'll then be shown as away when you stop listening and active when you start"
+            }
+            (Self::Es, Message::PresenceNeedsReauth) => {
+                "SlackFM necesita permiso para cambiar tu presencia primero. Ejecuta /reauth para darlo, y aparecerás como ausente cuando dejes de escuchar y activo cuando empieces"
+            }
+            (Self::En, Message::PresenceOn) => {
+                "You'll be shown as away when you stop listening and active when you start"
+            }
+            (Self::Es, Message::PresenceOn) => {
+                "Aparecerás como ausente cuando dejes de escuchar y activo cuando empieces"
+            }
+            (Self::En, Message::PresenceOff) => "Your presence will no longer follow your music",
+            (Self::Es, Message::PresenceOff) => "Tu presencia ya no seguirá tu música",
+            (Self::En, Message::LatestUsage) => "Please use /latest on or /latest off",
+            (Self::Es, Message::LatestUsage) => "Usa /latest on o /latest off",
+            (Self::En, Message::LatestOff) => {
+                "Only tracks Last.fm says you're playing will be shown"
+            }
+            (Self::Es, Message::LatestOff) => {
+                "Solo se mostrarán las canciones que Last.fm diga que estás escuchando"
+            }
+            (Self::En, Message::MirrorUsage) => "Please use /mirror #channel or /mirror off",
+            (Self::Es, Message::MirrorUsage) => "Usa /mirror #canal o /mirror off",
+            (Self::En, Message::MirrorUnavailable) => {
+                "Mirroring to a channel isn't set up on this server"
+            }
+            (Self::Es, Message::MirrorUnavailable) => {
+                "Publicar en un canal no está configurado en este servidor"
+            }
+            (Self::En, Message::MirrorOff) => "Your tracks will no longer be posted to a channel",
+            (Self::Es, Message::MirrorOff) => "Tus canciones ya no se publicarán en un canal",
+            (Self::En, Message::AlreadyPaused) => {
+                "SlackFM is already paused. Use /resume to carry on"
+            }
+            (Self::Es, Message::AlreadyPaused) => {
+                "SlackFM ya está en pausa. Usa /resume para continuar"
+            }
+            (Self::En, Message::PauseFailed) => {
+                "Error pausing SlackFM. A report has been logged on the server"
+            }
+            (Self::Es, Message::PauseFailed) => {
+                "Error al pausar SlackFM. Se ha registrado un informe en el servidor"
+            }
+            (Self::En, Message::Paused) => {
+                "Paused. Your status won't be updated until you use /resume"
+            }
+            (Self::Es, Message::Paused) => {
+                "En pausa. Tu estado no se actualizará hasta que uses /resume"
+            }
+            (Self::En, Message::NotPaused) => "SlackFM isn't paused",
+            (Self::Es, Message::NotPaused) => "SlackFM no está en pausa",
+            (Self::En, Message::ResumeFailed) => {
+                "Error resuming SlackFM. A report has been logged on the server"
+            }
+            (Self::Es, Message::ResumeFailed) => {
+                "Error al reanudar SlackFM. Se ha registrado un informe en el servidor"
+            }
+            (Self::En, Message::Resumed) => {
+                "Resumed. Your status will show what you're listening to again"
+            }
+            (Self::Es, Message::Resumed) => {
+                "Reanudado. Tu estado volverá a mostrar lo que estás escuchando"
+            }
+            (Self::En, Message::TemplateUsage) => {
+                "Please use /template <template>, e.g. /template {track} by {artist} ({album}), or /template off"
+            }
+            (Self::Es, Message::TemplateUsage) => {
+                "Usa /template <plantilla>, p. ej. /template {track} de {artist} ({album}), o /template off"
+            }
+            (Self::En, Message::TemplateOn) => {
+                "Your status will use your template from the next track on"
+            }
+            (Self::Es, Message::TemplateOn) => {
+                "Tu estado usará tu plantilla a partir de la próxima canción"
+            }
+            (Self::En, Message::TemplateOff) => {
+                "Your status will use the default format from the next track on"
+            }
+            (Self::Es, Message::TemplateOff) => {
+                "Tu estado usará el formato predeterminado a partir de la próxima canción"
+            }
+            (Self::En, Message::LangUsage) => {
+                "Please give a language (en or es), e.g. /lang es, or /lang default"
+            }
+            (Self::Es, Message::LangUsage) => {
+                "Indica un idioma (en o es), p. ej. /lang es, o /lang default"
+            }
+            (Self::En, Message::LastfmUsage) => "No username found. Please give one, e.g. /lastfm rj",
+            (Self::Es, Message::LastfmUsage) => {
+                "No se encontró ningún usuario. Indica uno, p. ej. /lastfm rj"
+            }
+            (Self::En, Message::StatusUpdating) => "Your status is being kept up to date",
+            (Self::Es, Message::StatusUpdating) => "Tu estado se está manteniendo al día",
+            (Self::En, Message::StatusNotUpdating) => {
+                "Your status isn't being updated right now. Run /connect <lastfm username> to restart it"
+            }
+            (Self::Es, Message::StatusNotUpdating) => {
+                "Tu estado no se está actualizando ahora mismo. Ejecuta /connect <usuario de lastfm> para reiniciarlo"
+            }
+            (Self::En, Message::NoStatusYet) => "No status has been set since the server started",
+            (Self::Es, Message::NoStatusYet) => {
+                "No se ha puesto ningún estado desde que se inició el servidor"
+            }
+            (Self::En, Message::IntervalUsage) => {
+                "Please give an interval in seconds, e.g. /interval 30"
+            }
+            (Self::Es, Message::IntervalUsage) => "Indica un intervalo en segundos, p. ej. /interval 30",
+            (Self::En, Message::ApiKeyUsage) => {
+                "Please give your Last.fm API key, e.g. /apikey <key>, or /apikey clear to use the server's"
+            }
+            (Self::Es, Message::ApiKeyUsage) => {
+                "Indica tu clave de API de Last.fm, p. ej. /apikey <clave>, o /apikey clear para usar la del servidor"
+            }
+            (Self::En, Message::ApiKeyRejected) => {
+                "Last.fm didn't accept that API key. Double check it at https://www.last.fm/api/accounts"
+            }
+            (Self::Es, Message::ApiKeyRejected) => {
+                "Last.fm no aceptó esa clave de API. Compruébala en https://www.last.fm/api/accounts"
+            }
+            (Self::En, Message::ApiKeyOwn) => "Now using your own Last.fm API key",
+            (Self::Es, Message::ApiKeyOwn) => "Ahora se usa tu propia clave de API de Last.fm",
+            (Self::En, Message::ApiKeyServer) => "Now using the server's Last.fm API key",
+            (Self::Es, Message::ApiKeyServer) => "Ahora se usa la clave de API de Last.fm del servidor",
+            (Self::En, Message::CountingTopMusic) => "Counting what everyone's been listening to...",
+            (Self::Es, Message::CountingTopMusic) => "Contando lo que todos han estado escuchando...",
+            (Self::En, Message::LoveUnavailable) => "Loving tracks isn't enabled on this server",
+            (Self::Es, Message::LoveUnavailable) => {
+                "Marcar canciones como favoritas no está activado en este servidor"
+            }
+            (Self::En, Message::LoveFailed) => {
+                "Last.fm didn't accept the change. Please try again later"
+            }
+            (Self::Es, Message::LoveFailed) => {
+                "Last.fm no aceptó el cambio. Inténtalo de nuevo más tarde"
+            }
+            (Self::En, Message::LoveWrongAccount) => {
+                "You allowed a different Last.fm account than the one you connected. Please log in to Last.fm as that account and try again"
+            }
+            (Self::Es, Message::LoveWrongAccount) => {
+                "Autorizaste una cuenta de Last.fm distinta a la que conectaste. Inicia sesión en Last.fm con esa cuenta e inténtalo de nuevo"
+            }
+            (Self::En, Message::LoveAllowed) => {
+                "SlackFM can now love tracks for you. Run /love again in Slack"
+            }
+            (Self::Es, Message::LoveAllowed) => {
+                "SlackFM ya puede marcar canciones como favoritas por ti. Ejecuta /love de nuevo en Slack"
+            }
+            (Self::En, Message::SpotifyUnavailable) => {
+                "Following Spotify isn't enabled on this server"
+            }
+            (Self::Es, Message::SpotifyUnavailable) => {
+                "Seguir Spotify no está activado en este servidor"
+            }
+            (Self::En, Message::SpotifyOff) => "Your status will follow Last.fm again",
+            (Self::Es, Message::SpotifyOff) => "Tu estado volverá a seguir Last.fm",
+            (Self::En, Message::SpotifyDenied) => {
+                "SlackFM wasn't allowed to see what you're playing on Spotify, so your status will keep following Last.fm"
+            }
+            (Self::Es, Message::SpotifyDenied) => {
+                "SlackFM no tiene permiso para ver lo que escuchas en Spotify, así que tu estado seguirá a Last.fm"
+            }
+            (Self::En, Message::SpotifyOn) => {
+                "Your status will now follow Spotify. Run /spotify off in Slack to go back to Last.fm"
+            }
+            (Self::Es, Message::SpotifyOn) => {
+                "Tu estado ahora seguirá Spotify. Ejecuta /spotify off en Slack para volver a Last.fm"
+            }
+            (Self::En, Message::ReauthFailed) => {
+                "Error starting reauthorization. A report has been logged on the server"
+            }
+            (Self::Es, Message::ReauthFailed) => {
+                "Error al iniciar la reautorización. Se ha registrado un informe en el servidor"
+            }
+            (Self::En, Message::ConnectUsage) => {
+                "No username found. Please give one, e.g. /connect rj"
+            }
+            (Self::Es, Message::ConnectUsage) => {
+                "No se encontró ningún usuario. Indica uno, p. ej. /connect rj"
+            }
+            (Self::En, Message::BotsCantConnect) => {
+                "Bots and apps can't have a status, so they can't be connected to SlackFM"
+            }
+            (Self::Es, Message::BotsCantConnect) => {
+                "Los bots y las apps no pueden tener estado, así que no se pueden conectar a SlackFM"
+            }
+            (Self::En, Message::UsernameUpdated) => "Updated Last.fm username",
+            (Self::Es, Message::UsernameUpdated) => "Usuario de Last.fm actualizado",
+            (Self::En, Message::ConnectFailed) => {
+                "Error adding your user to the database. A report has been logged on the server"
+            }
+            (Self::Es, Message::ConnectFailed) => {
+                "Error al añadir tu usuario a la base de datos. Se ha registrado un informe en el servidor"
+            }
+        }
+    }
+
+    pub fn listening_to(
+        self,
+        lastfm_username: &str,
+        name: &str,
+        artist: &str,
+        album: &str,
+    ) -> String {
+        match self {
+            Self::En => format!(
+                "{} is listening to {} - {} from the album {}",
+                lastfm_username, name, artist, album
+            ),
+            Self::Es => format!(
+                "{} está escuchando {} - {} del álbum {}",
+                lastfm_username, name, artist, album
+            ),
+        }
+    }
+
+    pub fn not_listening(self, lastfm_username: &str) -> String {
+        match self {
+            Self::En => format!("{} isn't listening to anything right now", lastfm_username),
+            Self::Es => format!("{} no está escuchando nada ahora mismo", lastfm_username),
+        }
+    }
+
+    /// The Last.fm user `lastfm_username` couldn't be found
+    pub fn lastfm_user_missing(self, lastfm_username: &str) -> String {
+        match self {
+            Self::En => format!(
+                "The Last.fm user {} doesn't exist. Make sure you're using the username from the URL (https://www.last.fm/user/<username>)",
+                lastfm_username
+            ),
+            Self::Es => format!(
+                "El usuario de Last.fm {} no existe. Asegúrate de usar el nombre de usuario de la URL (https://www.last.fm/user/<usuario>)",
+                lastfm_username
+            ),
+        }
+    }
+
+    pub fn default_status_saved(self, emoji: &str, text: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Saved your default status: {} {}. It'll be set whenever you stop listening",
+                emoji, text
+            ),
+            Self::Es => format!(
+                "Estado predeterminado guardado: {} {}. Se pondrá cada vez que dejes de escuchar",
+                emoji, text
+            ),
+        }
+    }
+
+    pub fn status_emoji(self, emoji: &str) -> String {
+        match self {
+            Self::En => format!("Your status emoji will be {} while you're listening", emoji),
+            Self::Es => format!("Tu emoji de estado será {} mientras escuchas", emoji),
+        }
+    }
+
+    pub fn idle_emoji(self, emoji: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Your status emoji will be {} after you stop listening",
+                emoji
+            ),
+            Self::Es => format!("Tu emoji de estado será {} cuando dejes de escuchar", emoji),
+        }
+    }
+
+    pub fn latest_on(self, minutes: u64) -> String {
+        match self {
+            Self::En => format!(
+                "Your latest scrobble will count as playing for {} minutes when Last.fm doesn't say what you're playing",
+                minutes
+            ),
+            Self::Es => format!(
+                "Tu último scrobble contará como en reproducción durante {} minutos cuando Last.fm no diga qué estás escuchando",
+                minutes
+            ),
+        }
+    }
+
+    pub fn mirroring_to(self, channel: &str) -> String {
+        match self {
+            Self::En => format!(
+                "The tracks you play will also be posted to <#{}>. Make sure SlackFM's bot is in the channel",
+                channel
+            ),
+            Self::Es => format!(
+                "Las canciones que escuches también se publicarán en <#{}>. Asegúrate de que el bot de SlackFM esté en el canal",
+                channel
+            ),
+        }
+    }
+
+    pub fn spotify_link(self, url: &str, minutes: i64) -> String {
+        match self {
+            Self::En => format!(
+                "Please visit {} to let SlackFM see what you're playing on Spotify. The link expires in {} minutes",
+                url, minutes
+            ),
+            Self::Es => format!(
+                "Visita {} para que SlackFM pueda ver lo que escuchas en Spotify. El enlace caduca en {} minutos",
+                url, minutes
+            ),
+        }
+    }
+
+    pub fn connected_to(self, lastfm_username: &str) -> String {
+        match self {
+            Self::En => format!("Connected to the Last.fm user {}", lastfm_username),
+            Self::Es => format!("Conectado al usuario de Last.fm {}", lastfm_username),
+        }
+    }
+
+    pub fn missing_scopes(self, scopes: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Some features need permissions you haven't granted yet ({}). Run /reauth to grant them",
+                scopes
+            ),
+            Self::Es => format!(
+                "Algunas funciones necesitan permisos que aún no has dado ({}). Ejecuta /reauth para darlos",
+                scopes
+            ),
+        }
+    }
+
+    pub fn last_status_cleared(self, at: &str) -> String {
+        match self {
+            Self::En => format!("Last status set: cleared, at {}", at),
+            Self::Es => format!("Último estado puesto: borrado, a las {}", at),
+        }
+    }
+
+    pub fn last_status(self, emoji: &str, text: &str, at: &str) -> String {
+        match self {
+            Self::En => format!("Last status set: {} {}, at {}", emoji, text, at),
+            Self::Es => format!("Último estado puesto: {} {}, a las {}", emoji, text, at),
+        }
+    }
+
+    pub fn authorization_pending(self, lastfm_username: &str, url: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Authorization pending for the Last.fm user {} — finish at {}",
+                lastfm_username, url
+            ),
+            Self::Es => format!(
+                "Autorización pendiente para el usuario de Last.fm {} — termínala en {}",
+                lastfm_username, url
+            ),
+        }
+    }
+
+    pub fn interval_too_short(self, min_seconds: u64) -> String {
+        match self {
+            Self::En => format!(
+                "That's a bit too often! Please pick an interval of at least {} seconds",
+                min_seconds
+            ),
+            Self::Es => format!(
+                "¡Eso es demasiado a menudo! Elige un intervalo de al menos {} segundos",
+                min_seconds
+            ),
+        }
+    }
+
+    pub fn checking_every(self, seconds: u64) -> String {
+        match self {
+            Self::En => format!("Now checking Last.fm every {} seconds", seconds),
+            Self::Es => format!("Ahora se comprueba Last.fm cada {} segundos", seconds),
+        }
+    }
+
+    pub fn log_link(self, url: &str, minutes: i64) -> String {
+        match self {
+            Self::En => format!(
+                "Here's your status history: {}. The link expires in {} minutes",
+                url, minutes
+            ),
+            Self::Es => format!(
+                "Aquí tienes tu historial de estados: {}. El enlace caduca en {} minutos",
+                url, minutes
+            ),
+        }
+    }
+
+    pub fn love_link(self, url: &str, minutes: i64) -> String {
+        match self {
+            Self::En => format!(
+                "Please visit {} to let SlackFM love tracks on your Last.fm account, then try again. The link expires in {} minutes",
+                url, minutes
+            ),
+            Self::Es => format!(
+                "Visita {} para que SlackFM pueda marcar canciones como favoritas en tu cuenta de Last.fm, y vuelve a intentarlo. El enlace caduca en {} minutos",
+                url, minutes
+            ),
+        }
+    }
+
+    pub fn loved(self, track: &str) -> String {
+        match self {
+            Self::En => format!("Loved {}", track),
+            Self::Es => format!("{} marcada como favorita", track),
+        }
+    }
+
+    pub fn unloved(self, track: &str) -> String {
+        match self {
+            Self::En => format!("Unloved {}", track),
+            Self::Es => format!("{} ya no está marcada como favorita", track),
+        }
+    }
+
+    pub fn reauth_link(self, url: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Please visit {} to grant SlackFM its current permissions. Your settings will be kept",
+                url
+            ),
+            Self::Es => format!(
+                "Visita {} para dar a SlackFM sus permisos actuales. Tus ajustes se mantendrán",
+                url
+            ),
+        }
+    }
+
+    pub fn connect_link(self, url: &str) -> String {
+        match self {
+            Self::En => format!(
+                "Please visit {} to allow SlackFM to access and modify your profile/status",
+                url
+            ),
+            Self::Es => format!(
+                "Visita {} para permitir que SlackFM acceda a tu perfil/estado y lo modifique",
+                url
+            ),
+        }
+    }
+
+    /// The DM sent once a user finished connecting
+    pub fn connected(self, lastfm_username: &str) -> String {
+        match self {
+            Self::En => format!(
+                "You're connected! Your status will now follow the Last.fm user {}",
+                lastfm_username
+            ),
+            Self::Es => format!(
+                "¡Ya estás conectado! Tu estado ahora seguirá al usuario de Last.fm {}",
+                lastfm_username
+            ),
+        }
+    }
+
+    /// Confirms the locale was changed, in the new locale
+    pub fn changed(self) -> &'static str {
+        match self {
+            Self::En => "SlackFM will reply in English from now on",
+            Self::Es => "SlackFM responderá en español a partir de ahora",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lang_values() {
+        assert_eq!("es".parse::<Locale>().unwrap(), Locale::Es);
+        assert_eq!("es_ES.UTF-8".parse::<Locale>().unwrap(), Locale::Es);
+        assert_eq!("EN-us".parse::<Locale>().unwrap(), Locale::En);
+        assert!("fr".parse::<Locale>().is_err());
+    }
+
+    #[test]
+    fn unknown_lang_falls_back_to_english() {
+        assert_eq!(Locale::from_lang(Some("fr_FR.UTF-8")), Locale::En);
+        assert_eq!(Locale::from_lang(Some("C")), Locale::En);
+        assert_eq!(Locale::from_lang(None), Locale::En);
+    }
+
+    #[test]
+    fn every_message_has_a_translation() {
+        for &message in Message::ALL {
+            let (en, es) = (Locale::En.text(message), Locale::Es.text(message));
+            assert!(!en.is_empty() && !es.is_empty(), "{:?} is empty", message);
+            assert_ne!(en, es, "{:?} isn't translated to Spanish", message);
+        }
+    }
+
+    #[test]
+    fn messages_are_translated() {
+        assert_eq!(
+            Locale::Es.not_listening("rj"),
+            "rj no está escuchando nada ahora mismo"
+        );
+        assert_ne!(
+            Locale::Es.text(Message::NotInDatabase),
+            Locale::En.text(Message::NotInDatabase)
+        );
+        assert_eq!(
+            Locale::Es.mirroring_to("C0123"),
+            "Las canciones que escuches también se publicarán en <#C0123>. Asegúrate de que el bot de SlackFM esté en el canal"
+        );
+    }
+}
@@ -1,25 +1,100 @@
+mod admin;
+mod board;
+mod connect_page;
 mod db;
 pub mod env;
+mod history;
+mod link_token;
+mod locale;
 mod oauth;
+mod scheduler;
+mod secrets;
+mod store;
+mod top_music;
 
-use std::{collections::HashMap, error::Error, fmt, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
     extract::{Query, State},
+    http::StatusCode,
     Extension,
 };
-use db::{Db, UserData};
+use board::NowPlayingBoard;
+use chrono::{TimeDelta, Utc};
+use connect_page::PendingConnections;
+use db::{Db, DbError, DefaultStatus, LockExt, UserData, UserSettings, MIN_POLL_INTERVAL_SECS};
 use dotenvy::dotenv;
 use error_stack::{Result, ResultExt};
-use futures::{pin_mut, stream, StreamExt};
-use oauth::{create_oauth_client, OauthCode};
-use oauth2::{reqwest::async_http_client, AuthorizationCode, CsrfToken};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use history::{StatusChange, StatusHistory};
+use locale::{Locale, Message};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use oauth::{authorize_url, create_oauth_client, user_scopes, OauthCode};
+use oauth2::{reqwest::async_http_client, url::Url, AuthorizationCode, CsrfToken};
+use scheduler::{PollScheduler, ScheduledLastfm};
+use secrets::{EnvSecretProvider, SecretError, Secrets};
 use slack_morphism::prelude::*;
-use slackfm::{lastfm, slack};
-use tokio::{net::TcpListener, sync::Mutex, task::AbortHandle};
-use tracing::{debug, error, info};
+use slackfm::{
+    lastfm,
+    slack::{self, SlackError, UserStatus},
+    source::{MusicSource, NowPlaying},
+    spotify::{SpotifyClient, SpotifyError},
+    status::{self, EmptyNameBehavior},
+};
+use store::{EncryptedJsonStore, SaveRetry};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, RwLock},
+    task::AbortHandle,
+    time::Instant,
+};
+use top_music::RecentTracksCache;
+use tracing::{debug, error, info, warn};
 use tracing_error::ErrorLayer;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter};
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer};
+
+/// Where SlackFM is publicly reachable
+pub const PUBLIC_URL: &str = "https://slackfm.wobbl.in";
+
+/// How long a /mylog link stays valid
+const LOG_LINK_TTL_MINUTES: i64 = 15;
+/// How long the link letting SlackFM love tracks on Last.fm stays valid
+const LASTFM_AUTH_LINK_TTL_MINUTES: i64 = 15;
+/// How long the link for connecting Spotify stays valid
+const SPOTIFY_AUTH_LINK_TTL_MINUTES: i64 = 15;
+
+/// How long before a rotating Slack token expires it gets refreshed
+const TOKEN_REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// How often connections that were started but never finished are cleaned up
+const ABANDONED_CONNECTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 10);
+/// How often the database file is rewritten from scratch
+const DB_COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60 * 6);
+/// How long changes are batched for before the database is written, unless DB_FLUSH_SECONDS is set
+const DEFAULT_DB_FLUSH_SECONDS: u64 = 5;
+/// How long a scrobble counts as playing for users with /latest on, unless
+/// LATEST_SCROBBLE_WINDOW_SECONDS is set
+const DEFAULT_LATEST_SCROBBLE_WINDOW_SECS: u64 = 600;
+/// How long clearing everyone's status may hold up shutting down
+const SHUTDOWN_CLEAR_TIMEOUT: Duration = Duration::from_secs(10);
+/// Where the server listens unless BIND_ADDR is set
+const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5127);
+/// How often a read-only replica checks whether the database file changed
+const DB_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 enum MainError {
@@ -40,8 +115,26 @@ impl Error for MainError {}
 
 #[tokio::main]
 async fn main() -> Result<(), MainError> {
+    // loaded first, so LOG_FORMAT can be set in the .env file too
+    dotenv()
+        .attach_printable("Error loading the .env file")
+        .change_context(MainError::SetupError)?;
+
+    let fmt_layer = match env::log_format().as_deref() {
+        None | Some("pretty") => tracing_subscriber::fmt::layer().boxed(),
+        Some("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        Some(other) => {
+            return Err(
+                error_stack::Report::new(MainError::SetupError).attach_printable(format!(
+                    "Unknown LOG_FORMAT {:?}, expected \"pretty\" or \"json\"",
+                    other
+                )),
+            )
+        }
+    };
+
     let subscriber = tracing_subscriber::Registry::default()
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer)
         .with(ErrorLayer::default())
         .with(EnvFilter::from_default_env());
 
@@ -49,13 +142,23 @@ async fn main() -> Result<(), MainError> {
         .attach_printable("Error setting up the logger")
         .change_context(MainError::SetupError)?;
 
-    dotenv()
-        .attach_printable("Error loading the .env file")
-        .change_context(MainError::SetupError)?;
-
     if env::any_set() {
         env::assert_env_vars();
-        run_server()
+
+        let addr = match env::bind_addr() {
+            Some(addr) => addr
+                .parse::<SocketAddr>()
+                .attach_printable_lazy(|| {
+                    format!(
+                        "Couldn't parse BIND_ADDR {:?}. Expected an address and port like 0.0.0.0:5127 or [::1]:5127",
+                        addr
+                    )
+                })
+                .change_context(MainError::SetupError)?,
+            None => DEFAULT_BIND_ADDR,
+        };
+
+        run_server(addr)
             .await
             .attach_printable("Error running the server")
             .change_context(MainError::ServerError)
@@ -72,6 +175,40 @@ fn error_handler(
 ) -> HttpStatusCode {
     eprintln!("{:#?}", err);
 
+    error_status(&*err)
+}
+
+/// The status Slack gets back for a request that failed. Slack retries 5xx responses but not 4xx
+/// ones, so only requests that would fail the same way again get a 4xx
+fn error_status(err: &(dyn std::error::Error + 'static)) -> HttpStatusCode {
+    use slack_morphism::{
+        errors::SlackClientError,
+        signature_verifier::{SlackEventAbsentSignatureError, SlackEventSignatureVerifierError},
+    };
+
+    if err.is::<SlackEventAbsentSignatureError>() {
+        return HttpStatusCode::UNAUTHORIZED;
+    }
+    if let Some(err) = err.downcast_ref::<SlackEventSignatureVerifierError>() {
+        return match err {
+            // the signing secret couldn't be used, which is on us
+            SlackEventSignatureVerifierError::CryptoInitError(_) => {
+                HttpStatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => HttpStatusCode::UNAUTHORIZED,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<SlackClientError>() {
+        return match err {
+            SlackClientError::ProtocolError(_) => HttpStatusCode::BAD_REQUEST,
+            // e.g. "Absent payload in the request from Slack", for a request missing its body
+            SlackClientError::SystemError(err) if err.cause.is_none() => {
+                HttpStatusCode::BAD_REQUEST
+            }
+            _ => HttpStatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
+
     HttpStatusCode::BAD_REQUEST
 }
 
@@ -80,13 +217,42 @@ async fn command_event(
     Extension(event): Extension<SlackCommandEvent>,
     State(state): State<AppState>,
 ) -> axum::Json<SlackCommandEventResponse> {
+    // every handler keys the database by user id, so never let a blank one through
+    if !has_valid_user_id(&event) {
+        warn!("Received {} without a valid user id", event.command);
+        return ephemeral_response(state.default_locale.text(Message::UnknownSender));
+    }
+
     match &*event.command.0 {
         "/connect" => connect_handler(event, state).await,
         "/disconnect" => disconnect_handler(event, state).await,
+        "/default" => default_handler(event, state).await,
+        "/lastfm" => lastfm_handler(event, state).await,
+        "/status" => status_handler(event, state).await,
+        "/nowplaying" => nowplaying_handler(event, state).await,
+        "/interval" => interval_handler(event, state).await,
+        "/mylog" => mylog_handler(event, state).await,
+        "/reauth" => reauth_handler(event, state).await,
+        "/apikey" => apikey_handler(event, state).await,
+        "/topmusic" => topmusic_handler(event, state).await,
+        "/showalbum" => showalbum_handler(event, state).await,
+        "/lang" => lang_handler(event, state).await,
+        "/emoji" => emoji_handler(event, state).await,
+        "/idle" => idle_handler(event, state).await,
+        "/template" => template_handler(event, state).await,
+        "/presence" => presence_handler(event, state).await,
+        "/mirror" => mirror_handler(event, state).await,
+        "/latest" => latest_handler(event, state).await,
+        "/spotify" => spotify_handler(event, state).await,
+        "/pause" => pause_handler(event, state).await,
+        "/resume" => resume_handler(event, state).await,
+        "/love" => love_handler(event, state, true).await,
+        "/unlove" => love_handler(event, state, false).await,
         _ => {
             info!("Received unknown command");
+            let locale = user_locale(&state, &event.user_id).await;
             axum::Json(SlackCommandEventResponse::new(
-                SlackMessageContent::new().with_text("Received unknown command".into()),
+                SlackMessageContent::new().with_text(locale.text(Message::UnknownCommand).into()),
             ))
         }
     }
@@ -98,347 +264,3286 @@ async fn disconnect_handler(
 ) -> axum::Json<SlackCommandEventResponse> {
     info!("Received disconnect command");
 
-    let mut db = state.db.lock().await;
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let mut db = state.db.write().await;
     let user_id = event.user_id;
 
-    match db.remove_user(&user_id.0) {
-        Ok(Some(_)) => {
-            let abort_handle = state.tasks.lock().await.remove(&user_id.into()).unwrap();
-            abort_handle.abort();
+    // kept past removal so the status can still be cleared, after the updater is stopped and
+    // can't set it again
+    let user = db.user(&user_id.0);
+
+    let removed = disconnect_user(&mut db, &mut *state.tasks.lock().await, &user_id);
+    drop(db);
+
+    match removed {
+        Ok(true) => {
+            state.history.remove_user(&user_id.0);
+
+            let cleared = match &user {
+                Some(user) => clear_disconnected_status(&state, &user_id, user).await,
+                None => true,
+            };
 
+            let text = if cleared {
+                locale.text(Message::Disconnected)
+            } else {
+                locale.text(Message::DisconnectedStatusKept)
+            };
             axum::Json(SlackCommandEventResponse::new(
-                SlackMessageContent::new().with_text("Disconnected lastfm user".into()),
+                SlackMessageContent::new().with_text(text.into()),
             ))
         }
-        Ok(None) => axum::Json(SlackCommandEventResponse::new(
-            SlackMessageContent::new()
-                .with_text("You were not found in the database!. Please run /connect".into()),
+        Ok(false) => axum::Json(SlackCommandEventResponse::new(
+            SlackMessageContent::new().with_text(locale.text(Message::NotInDatabase).into()),
         )),
         Err(e) => {
             error!("Error removing user {}: {}", user_id, e);
             axum::Json(SlackCommandEventResponse::new(
                 SlackMessageContent::new()
-                    .with_text(
-                        "Error disconnecting your user. A report has been logged on the server"
-                            .into(),
-                    )
+                    .with_text(locale.text(Message::DisconnectFailed).into())
                     .into(),
             ))
         }
     }
 }
 
-async fn connect_handler(
-    event: SlackCommandEvent,
-    state: AppState,
-) -> axum::Json<SlackCommandEventResponse> {
-    println!("Received connect command");
+/// Takes SlackFM's status off a user who just disconnected, so a track isn't left on their
+/// profile. Returns whether that worked
+async fn clear_disconnected_status(
+    state: &AppState,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+) -> bool {
+    let token = user_data
+        .lock_or_recover()
+        .slack_token()
+        .map(ToOwned::to_owned);
+    let Some(token) = token else {
+        return true;
+    };
 
-    let Some(lastfm_username) = event.text.and_then(|text| {
-        if text.is_empty() {
-            None
-        } else {
-            Some(
-                text.split_once(" ")
-                    .map(|split| split.0.to_owned())
-                    .unwrap_or_else(|| text.to_owned()),
-            )
+    // Do Not Disturb is ignored, this is the last chance to clear it
+    let mut slack_client = Arc::new(
+        slack::Client::from_client(state.slack_client.clone(), token, admin::team_of(user_data))
+            .with_status_timeout(state.slack_timeout),
+    );
+
+    match take_down_status(state, &mut slack_client, user_id, user_data).await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(
+                "Couldn't clear the status of disconnected user {}: {:?}",
+                user_id, e
+            );
+            false
         }
-    }) else {
-        return axum::Json(SlackCommandEventResponse::new(
-            SlackMessageContent::new().with_text("No username found. Please give one".into()),
-        ));
-    };
+    }
+}
 
-    // check if the lastfm user exists
-    if !state
-        .lastfm_client
-        .does_user_exist(&lastfm_username)
-        .await
-        .unwrap_or(false)
-    {
-        return axum::Json(SlackCommandEventResponse::new(
-            SlackMessageContent::new().with_text("The lastfm username isn't valid/doesn't exist. Make sure you're inputting your username from the URL (https://www.last.fm/user/<username>)".into()),
-        ));
+/// Removes a user and stops their updater, returning whether they were in the database. Users who
+/// never finished connecting don't have an updater to stop
+fn disconnect_user(
+    db: &mut Db,
+    tasks: &mut HashMap<SlackUserId, AbortHandle>,
+    user_id: &SlackUserId,
+) -> std::result::Result<bool, error_stack::Report<DbError>> {
+    let removed = db.remove_user(&user_id.0)?.is_some();
+    if let Some(abort_handle) = tasks.remove(user_id) {
+        abort_handle.abort();
     }
 
-    let mut db = state.db.lock().await;
+    Ok(removed)
+}
 
-    let user = db.user(&event.user_id.0);
+/// Stops the updaters of users who are no longer in the database
+fn stop_orphaned_updaters(db: &Db, tasks: &mut HashMap<SlackUserId, AbortHandle>) {
+    tasks.retain(|user_id, abort_handle| {
+        let exists = db.user(&user_id.0).is_some();
+        if !exists {
+            debug!("Stopping the updater of removed user {}", user_id);
+            abort_handle.abort();
+        }
+        exists
+    });
+}
 
-    if let Some(user) = user.filter(|user| user.lock().unwrap().slack_token().is_some()) {
-        user.lock().unwrap().update_lastfm_username(lastfm_username);
-        db.to_encrypted_file().unwrap();
+/// Stops every updater, e.g. when the server shuts down
+fn stop_all_updaters(tasks: &mut HashMap<SlackUserId, AbortHandle>) {
+    for (user_id, abort_handle) in tasks.drain() {
+        debug!("Stopping the updater of {}", user_id);
+        abort_handle.abort();
+    }
+}
 
-        axum::Json(SlackCommandEventResponse::new(
-            SlackMessageContent::new().with_text("Updated Last.fm username".into()),
-        ))
-    } else {
-        let oauth_client = create_oauth_client();
+/// Slack user ids are short alphanumeric strings like `U012AB3CD`
+fn has_valid_user_id(event: &SlackCommandEvent) -> bool {
+    let user_id = &event.user_id.0;
+    !user_id.is_empty() && user_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
 
-        // note: we aren't doing PKCE since this is only ran on a trusted server
+fn ephemeral_response(text: impl Into<String>) -> axum::Json<SlackCommandEventResponse> {
+    axum::Json(
+        SlackCommandEventResponse::new(SlackMessageContent::new().with_text(text.into()))
+            .with_response_type(SlackMessageResponseType::Ephemeral),
+    )
+}
 
-        let (auth_url, csrf_token) = oauth_client
-            .authorize_url(CsrfToken::new_random)
-            .add_extra_param("scope", "commands")
-            .add_extra_param("user_scope", "users.profile:read,users.profile:write")
-            .url();
+/// Parses `<text> :emoji:` into a default status. The emoji is optional, but has to come last.
+fn parse_default_status(text: &str) -> Option<DefaultStatus> {
+    let text = text.trim();
 
-        if let Err(e) = db.add_user(event.user_id.0, UserData::new(lastfm_username, csrf_token)) {
-            return axum::Json(SlackCommandEventResponse::new(
-                SlackMessageContent::new()
-                    .with_text(format!("Error adding your user to the database: {}", e)),
-            ));
+    let (text, emoji) = match text.rsplit_once(' ').unwrap_or(("", text)) {
+        (rest, emoji) if emoji.len() > 1 && emoji.starts_with(':') && emoji.ends_with(':') => {
+            (rest.trim(), emoji)
         }
+        _ => (text, ""),
+    };
 
-        // send an oauth link
-        axum::Json(
-            SlackCommandEventResponse::new(SlackMessageContent::new().with_text(format!(
-                "Please visit {} to allow SlackFM to access and modify your profile/status",
-                auth_url
-            )))
-            .with_response_type(SlackMessageResponseType::Ephemeral),
-        )
+    if text.is_empty() && emoji.is_empty() {
+        None
+    } else {
+        Some(DefaultStatus {
+            text: text.to_owned(),
+            emoji: emoji.to_owned(),
+        })
     }
 }
 
-async fn oauth_handler(
-    Query(code): Query<OauthCode>,
-    State(state): State<AppState>,
-) -> &'static str {
-    let db = state.db.lock().await;
+async fn default_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received default command");
 
-    // Retrieve the csrf token and pkce verifier
-    let Some(user_arc) = db.user_with_csrf(&code.state) else {
-        return "CSRF couldn't be linked to a user. Theres a middleman attack at play or I didn't save the token properly";
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
     };
 
-    let client = create_oauth_client();
+    let default_status = event.text.as_deref().and_then(parse_default_status);
+    user.lock_or_recover()
+        .settings_mut()
+        .set_default_status(default_status.clone());
 
-    let response = client
-        .exchange_code(AuthorizationCode::new(code.code))
-        .request_async(async_http_client)
-        .await
-        .unwrap();
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving default status for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
 
-    let user_token = response.extra_fields().authed_user.access_token.clone();
-    let user_id = response.extra_fields().authed_user.id.clone();
+    match default_status {
+        Some(DefaultStatus { text, emoji }) => {
+            ephemeral_response(locale.default_status_saved(&emoji, &text))
+        }
+        None => ephemeral_response(locale.text(Message::DefaultStatusCleared)),
+    }
+}
 
-    user_arc.lock().unwrap().promote_token(user_token);
+/// Whether the text is a single `:emoji:` shortcode
+fn is_emoji(text: &str) -> bool {
+    text.strip_prefix(':')
+        .and_then(|text| text.strip_suffix(':'))
+        .is_some_and(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+        })
+}
 
-    db.to_encrypted_file().unwrap();
+async fn emoji_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received emoji command");
 
-    let user_id: SlackUserId = user_id.into();
-    let abort_handle = tokio::task::spawn(update_user_data(
-        state.slack_client.clone(),
-        state.lastfm_client.clone(),
-        user_id.clone(),
-        user_arc,
-    ))
-    .abort_handle();
+    let locale = user_locale(&state, &event.user_id).await;
 
-    state.tasks.lock().await.insert(user_id, abort_handle);
+    let status_emoji = match event.text.as_deref().map(str::trim) {
+        None | Some("") | Some("default") => None,
+        Some(emoji) if is_emoji(emoji) => Some(emoji.to_owned()),
+        Some(_) => return ephemeral_response(locale.text(Message::EmojiUsage)),
+    };
 
-    "Authenticated!"
-}
+    let db = state.db.read().await;
 
-#[derive(Clone)]
-struct AppState {
-    db: Arc<Mutex<Db>>,
-    tasks: Arc<Mutex<HashMap<SlackUserId, AbortHandle>>>,
-    lastfm_client: Arc<lastfm::Client>,
-    slack_client: Arc<SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>>>,
-}
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
 
-#[derive(Debug)]
-enum ServerError {
-    IoError,
-    LastfmError,
-    DbError,
-}
+    user.lock_or_recover()
+        .settings_mut()
+        .set_status_emoji(status_emoji.clone());
 
-impl fmt::Display for ServerError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::IoError => f.write_str("An IO error occurred"),
-            Self::LastfmError => f.write_str("A Last.fm error occurred"),
-            Self::DbError => f.write_str("An error occured when setting up the database"),
-        }
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving status emoji for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    match status_emoji {
+        Some(emoji) => ephemeral_response(locale.status_emoji(&emoji)),
+        None => ephemeral_response(locale.text(Message::StatusEmojiDefault)),
     }
 }
 
-impl Error for ServerError {}
+async fn idle_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received idle command");
 
-async fn run_server() -> Result<(), ServerError> {
-    let cwd = std::env::current_dir()
-        .attach_printable("Couldn't get current working directory.")
-        .change_context(ServerError::IoError)?;
+    let locale = user_locale(&state, &event.user_id).await;
 
-    let db = Db::from_encrypted_file(cwd.join("db.json.enc"), env::slack_signing_secret())
-        .attach_printable("Couldn't load the database.")
-        .change_context(ServerError::DbError)?;
+    let idle_emoji = match event.text.as_deref().map(str::trim) {
+        None | Some("") | Some("default") => None,
+        Some(emoji) if is_emoji(emoji) => Some(emoji.to_owned()),
+        Some(_) => return ephemeral_response(locale.text(Message::IdleUsage)),
+    };
 
-    let app_state = AppState {
-        db: Arc::new(Mutex::new(db)),
-        tasks: Arc::new(Mutex::new(HashMap::new())),
-        lastfm_client: Arc::new(lastfm::Client::new(
-            env::lastfm_key(),
-            reqwest::Client::builder()
-                .user_agent("slackfm-bot")
-                .build()
-                .attach_printable("Couldn't create the Lastfm client HTTP connector.")
-                .change_context(ServerError::IoError)?,
-        )),
-        slack_client: Arc::new(SlackClient::new(
-            SlackClientHyperConnector::new()
-                .attach_printable("Couldn't create the Slack client HTTP connector.")
-                .change_context(ServerError::IoError)?
-                .with_rate_control(SlackApiRateControlConfig::new()),
-        )),
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
     };
 
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 5127));
+    user.lock_or_recover()
+        .settings_mut()
+        .set_idle_emoji(idle_emoji.clone());
 
-    let listener_environment = Arc::new(
-        SlackClientEventsListenerEnvironment::new(app_state.slack_client.clone())
-            .with_error_handler(error_handler),
-    );
-    let signing_secret: SlackSigningSecret = env::slack_signing_secret().into();
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving idle emoji for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
 
-    let listener: SlackEventsAxumListener<SlackHyperHttpsConnector> =
-        SlackEventsAxumListener::new(listener_environment.clone());
+    match idle_emoji {
+        Some(emoji) => ephemeral_response(locale.idle_emoji(&emoji)),
+        None => ephemeral_response(locale.text(Message::IdleEmojiDefault)),
+    }
+}
 
-    // build our application route with OAuth nested router and Push/Command/Interaction events
-    let app = axum::routing::Router::new()
-        .route(
-            "/command",
-            axum::routing::post(command_event).layer(
-                listener
-                    .events_layer(&signing_secret)
-                    .with_event_extractor(SlackEventsExtractors::command_event()),
-            ),
-        )
-        .with_state(app_state.clone())
-        .route("/auth", axum::routing::get(oauth_handler))
-        .with_state(app_state.clone());
+async fn showalbum_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received showalbum command");
 
-    spawn_initial_updaters(app_state.clone())
-        .await
-        .attach_printable("Couldn't spawn the initial updaters.")
-        .change_context(ServerError::LastfmError)?;
+    let locale = user_locale(&state, &event.user_id).await;
 
-    axum::serve(
-        TcpListener::bind(&addr)
-            .await
-            .attach_printable("Couldn't bind listener to the address.")
-            .change_context(ServerError::IoError)?,
-        app,
-    )
-    .await
-    .unwrap();
+    let show_album = match event.text.as_deref().map(str::trim) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return ephemeral_response(locale.text(Message::ShowAlbumUsage)),
+    };
 
-    Ok(())
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    user.lock_or_recover()
+        .settings_mut()
+        .set_show_album(show_album);
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving album setting for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    if show_album {
+        ephemeral_response(locale.text(Message::ShowAlbumOn))
+    } else {
+        ephemeral_response(locale.text(Message::ShowAlbumOff))
+    }
 }
 
-async fn spawn_initial_updaters(state: AppState) -> Result<(), ServerError> {
-    let mut db = state.db.lock().await;
-
-    db.map_db(|hashmap| {
-        stream::iter(hashmap.into_iter())
-            .filter(|(_, user_data)| {
-                let lastfm_client = state.lastfm_client.clone();
-                let lastfm_username = user_data.lock().unwrap().lastfm_username().to_owned();
-                async move {
-                    lastfm_client
-                        .does_user_exist(&lastfm_username)
-                        .await
-                        .unwrap_or(false)
-                }
-            })
-            .collect()
-    })
-    .await
-    .attach_printable("Couldn't remove bad users from the database.")
-    .change_context(ServerError::DbError)?;
+async fn presence_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received presence command");
 
-    for (slack_user_id, user_data) in db.users() {
-        let user_id = SlackUserId::new(slack_user_id.into());
-        let lastfm_client = state.lastfm_client.clone();
-        let abort_handle = tokio::task::spawn(update_user_data(
-            state.slack_client.clone(),
-            lastfm_client,
-            user_id.clone(),
-            user_data,
-        ))
-        .abort_handle();
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let sync_presence = match event.text.as_deref().map(str::trim) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return ephemeral_response(locale.text(Message::PresenceUsage)),
+    };
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
 
-        state.tasks.lock().await.insert(user_id, abort_handle);
+    let has_presence_scope = {
+        let mut user = user.lock_or_recover();
+        user.settings_mut().set_sync_presence(sync_presence);
+        user.has_scope(oauth::PRESENCE_SCOPE)
+    };
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving presence setting for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
     }
 
-    Ok(())
+    if sync_presence && !has_presence_scope {
+        ephemeral_response(locale.text(Message::PresenceNeedsReauth))
+    } else if sync_presence {
+        ephemeral_response(locale.text(Message::PresenceOn))
+    } else {
+        ephemeral_response(locale.text(Message::PresenceOff))
+    }
 }
 
-#[tracing::instrument(skip(client, lastfm_client, user_data))]
-async fn update_user_data(
-    client: Arc<SlackClient<SlackClientHyperConnector<SlackHyperHttpsConnector>>>,
-    lastfm_client: Arc<lastfm::Client>,
-    user_id: SlackUserId,
-    user_data: Arc<std::sync::Mutex<UserData>>,
-) {
-    let (lastfm_username, slack_token) = {
-        let user_data = user_data.lock().unwrap();
-        let lastfm = user_data.lastfm_username().to_owned();
-        let slack = user_data.slack_token().map(ToOwned::to_owned);
-        (lastfm, slack)
+async fn latest_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received latest command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let treat_latest_as_now_playing = match event.text.as_deref().map(str::trim) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return ephemeral_response(locale.text(Message::LatestUsage)),
     };
 
-    let Some(slack_token) = slack_token else {
-        info!(
-            "No slack token for user {}. User didn't authenticate it seems",
-            user_id
-        );
-        return;
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
     };
 
-    let slack_client = slack::Client::from_client(client, slack_token, env::slack_team_id());
+    let is_authed = {
+        let mut user = user.lock_or_recover();
+        user.settings_mut()
+            .set_treat_latest_as_now_playing(treat_latest_as_now_playing);
+        user.slack_token().is_some()
+    };
 
-    let stream = lastfm_client.stream_now_playing(&lastfm_username, Duration::from_secs(10));
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving latest setting for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
 
-    pin_mut!(stream);
+    // the updater only reads this when it starts
+    if is_authed {
+        spawn_updater(&state, event.user_id.clone(), user).await;
+    }
 
-    info!("Polling user data for user {}", user_id);
+    if treat_latest_as_now_playing {
+        ephemeral_response(locale.latest_on(state.latest_scrobble_window.as_secs() / 60))
+    } else {
+        ephemeral_response(locale.text(Message::LatestOff))
+    }
+}
 
-    while let Some(track) = stream.next().await {
-        debug!("Got track: {:?}", track);
-        match track {
-            Ok(track) => {
-                if let Some(track) = track {
-                    println!("updating status for {} to {}", &user_id, track.name());
-                    if let Err(e) = slack_client
-                        .update_user_status(
-                            user_id.clone(),
-                            Some(format!("{} - {}", track.name(), track.artist())),
-                            Some(":music:"),
-                            // We can't get the song length from lastfm, so we'll pretend it lasts forever :clueless:
-                            None,
-                        )
-                        .await
-                    {
-                        error!("Error setting status for {}: {:#?}", &user_id, e);
-                    }
-                } else {
-                    println!("updating status for {} to not listening/blank", user_id);
-                    if let Err(e) = slack_client
-                        .update_user_status(user_id.clone(), Some(""), Some(""), None)
-                        .await
-                    {
-                        error!("Error setting status for {}: {:#?}", &user_id, e);
-                    }
-                }
-            }
+async fn mirror_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received mirror command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let channel = match event.text.as_deref().map(str::trim) {
+        Some("off") => None,
+        Some(text) => match parse_channel_id(text) {
+            Some(channel) => Some(channel),
+            None => {
+                return ephemeral_response(locale.text(Message::MirrorUsage));
+            }
+        },
+        None => return ephemeral_response(locale.text(Message::MirrorUsage)),
+    };
+
+    // tracks are posted by the bot, so there's nothing to post with without one
+    if channel.is_some() && state.bot_client.is_none() {
+        return ephemeral_response(locale.text(Message::MirrorUnavailable));
+    }
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    user.lock_or_recover()
+        .settings_mut()
+        .set_now_playing_channel(channel.clone());
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving mirror channel for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    match channel {
+        Some(channel) => ephemeral_response(locale.mirroring_to(&channel)),
+        None => ephemeral_response(locale.text(Message::MirrorOff)),
+    }
+}
+
+/// Picks the channel id out of a mention like `<#C0123|general>`, or takes a bare id
+fn parse_channel_id(text: &str) -> Option<String> {
+    let id = match text
+        .strip_prefix("<#")
+        .and_then(|text| text.strip_suffix('>'))
+    {
+        Some(mention) => mention.split('|').next().unwrap_or_default(),
+        None => text,
+    };
+
+    let is_channel_id = id.len() > 1
+        && matches!(id.chars().next(), Some('C' | 'G'))
+        && id
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    is_channel_id.then(|| id.to_owned())
+}
+
+async fn pause_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received pause command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let db = state.db.read().await;
+
+    let Some(user) = db
+        .user(&event.user_id.0)
+        .filter(|user| user.lock_or_recover().slack_token().is_some())
+    else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    if user.lock_or_recover().is_paused() {
+        return ephemeral_response(locale.text(Message::AlreadyPaused));
+    }
+
+    user.lock_or_recover().set_paused(true);
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error pausing {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::PauseFailed));
+    }
+
+    if let Some(abort_handle) = state.tasks.lock().await.remove(&event.user_id) {
+        abort_handle.abort();
+    }
+    drop(db);
+
+    // the same as when they stop listening, so a status they had before is put back
+    let token = user.lock_or_recover().slack_token().map(ToOwned::to_owned);
+    if let Some(token) = token {
+        let mut slack_client = user_slack_client(&state, &user, token);
+        set_not_playing(&state, &mut slack_client, &event.user_id, &user).await;
+    }
+
+    ephemeral_response(locale.text(Message::Paused))
+}
+
+async fn resume_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received resume command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let db = state.db.read().await;
+
+    let Some(user) = db
+        .user(&event.user_id.0)
+        .filter(|user| user.lock_or_recover().slack_token().is_some())
+    else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    if !user.lock_or_recover().is_paused() {
+        return ephemeral_response(locale.text(Message::NotPaused));
+    }
+
+    user.lock_or_recover().set_paused(false);
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error resuming {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::ResumeFailed));
+    }
+
+    // spawned before the db is unlocked, like after connecting
+    spawn_updater(&state, event.user_id.clone(), user).await;
+
+    ephemeral_response(locale.text(Message::Resumed))
+}
+
+async fn template_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received template command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let template = match event.text.as_deref().map(str::trim) {
+        None | Some("") => return ephemeral_response(locale.text(Message::TemplateUsage)),
+        Some("off") => None,
+        Some(template) => {
+            if let Err(e) = status::validate_template(template) {
+                return ephemeral_response(e.to_string());
+            }
+            Some(template.to_owned())
+        }
+    };
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    user.lock_or_recover()
+        .settings_mut()
+        .set_status_template(template.clone());
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving status template for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    match template {
+        Some(_) => ephemeral_response(locale.text(Message::TemplateOn)),
+        None => ephemeral_response(locale.text(Message::TemplateOff)),
+    }
+}
+
+async fn lang_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received lang command");
+
+    // replies are in the current language until the new one is saved
+    let current_locale = user_locale(&state, &event.user_id).await;
+
+    let locale = match event.text.as_deref().map(str::trim) {
+        Some("default") => None,
+        Some(code) => match code.parse::<Locale>() {
+            Ok(locale) => Some(locale),
+            Err(_) => return ephemeral_response(current_locale.text(Message::LangUsage)),
+        },
+        None => return ephemeral_response(current_locale.text(Message::LangUsage)),
+    };
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(current_locale.text(Message::NotInDatabase));
+    };
+
+    user.lock_or_recover().settings_mut().set_locale(locale);
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving language for {}: {}", event.user_id, e);
+        return ephemeral_response(current_locale.text(Message::SaveFailed));
+    }
+
+    ephemeral_response(locale.unwrap_or(state.default_locale).changed())
+}
+
+/// The language a user picked with /lang, or the server's default
+async fn user_locale(state: &AppState, user_id: &SlackUserId) -> Locale {
+    let user = state.db.read().await.user(&user_id.0);
+
+    user.as_deref()
+        .and_then(|user| user.lock_or_recover().settings().locale())
+        .unwrap_or(state.default_locale)
+}
+
+async fn lastfm_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received lastfm command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let Some(lastfm_username) = event
+        .text
+        .as_deref()
+        .and_then(|text| text.split_whitespace().next())
+    else {
+        return ephemeral_response(locale.text(Message::LastfmUsage));
+    };
+
+    match state.lastfm_client.does_user_exist(lastfm_username).await {
+        Ok(true) => {}
+        Ok(false) => return ephemeral_response(locale.lastfm_user_missing(lastfm_username)),
+        Err(e) => {
+            error!("Error checking if {} exists: {:?}", lastfm_username, e);
+            return ephemeral_response(locale.text(Message::LastfmUnreachable));
+        }
+    }
+
+    match state.lastfm_client.get_now_playing(lastfm_username).await {
+        Ok(Some(track)) => ephemeral_response(locale.listening_to(
+            lastfm_username,
+            track.name(),
+            track.artist(),
+            track.album(),
+        )),
+        Ok(None) => ephemeral_response(locale.not_listening(lastfm_username)),
+        Err(e) => {
+            error!("Error getting now playing for {}: {:?}", lastfm_username, e);
+            ephemeral_response(locale.text(Message::LastfmUnreachable))
+        }
+    }
+}
+
+async fn status_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received status command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    // checked before the user is locked, since their lock can't be held across an await
+    let updating = state
+        .tasks
+        .lock()
+        .await
+        .get(&event.user_id)
+        .is_some_and(|task| !task.is_finished());
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotConnected));
+    };
+    let user = user.lock_or_recover();
+
+    if user.slack_token().is_some() {
+        let mut lines = vec![locale.connected_to(user.lastfm_username())];
+
+        let missing_scopes = user.missing_scopes(state.respect_dnd);
+        if !missing_scopes.is_empty() {
+            lines.push(locale.missing_scopes(&missing_scopes.join(", ")));
+        }
+
+        lines.push(if updating {
+            locale.text(Message::StatusUpdating).to_owned()
+        } else {
+            locale.text(Message::StatusNotUpdating).to_owned()
+        });
+
+        lines.push(match state.history.latest(&event.user_id.0) {
+            Some(change) if change.text.is_empty() && change.emoji.is_empty() => {
+                locale.last_status_cleared(&change.at.format("%H:%M UTC").to_string())
+            }
+            Some(change) => locale.last_status(
+                &change.emoji,
+                &change.text,
+                &change.at.format("%H:%M UTC").to_string(),
+            ),
+            None => locale.text(Message::NoStatusYet).to_owned(),
+        });
+
+        ephemeral_response(lines.join("\n"))
+    } else if let Some(csrf_token) = user.csrf_token() {
+        let oauth_client = create_oauth_client(&state.secrets.slack_client_secret);
+        ephemeral_response(
+            locale.authorization_pending(
+                user.lastfm_username(),
+                authorize_url(
+                    &oauth_client,
+                    csrf_token.clone(),
+                    &user.required_scopes(state.respect_dnd),
+                )
+                .as_str(),
+            ),
+        )
+    } else {
+        ephemeral_response(locale.text(Message::NotConnected))
+    }
+}
+
+async fn interval_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received interval command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let Some(seconds) = event
+        .text
+        .as_deref()
+        .and_then(|text| text.trim().parse::<u64>().ok())
+    else {
+        return ephemeral_response(locale.text(Message::IntervalUsage));
+    };
+
+    if seconds < MIN_POLL_INTERVAL_SECS {
+        return ephemeral_response(locale.interval_too_short(MIN_POLL_INTERVAL_SECS));
+    }
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    let is_authed = {
+        let mut user = user.lock_or_recover();
+        user.settings_mut().set_poll_interval_secs(seconds);
+        user.slack_token().is_some()
+    };
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving polling interval for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    // restart polling so the new interval is picked up straight away
+    if is_authed {
+        spawn_updater(&state, event.user_id.clone(), user).await;
+    }
+
+    ephemeral_response(locale.checking_every(seconds))
+}
+
+async fn apikey_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received apikey command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let Some(api_key) = event
+        .text
+        .as_deref()
+        .and_then(|text| text.split_whitespace().next())
+    else {
+        return ephemeral_response(locale.text(Message::ApiKeyUsage));
+    };
+
+    let db = state.db.read().await;
+
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    let api_key = if api_key == "clear" {
+        None
+    } else {
+        let lastfm_username = user.lock_or_recover().lastfm_username().to_owned();
+        match state
+            .lastfm_client
+            .with_key(api_key.to_owned())
+            .is_key_valid(&lastfm_username)
+            .await
+        {
+            Ok(true) => Some(api_key.to_owned()),
+            Ok(false) => return ephemeral_response(locale.text(Message::ApiKeyRejected)),
+            Err(e) => {
+                error!(
+                    "Error validating the API key for {}: {:?}",
+                    event.user_id, e
+                );
+                return ephemeral_response(locale.text(Message::LastfmUnreachable));
+            }
+        }
+    };
+
+    let is_authed = {
+        let mut user = user.lock_or_recover();
+        user.set_lastfm_api_key(api_key.clone());
+        user.slack_token().is_some()
+    };
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving API key for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::SaveFailed));
+    }
+
+    // restart polling so the new key is used straight away
+    if is_authed {
+        spawn_updater(&state, event.user_id.clone(), user).await;
+    }
+
+    if api_key.is_some() {
+        ephemeral_response(locale.text(Message::ApiKeyOwn))
+    } else {
+        ephemeral_response(locale.text(Message::ApiKeyServer))
+    }
+}
+
+async fn nowplaying_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received nowplaying command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let (lastfm_username, api_key) = {
+        let db = state.db.read().await;
+        let Some(user) = db.user(&event.user_id.0) else {
+            return ephemeral_response(locale.text(Message::NotInDatabase));
+        };
+        let user = user.lock_or_recover();
+        (
+            user.lastfm_username().to_owned(),
+            user.lastfm_api_key().map(ToOwned::to_owned),
+        )
+    };
+
+    let client = match api_key {
+        Some(api_key) => Arc::new(state.lastfm_client.with_key(api_key)),
+        None => state.lastfm_client.clone(),
+    };
+
+    match client.get_now_playing(&lastfm_username).await {
+        Ok(Some(track)) => axum::Json(
+            SlackCommandEventResponse::new(slack::now_playing_content(&track.into()))
+                .with_response_type(SlackMessageResponseType::Ephemeral),
+        ),
+        Ok(None) => ephemeral_response(locale.text(Message::NotPlaying)),
+        Err(e) => {
+            error!("Error getting what {} is playing: {:?}", lastfm_username, e);
+            ephemeral_response(locale.text(Message::LastfmUnreachable))
+        }
+    }
+}
+
+async fn topmusic_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received topmusic command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    // fetching everyone's tracks can take longer than Slack waits for a reply, so the chart is
+    // sent to the response url once it's ready
+    tokio::spawn(async move {
+        let top = workspace_top_music(&state, &event.team_id).await;
+        let message =
+            SlackApiPostWebhookMessageRequest::new(SlackMessageContent::new().with_text(top));
+        if let Err(e) = state
+            .slack_client
+            .respond_to_event(&event.response_url, &message)
+            .await
+        {
+            error!("Error sending the top music: {:?}", e);
+        }
+    });
+
+    ephemeral_response(locale.text(Message::CountingTopMusic))
+}
+
+/// The rendered top music of a workspace's connected users
+async fn workspace_top_music(state: &AppState, team_id: &SlackTeamId) -> String {
+    // (lastfm username, their own api key) of the workspace's connected users
+    let users: Vec<(String, Option<String>)> = {
+        let db = state.db.read().await;
+        db.users()
+            .filter(|(_, user)| admin::team_of(user) == team_id.0)
+            .filter_map(|(_, user)| {
+                let user = user.lock_or_recover();
+                user.slack_token()?;
+                Some((
+                    user.lastfm_username().to_owned(),
+                    user.lastfm_api_key().map(ToOwned::to_owned),
+                ))
+            })
+            .take(top_music::MAX_USERS)
+            .collect()
+    };
+
+    let recent_tracks: Vec<_> = stream::iter(users)
+        .map(|(lastfm_username, api_key)| {
+            let state = state.clone();
+            async move {
+                let client = match api_key {
+                    Some(api_key) => Arc::new(state.lastfm_client.with_key(api_key)),
+                    None => state.lastfm_client.clone(),
+                };
+                state
+                    .recent_tracks
+                    .recent_tracks(&client, &lastfm_username)
+                    .await
+                    .inspect_err(|e| {
+                        warn!(
+                            "Error getting recent tracks for {}: {:?}",
+                            lastfm_username, e
+                        )
+                    })
+                    .ok()
+            }
+        })
+        .buffer_unordered(top_music::CONCURRENT_REQUESTS)
+        .filter_map(|tracks| async move { tracks })
+        .collect()
+        .await;
+
+    top_music::tally(
+        recent_tracks
+            .iter()
+            .map(|tracks| tracks.iter().map(|track| (track.name(), track.artist()))),
+    )
+    .render()
+}
+
+async fn mylog_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received mylog command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let token = link_token::sign(
+        &state.secrets.slack_signing_secret,
+        link_token::Purpose::Log,
+        &event.user_id.0,
+        Utc::now() + chrono::Duration::minutes(LOG_LINK_TTL_MINUTES),
+    );
+
+    ephemeral_response(locale.log_link(
+        &format!("{}/mylog?token={}", PUBLIC_URL, token),
+        LOG_LINK_TTL_MINUTES,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct LogQuery {
+    token: String,
+}
+
+async fn log_handler(
+    Query(query): Query<LogQuery>,
+    State(state): State<AppState>,
+) -> std::result::Result<axum::Json<Vec<StatusChange>>, StatusCode> {
+    let user_id = link_token::verify(
+        &state.secrets.slack_signing_secret,
+        link_token::Purpose::Log,
+        &query.token,
+        Utc::now(),
+    )
+    .map_err(|e| {
+        info!("Rejected status history link: {:?}", e);
+        StatusCode::FORBIDDEN
+    })?;
+
+    Ok(axum::Json(state.history.for_user(&user_id)))
+}
+
+/// Loves (or unloves) the track the user is playing on Last.fm. The first time, the user is sent
+/// to Last.fm to allow it, which brings them back to [`lastfm_auth_handler`]
+async fn love_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+    love: bool,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received love command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let (lastfm_username, session_key, api_key) = {
+        let db = state.db.read().await;
+        let Some(user) = db.user(&event.user_id.0) else {
+            return ephemeral_response(locale.text(Message::NotInDatabase));
+        };
+        let user = user.lock_or_recover();
+        (
+            user.lastfm_username().to_owned(),
+            user.lastfm_session_key().map(ToOwned::to_owned),
+            user.lastfm_api_key().map(ToOwned::to_owned),
+        )
+    };
+
+    if env::lastfm_shared_secret().is_none() {
+        return ephemeral_response(locale.text(Message::LoveUnavailable));
+    }
+
+    let Some(session_key) = session_key else {
+        // Last.fm adds its own `token` to the callback, so ours goes in `user`
+        let link_token = link_token::sign(
+            &state.secrets.slack_signing_secret,
+            link_token::Purpose::LastfmAuth,
+            &event.user_id.0,
+            Utc::now() + chrono::Duration::minutes(LASTFM_AUTH_LINK_TTL_MINUTES),
+        );
+        let mut callback = Url::parse(PUBLIC_URL)
+            .unwrap()
+            .join("/lastfm/auth")
+            .unwrap();
+        callback.query_pairs_mut().append_pair("user", &link_token);
+
+        return ephemeral_response(locale.love_link(
+            state.lastfm_client.auth_url(&callback).as_str(),
+            LASTFM_AUTH_LINK_TTL_MINUTES,
+        ));
+    };
+
+    let client = match api_key {
+        Some(api_key) => Arc::new(state.lastfm_client.with_key(api_key)),
+        None => state.lastfm_client.clone(),
+    };
+
+    let track = match client.get_now_playing(&lastfm_username).await {
+        Ok(Some(track)) => track,
+        Ok(None) => return ephemeral_response(locale.text(Message::NotPlaying)),
+        Err(e) => {
+            error!("Error getting now playing for {}: {:?}", lastfm_username, e);
+            return ephemeral_response(locale.text(Message::LastfmUnreachable));
+        }
+    };
+
+    // the session key was issued to the server's API key, so loving is always signed with it
+    let result = if love {
+        state
+            .lastfm_client
+            .love_track(&session_key, track.artist(), track.name())
+            .await
+    } else {
+        state
+            .lastfm_client
+            .unlove_track(&session_key, track.artist(), track.name())
+            .await
+    };
+
+    match result {
+        Ok(()) if love => ephemeral_response(locale.loved(&track.to_string())),
+        Ok(()) => ephemeral_response(locale.unloved(&track.to_string())),
+        Err(e) => {
+            error!("Error loving a track for {}: {:?}", lastfm_username, e);
+            ephemeral_response(locale.text(Message::LoveFailed))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LastfmAuthQuery {
+    /// Our link token, identifying the slack user
+    user: String,
+    /// Last.fm's token, to exchange for a session key
+    token: String,
+}
+
+/// `GET /lastfm/auth`: where Last.fm sends users back after they let SlackFM love tracks
+async fn lastfm_auth_handler(
+    Query(query): Query<LastfmAuthQuery>,
+    State(state): State<AppState>,
+) -> std::result::Result<&'static str, StatusCode> {
+    let user_id = link_token::verify(
+        &state.secrets.slack_signing_secret,
+        link_token::Purpose::LastfmAuth,
+        &query.user,
+        Utc::now(),
+    )
+    .map_err(|e| {
+        info!("Rejected Last.fm auth link: {:?}", e);
+        StatusCode::FORBIDDEN
+    })?;
+
+    let session = state
+        .lastfm_client
+        .get_session(&query.token)
+        .await
+        .map_err(|e| {
+            error!("Error getting a Last.fm session for {}: {:?}", user_id, e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let locale = user_locale(&state, &SlackUserId::new(user_id.clone())).await;
+
+    let db = state.db.read().await;
+    let Some(user) = db.user(&user_id) else {
+        return Ok(locale.text(Message::NotInDatabase));
+    };
+
+    {
+        let mut user = user.lock_or_recover();
+        if !user.lastfm_username().eq_ignore_ascii_case(&session.name) {
+            warn!(
+                "{} allowed loving tracks as {}, but is connected as {}",
+                user_id,
+                session.name,
+                user.lastfm_username()
+            );
+            return Ok(locale.text(Message::LoveWrongAccount));
+        }
+        user.set_lastfm_session_key(Some(session.key));
+    }
+
+    if let Err(e) = db.save_user(&user_id) {
+        error!("Error saving Last.fm session for {}: {:?}", user_id, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Ok(locale.text(Message::LoveAllowed))
+}
+
+/// Follows Spotify instead of Last.fm, or goes back to Last.fm with `/spotify off`. The user is
+/// sent to Spotify to allow it, which brings them back to [`spotify_auth_handler`]
+async fn spotify_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received spotify command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let Some(spotify_client) = state.spotify_client.clone() else {
+        return ephemeral_response(locale.text(Message::SpotifyUnavailable));
+    };
+
+    let db = state.db.read().await;
+    let Some(user) = db.user(&event.user_id.0) else {
+        return ephemeral_response(locale.text(Message::NotInDatabase));
+    };
+
+    if event.text.as_deref().map(str::trim) == Some("off") {
+        let is_authed = {
+            let mut user = user.lock_or_recover();
+            user.set_spotify_refresh_token(None);
+            user.slack_token().is_some()
+        };
+
+        if let Err(e) = db.save_user(&event.user_id.0) {
+            error!("Error disconnecting Spotify for {}: {}", event.user_id, e);
+            return ephemeral_response(locale.text(Message::SaveFailed));
+        }
+
+        // the updater only picks its source when it starts
+        if is_authed {
+            spawn_updater(&state, event.user_id.clone(), user).await;
+        }
+
+        return ephemeral_response(locale.text(Message::SpotifyOff));
+    }
+
+    // Spotify hands `state` back untouched, so our link token goes there
+    let link_token = link_token::sign(
+        &state.secrets.slack_signing_secret,
+        link_token::Purpose::SpotifyAuth,
+        &event.user_id.0,
+        Utc::now() + chrono::Duration::minutes(SPOTIFY_AUTH_LINK_TTL_MINUTES),
+    );
+
+    ephemeral_response(
+        locale.spotify_link(
+            spotify_client
+                .authorize_url(&spotify_redirect_uri(), &link_token)
+                .as_str(),
+            SPOTIFY_AUTH_LINK_TTL_MINUTES,
+        ),
+    )
+}
+
+/// Where Spotify sends users back to. Has to be registered with the Spotify app
+fn spotify_redirect_uri() -> Url {
+    Url::parse(PUBLIC_URL)
+        .unwrap()
+        .join("/spotify/auth")
+        .unwrap()
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyAuthQuery {
+    /// Our link token, identifying the slack user
+    state: String,
+    /// Spotify's code, to exchange for a refresh token. Missing if the user didn't allow access
+    code: Option<String>,
+}
+
+/// `GET /spotify/auth`: where Spotify sends users back after they allowed SlackFM to see what
+/// they're playing
+async fn spotify_auth_handler(
+    Query(query): Query<SpotifyAuthQuery>,
+    State(state): State<AppState>,
+) -> std::result::Result<&'static str, StatusCode> {
+    let Some(spotify_client) = state.spotify_client.clone() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let user_id = link_token::verify(
+        &state.secrets.slack_signing_secret,
+        link_token::Purpose::SpotifyAuth,
+        &query.state,
+        Utc::now(),
+    )
+    .map_err(|e| {
+        info!("Rejected Spotify auth link: {:?}", e);
+        StatusCode::FORBIDDEN
+    })?;
+
+    let locale = user_locale(&state, &SlackUserId::new(user_id.clone())).await;
+
+    let Some(code) = query.code else {
+        return Ok(locale.text(Message::SpotifyDenied));
+    };
+
+    let refresh_token = spotify_client
+        .connect(&code, &spotify_redirect_uri())
+        .await
+        .map_err(|e| {
+            error!("Error connecting Spotify for {}: {:?}", user_id, e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let db = state.db.read().await;
+    let Some(user) = db.user(&user_id) else {
+        return Ok(locale.text(Message::NotInDatabase));
+    };
+
+    let is_authed = {
+        let mut user = user.lock_or_recover();
+        user.set_spotify_refresh_token(Some(refresh_token));
+        user.slack_token().is_some()
+    };
+
+    if let Err(e) = db.save_user(&user_id) {
+        error!(
+            "Error saving the Spotify connection of {}: {:?}",
+            user_id, e
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if is_authed {
+        spawn_updater(&state, SlackUserId::new(user_id), user).await;
+    }
+
+    Ok(locale.text(Message::SpotifyOn))
+}
+
+async fn reauth_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received reauth command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let db = state.db.read().await;
+
+    let Some(user) = db
+        .user(&event.user_id.0)
+        .filter(|user| user.lock_or_recover().slack_token().is_some())
+    else {
+        return ephemeral_response(locale.text(Message::NotConnected));
+    };
+
+    let csrf_token = CsrfToken::new_random();
+    let scopes = {
+        let mut user = user.lock_or_recover();
+        user.start_reauth(csrf_token.clone());
+        user.required_scopes(state.respect_dnd)
+    };
+
+    if let Err(e) = db.save_user(&event.user_id.0) {
+        error!("Error saving reauth state for {}: {}", event.user_id, e);
+        return ephemeral_response(locale.text(Message::ReauthFailed));
+    }
+
+    let oauth_client = create_oauth_client(&state.secrets.slack_client_secret);
+    ephemeral_response(
+        locale.reauth_link(authorize_url(&oauth_client, csrf_token, &scopes).as_str()),
+    )
+}
+
+async fn connect_handler(
+    event: SlackCommandEvent,
+    state: AppState,
+) -> axum::Json<SlackCommandEventResponse> {
+    info!("Received connect command");
+
+    let locale = user_locale(&state, &event.user_id).await;
+
+    let Some(lastfm_username) = event.text.and_then(|text| {
+        if text.is_empty() {
+            None
+        } else {
+            Some(
+                text.split_once(" ")
+                    .map(|split| split.0.to_owned())
+                    .unwrap_or_else(|| text.to_owned()),
+            )
+        }
+    }) else {
+        return axum::Json(SlackCommandEventResponse::new(
+            SlackMessageContent::new().with_text(locale.text(Message::ConnectUsage).into()),
+        ));
+    };
+
+    // check if the lastfm user exists
+    if !state
+        .lastfm_client
+        .does_user_exist(&lastfm_username)
+        .await
+        .unwrap_or(false)
+    {
+        return axum::Json(SlackCommandEventResponse::new(
+            SlackMessageContent::new().with_text(locale.lastfm_user_missing(&lastfm_username)),
+        ));
+    }
+
+    // bots can't have a status, so connecting one would only store an unusable token. Looking
+    // the user up needs a bot token with users:read, so the check is skipped without one
+    if let Some(bot_client) = &state.bot_client {
+        match bot_client.user_info(event.user_id.clone()).await {
+            Ok(user) if slack::is_bot(&user) => {
+                return ephemeral_response(locale.text(Message::BotsCantConnect));
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.current_context(), SlackError::MissingScope) => {
+                warn!("The bot is missing the users:read scope, skipping the bot check");
+            }
+            Err(e) => warn!("Couldn't check if {} is a bot: {:?}", event.user_id, e),
+        }
+    }
+
+    let mut db = state.db.write().await;
+
+    let user = db.user(&event.user_id.0);
+
+    if let Some(user) = user.filter(|user| user.lock_or_recover().slack_token().is_some()) {
+        user.lock_or_recover()
+            .update_lastfm_username(lastfm_username);
+        if let Err(e) = db.save_user(&event.user_id.0) {
+            error!(
+                "Error saving Last.fm username for {}: {:?}",
+                event.user_id, e
+            );
+            return ephemeral_response(locale.text(Message::SaveFailed));
+        }
+
+        // the running updater read the old username when it started
+        spawn_updater(&state, event.user_id.clone(), user).await;
+
+        axum::Json(SlackCommandEventResponse::new(
+            SlackMessageContent::new().with_text(locale.text(Message::UsernameUpdated).into()),
+        ))
+    } else {
+        let oauth_client = create_oauth_client(&state.secrets.slack_client_secret);
+
+        // note: we aren't doing PKCE since this is only ran on a trusted server
+
+        let csrf_token = CsrfToken::new_random();
+        let auth_url = authorize_url(
+            &oauth_client,
+            csrf_token.clone(),
+            &user_scopes(state.respect_dnd, false),
+        );
+
+        if let Err(e) = db.add_user(
+            event.user_id.0.clone(),
+            UserData::new(lastfm_username, csrf_token),
+        ) {
+            error!("Error adding {} to the database: {:?}", event.user_id, e);
+            return axum::Json(SlackCommandEventResponse::new(
+                SlackMessageContent::new().with_text(locale.text(Message::ConnectFailed).into()),
+            ));
+        }
+
+        // send an oauth link
+        axum::Json(
+            SlackCommandEventResponse::new(
+                SlackMessageContent::new().with_text(locale.connect_link(auth_url.as_str())),
+            )
+            .with_response_type(SlackMessageResponseType::Ephemeral),
+        )
+    }
+}
+
+async fn oauth_handler(
+    Query(code): Query<OauthCode>,
+    State(state): State<AppState>,
+) -> std::result::Result<&'static str, (StatusCode, &'static str)> {
+    // only checked under a read lock, so nothing waits on the exchange with Slack below
+    let is_connecting = state.db.read().await.user_with_csrf(&code.state).is_some()
+        || state
+            .pending_connections
+            .get(&code.state, Utc::now())
+            .is_some();
+    if !is_connecting {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "CSRF couldn't be linked to a user. Theres a middleman attack at play or I didn't save the token properly",
+        ));
+    }
+
+    let client = create_oauth_client(&state.secrets.slack_client_secret);
+
+    // fails if Slack is having trouble, or the code was already used (e.g. the link was opened
+    // twice)
+    let response = match client
+        .exchange_code(AuthorizationCode::new(code.code))
+        .request_async(async_http_client)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Error exchanging the Slack OAuth code: {:?}", e);
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                "Slack couldn't finish connecting SlackFM. Please run /connect and try again",
+            ));
+        }
+    };
+
+    let authed_user = &response.extra_fields().authed_user;
+    let user_id = authed_user.id.clone();
+    let scopes = authed_user
+        .scope
+        .as_ref()
+        .map(|scope| scope.split(',').map(ToOwned::to_owned).collect());
+    let team_id = response
+        .extra_fields()
+        .team
+        .as_ref()
+        .map(|team| team.id.clone());
+
+    let Some(user_token) = authed_user.user_token().map(ToOwned::to_owned) else {
+        error!(
+            "Slack didn't return a user token for {} (token type {:?}). Does the app request user scopes?",
+            user_id, authed_user.token_type
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Slack didn't give SlackFM a user token, so it can't update your status. Please ask whoever runs SlackFM to check the app's user scopes",
+        ));
+    };
+
+    let mut db = state.db.write().await;
+
+    // looked up again, since the user could have disconnected (or finished connecting in another
+    // tab) while Slack answered. Users who connected from the web page are only kept in memory
+    // until Slack says who they are
+    let web_connection = state.pending_connections.get(&code.state, Utc::now());
+    let Some(mut user_arc) = db
+        .user_with_csrf(&code.state)
+        .or_else(|| web_connection.clone())
+    else {
+        return Err((
+            StatusCode::CONFLICT,
+            "Your connection changed while Slack was answering. Please run /connect and try again",
+        ));
+    };
+
+    {
+        let mut user = user_arc.lock_or_recover();
+        user.promote_token(
+            user_token,
+            authed_user.refresh_token.clone(),
+            authed_user.expires_at(),
+        );
+        user.set_team_id(team_id);
+        user.set_scopes(scopes);
+    }
+
+    // users who connected from the web page are only now known by their slack user id
+    if let Some(web_connection) = web_connection {
+        state.pending_connections.remove(&code.state);
+        match db.claim_user(web_connection, &user_id) {
+            Ok(claimed) => user_arc = claimed,
+            Err(e) => error!("Error saving the web connection of {}: {:?}", user_id, e),
+        }
+    } else if let Err(e) = db.save_user(&user_id) {
+        error!("Error saving the connection of {}: {:?}", user_id, e);
+    }
+    // a new token can't be fetched again, so it's written straight away
+    if let Err(e) = db.flush() {
+        error!("Error saving the connection of {}: {:?}", user_id, e);
+    }
+
+    let lastfm_username = user_arc.lock_or_recover().lastfm_username().to_owned();
+    let user_id: SlackUserId = user_id.into();
+    // spawned before the db is unlocked, so a /connect changing the username can't slip in
+    // between the promotion and the updater's first read (see `Db`)
+    spawn_updater(&state, user_id.clone(), user_arc).await;
+    drop(db);
+
+    confirm_connection(&state, &user_id, &lastfm_username).await;
+
+    Ok("Authenticated!")
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    /// Missing while the database is busy, e.g. during startup cleanup
+    users: Option<usize>,
+    active_tasks: usize,
+}
+
+/// `GET /health`: a liveness probe. Doesn't wait on the database, so a long startup can't fail it
+async fn health_handler(State(state): State<AppState>) -> axum::Json<Health> {
+    let users = state.db.try_read().ok().map(|db| db.users().count());
+    let active_tasks = state
+        .tasks
+        .lock()
+        .await
+        .values()
+        .filter(|task| !task.is_finished())
+        .count();
+
+    axum::Json(Health {
+        users,
+        active_tasks,
+    })
+}
+
+/// `GET /ready`: a readiness probe, which fails until every user's updater has been started
+async fn ready_handler(State(state): State<AppState>) -> StatusCode {
+    if state.ready.load(Ordering::Acquire) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Lets the user know they're connected with a DM from the bot, with a reaction on top.
+///
+/// Slash commands don't leave a message behind to react to, so the DM is what gets the reaction.
+/// This is only done when a bot token is configured, and the reaction is skipped if the bot is
+/// missing the reactions:write scope.
+async fn confirm_connection(state: &AppState, user_id: &SlackUserId, lastfm_username: &str) {
+    let Some(bot_client) = &state.bot_client else {
+        return;
+    };
+
+    let locale = user_locale(state, user_id).await;
+
+    // posting to a user id sends a DM from the bot
+    let channel = SlackChannelId::new(user_id.to_string());
+    let content = SlackMessageContent::new().with_text(locale.connected(lastfm_username));
+
+    let ts = match bot_client.post_message(channel.clone(), content).await {
+        Ok(ts) => ts,
+        Err(e) => {
+            error!(
+                "Error sending connection confirmation to {}: {:?}",
+                user_id, e
+            );
+            return;
+        }
+    };
+
+    let reaction = env::connected_reaction().unwrap_or_else(|| "white_check_mark".to_owned());
+    match bot_client
+        .add_reaction(channel, ts, reaction.trim_matches(':'))
+        .await
+    {
+        Ok(()) => {}
+        Err(e) if matches!(e.current_context(), SlackError::MissingScope) => {
+            warn!("The bot is missing the reactions:write scope, skipping the connected reaction");
+        }
+        Err(e) => error!("Error reacting to connection confirmation: {:?}", e),
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    db: Arc<RwLock<Db>>,
+    tasks: Arc<Mutex<HashMap<SlackUserId, AbortHandle>>>,
+    lastfm_client: Arc<lastfm::Client>,
+    /// Set when SPOTIFY_CLIENT_ID and SPOTIFY_CLIENT_SECRET are, letting users follow Spotify
+    spotify_client: Option<Arc<SpotifyClient>>,
+    slack_client: slack::SharedClient,
+    now_playing_board: Option<Arc<NowPlayingBoard>>,
+    bot_client: Option<Arc<slack::Client>>,
+    secrets: Arc<Secrets>,
+    empty_name_behavior: EmptyNameBehavior,
+    now_playing_emoji: String,
+    loved_emoji: String,
+    idle_emoji: String,
+    idle_text: String,
+    stop_grace: Duration,
+    debounce: Duration,
+    latest_scrobble_window: Duration,
+    expiry_padding: TimeDelta,
+    respect_dnd: bool,
+    slack_timeout: Duration,
+    metrics: PrometheusHandle,
+    history: Arc<StatusHistory>,
+    scheduler: Arc<PollScheduler>,
+    default_locale: Locale,
+    recent_tracks: Arc<RecentTracksCache>,
+    pending_connections: Arc<PendingConnections>,
+    /// Set once the initial updaters have been spawned
+    ready: Arc<AtomicBool>,
+    updater_shard: UpdaterShard,
+}
+
+impl AppState {
+    /// Stops the updaters so none outlive the server. Call it once the server has stopped, as
+    /// nothing else aborts them
+    async fn shutdown(&self) {
+        stop_all_updaters(&mut *self.tasks.lock().await);
+    }
+}
+
+#[derive(Debug)]
+enum ServerError {
+    IoError,
+    DbError,
+    SecretsError,
+    ConfigError,
+    MetricsError,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError => f.write_str("An IO error occurred"),
+            Self::DbError => f.write_str("An error occured when setting up the database"),
+            Self::SecretsError => f.write_str("An error occurred while loading secrets"),
+            Self::ConfigError => f.write_str("The configuration is invalid"),
+            Self::MetricsError => f.write_str("An error occurred while setting up metrics"),
+        }
+    }
+}
+
+impl Error for ServerError {}
+
+async fn run_server(addr: SocketAddr) -> Result<(), ServerError> {
+    let cwd = std::env::current_dir()
+        .attach_printable("Couldn't get current working directory.")
+        .change_context(ServerError::IoError)?;
+
+    let secrets = load_secrets()
+        .await
+        .attach_printable("Couldn't load the secrets.")
+        .change_context(ServerError::SecretsError)?;
+
+    let default_save_retry = SaveRetry::default();
+    let save_retry = SaveRetry {
+        retries: env::db_save_retries().unwrap_or(default_save_retry.retries),
+        delay: env::db_save_retry_delay_ms()
+            .map(Duration::from_millis)
+            .unwrap_or(default_save_retry.delay),
+    };
+
+    let db = load_db(&cwd, &secrets.db_key)
+        .await
+        .attach_printable("Couldn't load the database.")
+        .change_context(ServerError::DbError)?
+        .with_read_only(env::db_read_only().unwrap_or(false));
+    let db_flush_interval =
+        Duration::from_secs(env::db_flush_seconds().unwrap_or(DEFAULT_DB_FLUSH_SECONDS));
+    let db = db.with_deferred_saves(!db_flush_interval.is_zero());
+
+    let updater_shard = UpdaterShard {
+        index: env::updater_shard().unwrap_or(0),
+        count: env::updater_shards().unwrap_or(1),
+    };
+    if updater_shard.index >= updater_shard.count {
+        return Err(
+            error_stack::Report::new(ServerError::ConfigError).attach_printable(format!(
+                "UPDATER_SHARD must be less than UPDATER_SHARDS ({}).",
+                updater_shard.count
+            )),
+        );
+    }
+
+    let empty_name_behavior = env::empty_name_behavior()
+        .map(|behavior| behavior.parse::<EmptyNameBehavior>())
+        .transpose()
+        .attach_printable("Couldn't parse EMPTY_NAME_BEHAVIOR.")
+        .change_context(ServerError::ConfigError)?
+        .unwrap_or_default();
+
+    let metrics = PrometheusBuilder::new()
+        .install_recorder()
+        .attach_printable("Couldn't install the Prometheus recorder.")
+        .change_context(ServerError::MetricsError)?;
+
+    // every Slack client shares this one's connection pool
+    let slack_client = slack::new_shared_client()
+        .attach_printable("Couldn't create the Slack client HTTP connector.")
+        .change_context(ServerError::IoError)?;
+
+    let bot_client = env::slack_bot_token().map(|bot_token| {
+        Arc::new(slack::Client::from_client(
+            slack_client.clone(),
+            bot_token,
+            env::slack_team_id(),
+        ))
+    });
+
+    // posting to a channel needs a bot token, so only enable the board when both are configured
+    let now_playing_board =
+        env::now_playing_channel()
+            .zip(bot_client.clone())
+            .map(|(channel, bot_client)| {
+                info!("Keeping a now playing message in channel {}", channel);
+                Arc::new(NowPlayingBoard::new(bot_client, channel.into()))
+            });
+
+    let expiry_padding = match env::expiry_padding_seconds() {
+        Some(secs) => i64::try_from(secs)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .ok_or(ServerError::ConfigError)
+            .attach_printable("EXPIRY_PADDING_SECONDS is too large.")?,
+        None => TimeDelta::seconds(status::DEFAULT_EXPIRY_PADDING_SECS),
+    };
+
+    let lastfm_api_base = match env::lastfm_api_base() {
+        Some(base) => Url::parse(&base)
+            .attach_printable("Couldn't parse LASTFM_API_BASE.")
+            .change_context(ServerError::ConfigError)?,
+        None => Url::parse(lastfm::API_BASE).unwrap(),
+    };
+    if lastfm_api_base.scheme() != "https" {
+        warn!(
+            "Talking to Last.fm over {} at {}. API keys will be sent unencrypted",
+            lastfm_api_base.scheme(),
+            lastfm_api_base
+        );
+    }
+
+    let mut lastfm_client = lastfm::Client::with_base_url(
+        env::lastfm_key(),
+        reqwest::Client::builder()
+            .user_agent("slackfm-bot")
+            .build()
+            .attach_printable("Couldn't create the Lastfm client HTTP connector.")
+            .change_context(ServerError::IoError)?,
+        lastfm_api_base,
+    );
+    if let Some(secret) = env::lastfm_shared_secret() {
+        lastfm_client = lastfm_client.with_secret(secret);
+    }
+
+    let spotify_client = match env::spotify_client_id().zip(env::spotify_client_secret()) {
+        Some((client_id, client_secret)) => {
+            info!("Users can follow Spotify instead of Last.fm with /spotify");
+            let client = reqwest::Client::builder()
+                .user_agent("slackfm-bot")
+                .build()
+                .attach_printable("Couldn't create the Spotify client HTTP connector.")
+                .change_context(ServerError::IoError)?;
+            Some(Arc::new(SpotifyClient::new(
+                client_id,
+                client_secret,
+                client,
+            )))
+        }
+        None => None,
+    };
+
+    let app_state = AppState {
+        db: Arc::new(RwLock::new(db)),
+        tasks: Arc::new(Mutex::new(HashMap::new())),
+        lastfm_client: Arc::new(lastfm_client),
+        spotify_client,
+        slack_client,
+        now_playing_board,
+        bot_client,
+        secrets: Arc::new(secrets),
+        empty_name_behavior,
+        now_playing_emoji: env::now_playing_emoji().unwrap_or_else(|| ":music:".to_owned()),
+        loved_emoji: env::loved_emoji().unwrap_or_else(|| ":heart:".to_owned()),
+        idle_emoji: env::idle_emoji().unwrap_or_default(),
+        idle_text: env::idle_text().unwrap_or_default(),
+        stop_grace: Duration::from_secs(env::stop_grace_seconds().unwrap_or(0)),
+        debounce: Duration::from_secs(env::debounce_seconds().unwrap_or(0)),
+        latest_scrobble_window: Duration::from_secs(
+            env::latest_scrobble_window_seconds().unwrap_or(DEFAULT_LATEST_SCROBBLE_WINDOW_SECS),
+        ),
+        expiry_padding,
+        respect_dnd: env::respect_dnd().unwrap_or(false),
+        slack_timeout: env::slack_timeout_seconds()
+            .map(Duration::from_secs)
+            .unwrap_or(slack::DEFAULT_STATUS_TIMEOUT),
+        metrics,
+        history: Arc::new(StatusHistory::default()),
+        recent_tracks: Arc::new(RecentTracksCache::default()),
+        pending_connections: Arc::new(PendingConnections::default()),
+        scheduler: Arc::new(PollScheduler::default()),
+        default_locale: Locale::from_lang(env::lang().as_deref()),
+        ready: Arc::new(AtomicBool::new(false)),
+        updater_shard,
+    };
+
+    let listener_environment = Arc::new(
+        SlackClientEventsListenerEnvironment::new(app_state.slack_client.clone())
+            .with_error_handler(error_handler),
+    );
+    let signing_secret: SlackSigningSecret = app_state.secrets.slack_signing_secret.clone().into();
+
+    let listener: SlackEventsAxumListener<SlackHyperHttpsConnector> =
+        SlackEventsAxumListener::new(listener_environment.clone());
+
+    let read_only = app_state.db.read().await.is_read_only();
+
+    // build our application route with OAuth nested router and Push/Command/Interaction events
+    let app = axum::routing::Router::new()
+        .route(
+            "/command",
+            axum::routing::post(command_event).layer(
+                listener
+                    .events_layer(&signing_secret)
+                    .with_event_extractor(SlackEventsExtractors::command_event()),
+            ),
+        )
+        .with_state(app_state.clone())
+        .route("/auth", axum::routing::get(oauth_handler))
+        .route(
+            "/connect",
+            axum::routing::get(connect_page::page).post(connect_page::submit),
+        )
+        .route("/metrics", axum::routing::get(metrics_handler))
+        .route("/health", axum::routing::get(health_handler))
+        .route("/ready", axum::routing::get(ready_handler))
+        .route("/mylog", axum::routing::get(log_handler))
+        .route("/lastfm/auth", axum::routing::get(lastfm_auth_handler))
+        .route("/spotify/auth", axum::routing::get(spotify_auth_handler))
+        .route("/admin/teams", axum::routing::get(admin::list_teams))
+        .route(
+            "/admin/teams/:team_id/revoke",
+            axum::routing::post(admin::revoke_team),
+        )
+        .route("/admin/clear-all", axum::routing::post(admin::clear_all))
+        .route("/admin/user/:user_id", axum::routing::get(admin::user_info))
+        .with_state(app_state.clone());
+
+    // replicas can't take connections or commands, since they'd be lost on the next reload
+    let app = if read_only {
+        info!("Running as a read-only replica");
+        axum::routing::Router::new()
+            .route("/metrics", axum::routing::get(metrics_handler))
+            .route("/health", axum::routing::get(health_handler))
+            .route("/ready", axum::routing::get(ready_handler))
+            .with_state(app_state.clone())
+    } else {
+        app
+    };
+
+    tokio::spawn(app_state.scheduler.clone().run());
+    if read_only {
+        tokio::spawn(reload_db_on_change(app_state.clone()));
+    } else {
+        tokio::spawn(compact_db_periodically(app_state.db.clone()));
+        // without deferred saves, only the saves that failed are left to flush
+        let flush_interval = if db_flush_interval.is_zero() {
+            Duration::from_secs(DEFAULT_DB_FLUSH_SECONDS)
+        } else {
+            db_flush_interval
+        };
+        tokio::spawn(flush_db_periodically(
+            app_state.db.clone(),
+            flush_interval,
+            save_retry,
+        ));
+        tokio::spawn(remove_abandoned_connections_periodically(
+            app_state.db.clone(),
+        ));
+    }
+
+    // checking every user against Last.fm can take a while, so it's done while already serving
+    // (with /ready failing until it's done)
+    tokio::spawn({
+        let app_state = app_state.clone();
+        async move {
+            match spawn_initial_updaters(app_state.clone()).await {
+                Ok(()) => {
+                    info!("Spawned the initial updaters");
+                    app_state.ready.store(true, Ordering::Release);
+                }
+                Err(e) => error!("Couldn't spawn the initial updaters: {:?}", e),
+            }
+        }
+    });
+
+    axum::serve(
+        TcpListener::bind(&addr)
+            .await
+            .attach_printable("Couldn't bind listener to the address.")
+            .change_context(ServerError::IoError)?,
+        app,
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .attach_printable("The server stopped unexpectedly.")
+    .change_context(ServerError::IoError)?;
+
+    // nobody's status should be left stuck on a track once the server is gone
+    app_state.shutdown().await;
+
+    // the writing instance takes care of statuses, a replica restarting shouldn't touch them
+    if !read_only {
+        info!("Clearing statuses before shutting down");
+        match tokio::time::timeout(SHUTDOWN_CLEAR_TIMEOUT, admin::clear_statuses(&app_state)).await
+        {
+            Ok(summary) => info!("Cleared statuses: {:?}", summary),
+            Err(_) => warn!(
+                "Clearing statuses took longer than {:?}, shutting down anyway",
+                SHUTDOWN_CLEAR_TIMEOUT
+            ),
+        }
+    }
+
+    info!("Compacting the database before shutting down");
+    app_state
+        .db
+        .read()
+        .await
+        .compact()
+        .attach_printable("Couldn't compact the database on shutdown.")
+        .change_context(ServerError::DbError)?;
+
+    Ok(())
+}
+
+async fn compact_db_periodically(db: Arc<RwLock<Db>>) {
+    let mut interval = tokio::time::interval(DB_COMPACT_INTERVAL);
+    // the first tick completes immediately, and the database was just loaded
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        info!("Compacting the database");
+        if let Err(e) = db.read().await.compact() {
+            error!("Error compacting the database: {:?}", e);
+        }
+    }
+}
+
+async fn flush_db_periodically(
+    db: Arc<RwLock<Db>>,
+    flush_interval: Duration,
+    save_retry: SaveRetry,
+) {
+    let mut interval = tokio::time::interval(flush_interval);
+
+    loop {
+        interval.tick().await;
+
+        let mut attempt = 0;
+        loop {
+            // the database is only locked while writing, not while waiting to retry
+            let flushed = db.read().await.flush();
+            match flushed {
+                Ok(()) => break,
+                Err(e) if attempt < save_retry.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Couldn't flush the database (attempt {} of {}), retrying in {:?}: {:?}",
+                        attempt,
+                        save_retry.retries + 1,
+                        save_retry.delay,
+                        e
+                    );
+                    tokio::time::sleep(save_retry.delay).await;
+                }
+                Err(e) => {
+                    error!("Error flushing the database: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn remove_abandoned_connections_periodically(db: Arc<RwLock<Db>>) {
+    let mut interval = tokio::time::interval(ABANDONED_CONNECTION_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match db.write().await.remove_abandoned_connections(Utc::now()) {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} abandoned connections", removed),
+            Err(e) => error!("Error removing abandoned connections: {:?}", e),
+        }
+    }
+}
+
+/// Keeps a read-only replica in sync with the database file, restarting the updaters of users
+/// that changed
+async fn reload_db_on_change(state: AppState) {
+    let mut interval = tokio::time::interval(DB_RELOAD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if !state.db.read().await.changed_on_disk() {
+            continue;
+        }
+
+        let mut db = state.db.write().await;
+
+        info!("The database file changed, reloading it");
+        let reload = match db.reload() {
+            Ok(reload) => reload,
+            Err(e) => {
+                error!("Error reloading the database: {:?}", e);
+                continue;
+            }
+        };
+
+        for user_id in reload.removed {
+            if let Some(handle) = state.tasks.lock().await.remove(&SlackUserId::new(user_id)) {
+                handle.abort();
+            }
+        }
+        for user_id in reload.changed {
+            if let Some(user_data) = db.user(&user_id) {
+                spawn_updater(&state, SlackUserId::new(user_id), user_data).await;
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for Ctrl+C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Couldn't listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutting down");
+}
+
+async fn load_secrets() -> Result<Secrets, SecretError> {
+    match env::secrets_provider().as_deref() {
+        None | Some("env") => Secrets::resolve(&EnvSecretProvider).await,
+        #[cfg(feature = "vault")]
+        Some("vault") => {
+            let provider = secrets::VaultSecretProvider::from_env(reqwest::Client::new())?;
+            Secrets::resolve(&provider).await
+        }
+        Some(other) => Err(error_stack::Report::new(SecretError::ProviderError)
+            .attach_printable(format!("Unknown secrets provider {}", other))),
+    }
+}
+
+async fn load_db(cwd: &Path, key: &str) -> Result<Db, DbError> {
+    let identity = env::db_age_identity()
+        .map(|path| store::read_identity_file(Path::new(&path)))
+        .transpose()?;
+
+    match env::db_backend().as_deref() {
+        None | Some("json") => {
+            let mut store = EncryptedJsonStore::new(cwd.join("db.json.enc"), key.to_owned());
+            if let Some(identity) = identity {
+                store = store.with_identity(identity);
+            }
+            Db::load(store).await
+        }
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") if identity.is_some() => {
+            Err(error_stack::Report::new(DbError::EncryptionError)
+                .attach_printable("DB_AGE_IDENTITY only works with the json database backend"))
+        }
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") => {
+            let store = store::SqliteStore::open(cwd.join("db.sqlite"), key)?;
+            Db::load(store).await
+        }
+        Some(other) => Err(error_stack::Report::new(DbError::IoError)
+            .attach_printable(format!("Unknown database backend {}", other))),
+    }
+}
+
+async fn spawn_initial_updaters(state: AppState) -> Result<(), ServerError> {
+    let (read_only, users) = {
+        let db = state.db.read().await;
+        let users: HashMap<_, _> = db
+            .users()
+            .map(|(user_id, user_data)| (user_id.clone(), user_data))
+            .collect();
+        (db.is_read_only(), users)
+    };
+
+    // a replica would only forget the bad users until its next reload
+    if !read_only {
+        let checked: HashMap<_, _> = users
+            .iter()
+            .map(|(user_id, user_data)| {
+                let lastfm_username = user_data.lock_or_recover().lastfm_username().to_owned();
+                (user_id.clone(), lastfm_username)
+            })
+            .collect();
+
+        // checking takes a request per user, so it's done without holding up the database
+        let kept = retain_existing_users(users, |lastfm_username| {
+            let lastfm_client = state.lastfm_client.clone();
+            async move { lastfm_client.does_user_exist(&lastfm_username).await }
+        })
+        .await;
+
+        let mut db = state.db.write().await;
+        for (user_id, lastfm_username) in checked {
+            if kept.contains_key(&user_id) {
+                continue;
+            }
+
+            // they may have reconnected with another Last.fm account while being checked
+            let unchanged = db.user(&user_id).is_some_and(|user_data| {
+                user_data.lock_or_recover().lastfm_username() == lastfm_username
+            });
+            if unchanged {
+                db.remove_user(&user_id)
+                    .attach_printable("Couldn't remove bad users from the database.")
+                    .change_context(ServerError::DbError)?;
+            }
+        }
+        stop_orphaned_updaters(&db, &mut *state.tasks.lock().await);
+    }
+
+    let db = state.db.read().await;
+    for (slack_user_id, user_data) in db.users() {
+        spawn_updater(&state, SlackUserId::new(slack_user_id.into()), user_data).await;
+    }
+
+    Ok(())
+}
+
+/// Drops the users whose Last.fm account doesn't exist. Users whose check failed are kept, so a
+/// Last.fm outage at startup can't empty the database
+async fn retain_existing_users<F, Fut, E>(
+    users: HashMap<String, Arc<std::sync::Mutex<UserData>>>,
+    does_user_exist: F,
+) -> HashMap<String, Arc<std::sync::Mutex<UserData>>>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<bool, E>>,
+    E: fmt::Debug,
+{
+    let total = users.len();
+    let mut kept = HashMap::with_capacity(total);
+    let mut unchecked = 0;
+
+    for (user_id, user_data) in users {
+        let lastfm_username = user_data.lock_or_recover().lastfm_username().to_owned();
+
+        match does_user_exist(lastfm_username.clone()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                info!(
+                    "Removing {}: the Last.fm user {} doesn't exist",
+                    user_id, lastfm_username
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't check if the Last.fm user {} exists, keeping {}: {:?}",
+                    lastfm_username, user_id, e
+                );
+                unchecked += 1;
+            }
+        }
+
+        kept.insert(user_id, user_data);
+    }
+
+    if unchecked > 0 {
+        warn!(
+            "Couldn't check {} of {} users with Last.fm. They were kept and will be checked again on the next start",
+            unchecked, total
+        );
+    }
+
+    kept
+}
+
+/// Which share of the users this instance runs updaters for, so the writer and its replicas don't
+/// all update the same users
+#[derive(Debug, Clone, Copy)]
+struct UpdaterShard {
+    index: u32,
+    count: u32,
+}
+
+impl UpdaterShard {
+    /// Whether this instance updates the user. Hashed with FNV-1a, which (unlike std's hasher)
+    /// gives every instance the same answer
+    fn updates(&self, user_id: &str) -> bool {
+        let hash = user_id
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+
+        hash % u64::from(self.count) == u64::from(self.index)
+    }
+}
+
+/// Spawns a task updating the user's status, replacing (and aborting) any task they already had
+async fn spawn_updater(
+    state: &AppState,
+    user_id: SlackUserId,
+    user_data: Arc<std::sync::Mutex<UserData>>,
+) {
+    if !state.updater_shard.updates(&user_id.0) {
+        debug!("Not updating {}: another instance does", user_id);
+        return;
+    }
+
+    // covers restarts and replica reloads too, so a pause lasts until /resume
+    if user_data.lock_or_recover().is_paused() {
+        debug!("Not updating {}: they paused SlackFM", user_id);
+        if let Some(old_handle) = state.tasks.lock().await.remove(&user_id) {
+            old_handle.abort();
+        }
+        return;
+    }
+
+    let abort_handle =
+        tokio::task::spawn(update_user_data(state.clone(), user_id.clone(), user_data))
+            .abort_handle();
+
+    if let Some(old_handle) = state.tasks.lock().await.insert(user_id, abort_handle) {
+        old_handle.abort();
+    }
+}
+
+#[tracing::instrument(skip(state, user_data))]
+async fn update_user_data(
+    state: AppState,
+    user_id: SlackUserId,
+    user_data: Arc<std::sync::Mutex<UserData>>,
+) {
+    let (lastfm_username, slack_token, lastfm_api_key, spotify_refresh_token, settings) = {
+        let user_data = user_data.lock_or_recover();
+        let lastfm = user_data.lastfm_username().to_owned();
+        let slack = user_data.slack_token().map(ToOwned::to_owned);
+        let api_key = user_data.lastfm_api_key().map(ToOwned::to_owned);
+        let spotify = user_data.spotify_refresh_token().map(ToOwned::to_owned);
+        (
+            lastfm,
+            slack,
+            api_key,
+            spotify,
+            user_data.settings().clone(),
+        )
+    };
+
+    let Some(slack_token) = slack_token else {
+        info!(
+            "No slack token for user {}. User didn't authenticate it seems",
+            user_id
+        );
+        return;
+    };
+
+    let _polling = PollingTask::start();
+    let slack_client = user_slack_client(&state, &user_data, slack_token);
+
+    // users with their own API key get their own client so their requests count against it
+    let lastfm_client = match lastfm_api_key {
+        Some(api_key) => Arc::new(state.lastfm_client.with_key(api_key)),
+        None => state.lastfm_client.clone(),
+    };
+
+    // users who connected Spotify follow it instead of Last.fm
+    match (state.spotify_client.clone(), spotify_refresh_token) {
+        (Some(spotify_client), Some(refresh_token)) => {
+            info!("Following Spotify for user {}", user_id);
+
+            follow_source(
+                &state,
+                &user_id,
+                &user_data,
+                slack_client,
+                &lastfm_client,
+                &StoredSpotify {
+                    state: &state,
+                    user_id: &user_id,
+                    user_data: &user_data,
+                    client: &spotify_client,
+                },
+                &refresh_token,
+            )
+            .await;
+        }
+        _ => {
+            info!("Polling user data for user {}", user_id);
+
+            let mut source = ScheduledLastfm::new(
+                state.scheduler.clone(),
+                user_id.clone(),
+                lastfm_client.clone(),
+            );
+            if settings.treat_latest_as_now_playing() {
+                source = source.with_latest_scrobble_window(state.latest_scrobble_window);
+            }
+
+            follow_source(
+                &state,
+                &user_id,
+                &user_data,
+                slack_client,
+                &lastfm_client,
+                &source,
+                &lastfm_username,
+            )
+            .await;
+        }
+    }
+}
+
+/// Follows a user on Spotify, storing their refresh token whenever Spotify rotates it so they can
+/// still be followed after a restart
+struct StoredSpotify<'a> {
+    state: &'a AppState,
+    user_id: &'a SlackUserId,
+    user_data: &'a std::sync::Mutex<UserData>,
+    client: &'a SpotifyClient,
+}
+
+impl StoredSpotify<'_> {
+    async fn store_refresh_token(&self, connected_with: &str) {
+        let current = self.client.current_refresh_token(connected_with);
+        {
+            let mut user_data = self.user_data.lock_or_recover();
+            if user_data.spotify_refresh_token() == Some(current.as_str()) {
+                return;
+            }
+            user_data.set_spotify_refresh_token(Some(current));
+        }
+
+        debug!("Storing the rotated Spotify token of {}", self.user_id);
+        if let Err(e) = self.state.db.read().await.save_user(&self.user_id.0) {
+            error!(
+                "Error saving the Spotify token of {}: {:?}",
+                self.user_id, e
+            );
+        }
+    }
+}
+
+impl MusicSource for StoredSpotify<'_> {
+    type Error = SpotifyError;
+
+    fn stream_now_playing<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> BoxStream<'a, Result<Option<NowPlaying>, Self::Error>> {
+        self.client
+            .stream_now_playing(user, polling_interval)
+            .then(move |change| async move {
+                self.store_refresh_token(user).await;
+                change
+            })
+            .boxed()
+    }
+}
+
+/// Counts a running updater in the `active_polling_tasks` gauge for as long as it's alive, so
+/// updaters that are aborted or panic are still taken off
+struct PollingTask;
+
+impl PollingTask {
+    fn start() -> Self {
+        metrics::gauge!("active_polling_tasks").increment(1.0);
+        Self
+    }
+}
+
+impl Drop for PollingTask {
+    fn drop(&mut self) {
+        metrics::gauge!("active_polling_tasks").decrement(1.0);
+    }
+}
+
+/// Keeps the user's status in line with what `source` says `user` is playing, until the source
+/// stops. Track lengths the source doesn't know are looked up on Last.fm
+async fn follow_source<S: MusicSource>(
+    state: &AppState,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    mut slack_client: Arc<slack::Client>,
+    lastfm_client: &lastfm::Client,
+    source: &S,
+    user: &str,
+) {
+    let poll_interval = user_data.lock_or_recover().settings().poll_interval();
+    let mut changes = source.stream_now_playing(user, poll_interval);
+
+    // when to clear the status after the user stopped playing. This is delayed by the stop grace
+    // period so the gap between two songs doesn't flicker the status
+    let mut clear_at: Option<Instant> = None;
+    // presence is only changed on play/stop transitions, not on every track
+    let mut playing = false;
+    // a new track waiting out the debounce window, and when it gets shown
+    let mut pending: Option<(Instant, NowPlaying)> = None;
+
+    loop {
+        let change = tokio::select! {
+            change = changes.next() => change,
+            () = tokio::time::sleep_until(clear_at.unwrap_or_else(Instant::now)), if clear_at.is_some() => {
+                clear_at = None;
+                set_not_playing(state, &mut slack_client, user_id, user_data).await;
+                playing = false;
+                sync_presence(
+                    state,
+                    &mut slack_client,
+                    user_id,
+                    user_data,
+                    slack::SlackPresence::Away,
+                )
+                .await;
+                continue;
+            }
+            () = tokio::time::sleep_until(pending.as_ref().map_or_else(Instant::now, |(at, _)| *at)), if pending.is_some() => {
+                if let Some((_, track)) = pending.take() {
+                    play_track(
+                        state,
+                        &mut slack_client,
+                        lastfm_client,
+                        user_id,
+                        user_data,
+                        &track,
+                        &mut playing,
+                    )
+                    .await;
+                }
+                continue;
+            }
+        };
+
+        // the stream ends when a newer updater for the same user replaced this one, or the source
+        // can't carry on (e.g. the user revoked access)
+        let Some(change) = change else {
+            debug!("Stopped following {} for {}", user, user_id);
+            return;
+        };
+
+        let track = match change {
+            Ok(track) => track,
             Err(e) => {
+                // the next poll is tried as usual
                 error!("Error: {:#?}", e);
+                continue;
+            }
+        };
+
+        debug!("Got track: {:?}", track);
+        if track.is_none() {
+            pending = None;
+        }
+        match track {
+            Some(track) => {
+                // a new song started within the grace period, so the status never gets cleared
+                clear_at = None;
+                if state.debounce.is_zero() {
+                    play_track(
+                        state,
+                        &mut slack_client,
+                        lastfm_client,
+                        user_id,
+                        user_data,
+                        &track,
+                        &mut playing,
+                    )
+                    .await;
+                } else {
+                    // every skip restarts the window, so only a track that's kept playing is shown
+                    debug!(
+                        "Showing {} for {} in {:?} if it's still playing",
+                        track, user_id, state.debounce
+                    );
+                    pending = Some((Instant::now() + state.debounce, track));
+                }
+            }
+            None if state.stop_grace.is_zero() => {
+                set_not_playing(state, &mut slack_client, user_id, user_data).await;
+                playing = false;
+                sync_presence(
+                    state,
+                    &mut slack_client,
+                    user_id,
+                    user_data,
+                    slack::SlackPresence::Away,
+                )
+                .await;
+            }
+            None => {
+                debug!(
+                    "User {} stopped playing, clearing their status in {:?}",
+                    user_id, state.stop_grace
+                );
+                clear_at = Some(Instant::now() + state.stop_grace);
+            }
+        }
+    }
+}
+
+/// Shows a track in the user's status, marking them active if they just started listening
+async fn play_track(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    lastfm_client: &lastfm::Client,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    track: &NowPlaying,
+    playing: &mut bool,
+) {
+    let result = with_user_client(
+        state,
+        user_id,
+        user_data,
+        slack_client,
+        |client| async move {
+            set_now_playing(state, &client, lastfm_client, user_id, user_data, track).await
+        },
+    )
+    .await;
+    if let Err(e) = result {
+        error!("Error setting status for {}: {:#?}", user_id, e);
+    }
+
+    if !*playing {
+        *playing = true;
+        sync_presence(
+            state,
+            slack_client,
+            user_id,
+            user_data,
+            slack::SlackPresence::Active,
+        )
+        .await;
+    }
+
+    mirror_track(state, user_data, track).await;
+}
+
+/// Posts the track to the user's mirror channel, if they set one with /mirror
+async fn mirror_track(
+    state: &AppState,
+    user_data: &std::sync::Mutex<UserData>,
+    track: &NowPlaying,
+) {
+    let Some(bot_client) = &state.bot_client else {
+        return;
+    };
+    // read live so /mirror applies without restarting the updater
+    let channel = user_data
+        .lock_or_recover()
+        .settings()
+        .now_playing_channel()
+        .map(ToOwned::to_owned);
+    let Some(channel) = channel else {
+        return;
+    };
+
+    if let Err(e) = bot_client
+        .post_now_playing_message(channel.into(), track)
+        .await
+    {
+        error!("Error mirroring a track to a channel: {:#?}", e);
+    }
+}
+
+async fn set_now_playing(
+    state: &AppState,
+    slack_client: &slack::Client,
+    lastfm_client: &lastfm::Client,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    track: &NowPlaying,
+) -> Result<(), SlackError> {
+    // read live so /showalbum, /template and /emoji apply without restarting the updater
+    let (show_album, template, status_emoji) = {
+        let user_data = user_data.lock_or_recover();
+        let settings = user_data.settings();
+        (
+            settings.show_album(),
+            settings.status_template().map(ToOwned::to_owned),
+            settings.status_emoji().map(ToOwned::to_owned),
+        )
+    };
+
+    let status_text = match &template {
+        Some(template) => status::templated_status_text(track, state.empty_name_behavior, template),
+        None => status::status_text(track, state.empty_name_behavior, show_album),
+    };
+    let Some(status_text) = status_text else {
+        info!("Not updating status for {}: track has no name", user_id);
+        return Ok(());
+    };
+
+    let now_playing_emoji = status_emoji.as_deref().unwrap_or(&state.now_playing_emoji);
+    let emoji = status::status_emoji(track, now_playing_emoji, &state.loved_emoji);
+
+    // without a duration the status lasts until the user stops listening
+    let track_length = match track.duration() {
+        Some(duration) => Some(duration),
+        None => match lastfm_client
+            .get_track_duration(track.artist(), track.name())
+            .await
+        {
+            Ok(duration) => duration,
+            Err(e) => {
+                warn!("Couldn't get the duration of {}: {:?}", track, e);
+                None
+            }
+        },
+    }
+    .and_then(|duration| TimeDelta::from_std(duration).ok());
+
+    info!("Updating status for {} to {}", user_id, status_text);
+    let result = match slack_client
+        .update_user_status(
+            user_id.clone(),
+            Some(status_text.as_str()),
+            Some(emoji),
+            status::status_expiry(Utc::now(), track_length, state.expiry_padding),
+        )
+        .await
+    {
+        Ok(Some(update)) => {
+            // a leftover status from before a restart isn't the user's own
+            let replaced = Some(update.previous).filter(|replaced| {
+                replaced.emoji != state.now_playing_emoji
+                    && replaced.emoji != now_playing_emoji
+                    && replaced.emoji != state.loved_emoji
+            });
+            let saved = user_data
+                .lock_or_recover()
+                .record_status_set(replaced, UserStatus::of(&update.profile));
+            if saved {
+                if let Err(e) = state.db.read().await.save_user(&user_id.0) {
+                    error!("Error saving the previous status of {}: {:?}", user_id, e);
+                }
+            }
+
+            state.history.record(&user_id.0, status_text, emoji);
+            metrics::counter!("statuses_updated_total", "outcome" => "ok").increment(1);
+            Ok(())
+        }
+        Ok(None) => {
+            debug!(
+                "Skipped setting status for {}: Do Not Disturb is on",
+                user_id
+            );
+            Ok(())
+        }
+        Err(e) => {
+            metrics::counter!("statuses_updated_total", "outcome" => "err").increment(1);
+            Err(e)
+        }
+    };
+
+    if let Some(board) = &state.now_playing_board {
+        if let Err(e) = board.set_playing(user_id, Some(track.to_string())).await {
+            error!("Error updating the now playing message: {:#?}", e);
+        }
+    }
+
+    result
+}
+
+/// A client acting as the user in their own workspace, for their updater
+fn user_slack_client(
+    state: &AppState,
+    user_data: &std::sync::Mutex<UserData>,
+    token: String,
+) -> Arc<slack::Client> {
+    Arc::new(
+        slack::Client::from_client(state.slack_client.clone(), token, admin::team_of(user_data))
+            .with_respect_dnd(state.respect_dnd)
+            .with_status_timeout(state.slack_timeout),
+    )
+}
+
+/// Makes a request to Slack as the user. A rotating token is refreshed first if it's about to
+/// expire, and the request is made once more if Slack says it already has. Every request with a
+/// user's token goes through here, so none of them fail on a token that could've been refreshed
+async fn with_user_client<T, F, Fut>(
+    state: &AppState,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    slack_client: &mut Arc<slack::Client>,
+    request: F,
+) -> Result<T, SlackError>
+where
+    F: Fn(Arc<slack::Client>) -> Fut,
+    Fut: std::future::Future<Output = Result<T, SlackError>>,
+{
+    refresh_slack_token(state, user_id, user_data, slack_client, false).await;
+    let result = request(slack_client.clone()).await;
+
+    // the expiry we stored can be off, so Slack has the final say
+    if result
+        .as_ref()
+        .is_err_and(|e| matches!(e.current_context(), SlackError::TokenExpired))
+        && refresh_slack_token(state, user_id, user_data, slack_client, true).await
+    {
+        return request(slack_client.clone()).await;
+    }
+
+    result
+}
+
+/// Trades the user's rotating Slack token for a new one once it's about to expire (or straight
+/// away when `expired`), and points `slack_client` at it. Returns whether it was refreshed
+async fn refresh_slack_token(
+    state: &AppState,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    slack_client: &mut Arc<slack::Client>,
+    expired: bool,
+) -> bool {
+    let (refresh_token, expires_at) = {
+        let user_data = user_data.lock_or_recover();
+        (
+            user_data.slack_refresh_token().map(ToOwned::to_owned),
+            user_data.slack_token_expires_at(),
+        )
+    };
+
+    let expiring = expires_at.is_some_and(|expires_at| {
+        expires_at <= Utc::now() + TimeDelta::minutes(TOKEN_REFRESH_MARGIN_MINUTES)
+    });
+    if !expired && !expiring {
+        return false;
+    }
+
+    let Some(refresh_token) = refresh_token else {
+        if expired {
+            warn!(
+                "The Slack token of {} expired and can't be refreshed. They need to /reauth",
+                user_id
+            );
+        }
+        return false;
+    };
+
+    let refreshed =
+        match oauth::refresh_user_token(&state.secrets.slack_client_secret, &refresh_token).await {
+            Ok(refreshed) => refreshed,
+            Err(e) => {
+                error!("Error refreshing the Slack token of {}: {:?}", user_id, e);
+                return false;
+            }
+        };
+
+    info!("Refreshed the Slack token of {}", user_id);
+    *slack_client = Arc::new(slack_client.with_token(refreshed.access_token.clone()));
+    user_data.lock_or_recover().set_refreshed_token(
+        refreshed.access_token,
+        // keep the old refresh token if Slack didn't send a new one
+        refreshed.refresh_token.or(Some(refresh_token)),
+        refreshed.expires_at,
+    );
+
+    if let Err(e) = state.db.read().await.save_user(&user_id.0) {
+        error!("Error saving the refreshed token of {}: {:?}", user_id, e);
+    }
+
+    true
+}
+
+/// The status set once a user stops listening: their default status if they have one, otherwise
+/// the idle status (blank unless configured)
+fn not_playing_status(
+    settings: &UserSettings,
+    idle_text: &str,
+    idle_emoji: &str,
+) -> (String, String) {
+    match settings.default_status() {
+        Some(status) => (status.text.clone(), status.emoji.clone()),
+        None => (
+            idle_text.to_owned(),
+            settings.idle_emoji().unwrap_or(idle_emoji).to_owned(),
+        ),
+    }
+}
+
+async fn set_not_playing(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+) {
+    let saved_status = user_data.lock_or_recover().saved_status();
+    match saved_status {
+        Some((saved, set)) if !saved.has_expired(Utc::now()) => {
+            restore_status(state, slack_client, user_id, user_data, saved, set).await
+        }
+        Some(_) => {
+            // Slack would've cleared it by now, so it isn't put back
+            debug!("The saved status of {} has expired", user_id);
+            user_data.lock_or_recover().clear_saved_status();
+            if let Err(e) = state.db.read().await.save_user(&user_id.0) {
+                error!("Error forgetting the saved status of {}: {:?}", user_id, e);
             }
+            clear_status(state, slack_client, user_id, user_data).await
+        }
+        None => clear_status(state, slack_client, user_id, user_data).await,
+    }
+
+    if let Some(board) = &state.now_playing_board {
+        if let Err(e) = board.set_playing(user_id, None).await {
+            error!("Error updating the now playing message: {:#?}", e);
+        }
+    }
+}
+
+/// Marks the user active or away if they turned on /presence
+async fn sync_presence(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    presence: slack::SlackPresence,
+) {
+    // read live so /presence applies without restarting the updater
+    if !user_data.lock_or_recover().settings().sync_presence() {
+        return;
+    }
+
+    match with_user_client(
+        state,
+        user_id,
+        user_data,
+        slack_client,
+        |client| async move { client.set_presence(presence).await },
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(e) if matches!(e.current_context(), SlackError::MissingScope) => {
+            warn!("Can't set presence without the users:write scope");
+        }
+        Err(e) => error!("Error setting presence: {:#?}", e),
+    }
+}
+
+/// Takes SlackFM's status off the user for good: the status it replaced is put back, or it's
+/// blanked if there isn't one (or it has expired). Only a status SlackFM set is touched, so one
+/// the user set themselves is left alone. Returns whether the status was changed
+async fn take_down_status(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+) -> Result<bool, SlackError> {
+    let (saved, set) = {
+        let user_data = user_data.lock_or_recover();
+        (user_data.saved_status(), user_data.status_set().cloned())
+    };
+    let Some(set) = set else {
+        // nothing of ours to take down
+        return Ok(false);
+    };
+    let replacement = saved
+        .map(|(saved, _)| saved)
+        .filter(|saved| !saved.has_expired(Utc::now()))
+        .unwrap_or_default();
+
+    let replaced = if replacement.shows_same(&set) {
+        false
+    } else {
+        let (replacement, set) = (&replacement, &set);
+        with_user_client(
+            state,
+            user_id,
+            user_data,
+            slack_client,
+            |client| async move {
+                client
+                    .restore_user_status(user_id.clone(), replacement, set)
+                    .await
+            },
+        )
+        .await?
+    };
+
+    if replaced {
+        state
+            .history
+            .record(&user_id.0, replacement.text, replacement.emoji);
+    }
+    user_data.lock_or_recover().forget_status_set();
+
+    Ok(replaced)
+}
+
+/// Puts back the status the user had before they started listening, unless they've changed it
+/// since
+async fn restore_status(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+    saved: UserStatus,
+    set: UserStatus,
+) {
+    let restored = {
+        let (saved, set) = (&saved, &set);
+        with_user_client(
+            state,
+            user_id,
+            user_data,
+            slack_client,
+            |client| async move {
+                client
+                    .restore_user_status(user_id.clone(), saved, set)
+                    .await
+            },
+        )
+        .await
+    };
+
+    match restored {
+        Ok(true) => {
+            info!("Restored the previous status of {}", user_id);
+            state.history.record(&user_id.0, saved.text, saved.emoji);
+        }
+        Ok(false) => info!(
+            "{} changed their status while listening, leaving it alone",
+            user_id
+        ),
+        Err(e) => {
+            // kept so it's tried again the next time they stop
+            error!("Error restoring the status of {}: {:#?}", user_id, e);
+            return;
+        }
+    }
+
+    user_data.lock_or_recover().clear_saved_status();
+    if let Err(e) = state.db.read().await.save_user(&user_id.0) {
+        error!("Error saving the restored status of {}: {:?}", user_id, e);
+    }
+}
+
+async fn clear_status(
+    state: &AppState,
+    slack_client: &mut Arc<slack::Client>,
+    user_id: &SlackUserId,
+    user_data: &std::sync::Mutex<UserData>,
+) {
+    // read the settings again, they can be changed without restarting the updater
+    let (text, emoji) = not_playing_status(
+        user_data.lock_or_recover().settings(),
+        &state.idle_text,
+        &state.idle_emoji,
+    );
+
+    info!(
+        "Updating status for {} to not listening/default ({} {})",
+        user_id, emoji, text
+    );
+    // nothing is saved when clearing, so the current profile isn't needed
+    let cleared = {
+        let (text, emoji) = (&text, &emoji);
+        with_user_client(
+            state,
+            user_id,
+            user_data,
+            slack_client,
+            |client| async move { client.set_status(user_id.clone(), text, emoji, None).await },
+        )
+        .await
+    };
+
+    match cleared {
+        Ok(Some(profile)) => {
+            user_data
+                .lock_or_recover()
+                .record_status_set(None, UserStatus::of(&profile));
+            state.history.record(&user_id.0, text, emoji)
+        }
+        Ok(None) => debug!(
+            "Skipped clearing status for {}: Do Not Disturb is on",
+            user_id
+        ),
+        Err(e) => error!("Error setting status for {}: {:#?}", user_id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_event(user_id: &str) -> SlackCommandEvent {
+        serde_json::from_value(serde_json::json!({
+            "team_id": "T0001",
+            "channel_id": "C0001",
+            "user_id": user_id,
+            "command": "/status",
+            "response_url": "https://hooks.slack.com/commands/T0001/1/abc",
+            "trigger_id": "1.2.abc",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn empty_user_id_is_rejected() {
+        assert!(!has_valid_user_id(&command_event("")));
+        assert!(!has_valid_user_id(&command_event(" ")));
+        assert!(!has_valid_user_id(&command_event("U01/../db")));
+    }
+
+    fn user(lastfm_username: &str) -> Arc<std::sync::Mutex<UserData>> {
+        Arc::new(std::sync::Mutex::new(UserData::new(
+            lastfm_username.to_owned(),
+            CsrfToken::new_random(),
+        )))
+    }
+
+    fn test_db(dir: &tempfile::TempDir) -> Db {
+        Db::new(EncryptedJsonStore::new(
+            dir.path().join("db.json.enc"),
+            "test-key".to_owned(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn disconnecting_a_never_authenticated_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = test_db(&dir);
+        db.add_user(
+            "U_PENDING".to_owned(),
+            UserData::new("alice".to_owned(), CsrfToken::new_random()),
+        )
+        .unwrap();
+        // they never finished OAuth, so no updater was spawned
+        let mut tasks = HashMap::new();
+
+        let user_id = SlackUserId::new("U_PENDING".to_owned());
+        assert!(disconnect_user(&mut db, &mut tasks, &user_id).unwrap());
+        assert!(db.user("U_PENDING").is_none());
+        assert!(!disconnect_user(&mut db, &mut tasks, &user_id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn updaters_of_removed_users_are_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = test_db(&dir);
+        db.add_user(
+            "U_KEPT".to_owned(),
+            UserData::new("alice".to_owned(), CsrfToken::new_random()),
+        )
+        .unwrap();
+
+        let kept = tokio::spawn(std::future::pending::<()>());
+        let removed = tokio::spawn(std::future::pending::<()>());
+        let mut tasks = HashMap::from([
+            (SlackUserId::new("U_KEPT".to_owned()), kept.abort_handle()),
+            (
+                SlackUserId::new("U_GONE".to_owned()),
+                removed.abort_handle(),
+            ),
+        ]);
+
+        stop_orphaned_updaters(&db, &mut tasks);
+
+        assert!(removed.await.unwrap_err().is_cancelled());
+        assert_eq!(
+            tasks.into_keys().collect::<Vec<_>>(),
+            vec![SlackUserId::new("U_KEPT".to_owned())]
+        );
+        kept.abort();
+    }
+
+    #[tokio::test]
+    async fn every_updater_is_stopped_on_shutdown() {
+        let first = tokio::spawn(std::future::pending::<()>());
+        let second = tokio::spawn(std::future::pending::<()>());
+        let mut tasks = HashMap::from([
+            (SlackUserId::new("U_FIRST".to_owned()), first.abort_handle()),
+            (
+                SlackUserId::new("U_SECOND".to_owned()),
+                second.abort_handle(),
+            ),
+        ]);
+
+        stop_all_updaters(&mut tasks);
+
+        assert!(tasks.is_empty());
+        assert!(first.await.unwrap_err().is_cancelled());
+        assert!(second.await.unwrap_err().is_cancelled());
+    }
+
+    #[test]
+    fn every_user_is_updated_by_exactly_one_shard() {
+        let shards: Vec<_> = (0..3)
+            .map(|index| UpdaterShard { index, count: 3 })
+            .collect();
+
+        let mut used = [false; 3];
+        for user in 0..100 {
+            let user_id = format!("U{:04}", user);
+            let updating: Vec<_> = shards
+                .iter()
+                .filter(|shard| shard.updates(&user_id))
+                .collect();
+
+            assert_eq!(
+                updating.len(),
+                1,
+                "{} is updated by {:?}",
+                user_id,
+                updating
+            );
+            used[updating[0].index as usize] = true;
         }
+        assert_eq!(used, [true; 3]);
+
+        let single = UpdaterShard { index: 0, count: 1 };
+        assert!(single.updates("U0001"));
+    }
+
+    #[tokio::test]
+    async fn users_whose_check_errored_are_kept() {
+        let users = HashMap::from([
+            ("U_GONE".to_owned(), user("gone")),
+            ("U_ERRORED".to_owned(), user("errored")),
+            ("U_EXISTS".to_owned(), user("exists")),
+        ]);
+
+        let kept = retain_existing_users(users, |lastfm_username| async move {
+            match lastfm_username.as_str() {
+                "gone" => Ok(false),
+                "errored" => Err("Last.fm is down"),
+                _ => Ok(true),
+            }
+        })
+        .await;
+
+        let mut kept: Vec<_> = kept.into_keys().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["U_ERRORED", "U_EXISTS"]);
+    }
+
+    #[test]
+    fn default_status_wins_over_idle_status() {
+        let mut settings = UserSettings::default();
+        assert_eq!(
+            not_playing_status(&settings, "", ""),
+            (String::new(), String::new())
+        );
+        assert_eq!(
+            not_playing_status(&settings, "Paused", ":zzz:"),
+            ("Paused".to_owned(), ":zzz:".to_owned())
+        );
+
+        settings.set_idle_emoji(Some(":sleeping:".to_owned()));
+        assert_eq!(
+            not_playing_status(&settings, "Paused", ":zzz:"),
+            ("Paused".to_owned(), ":sleeping:".to_owned())
+        );
+
+        settings.set_default_status(Some(DefaultStatus {
+            text: "Working".to_owned(),
+            emoji: ":computer:".to_owned(),
+        }));
+        assert_eq!(
+            not_playing_status(&settings, "Paused", ":zzz:"),
+            ("Working".to_owned(), ":computer:".to_owned())
+        );
+    }
+
+    #[test]
+    fn slack_user_id_is_accepted() {
+        assert!(has_valid_user_id(&command_event("U012AB3CD")));
+        assert!(has_valid_user_id(&command_event("W012AB3CD")));
+    }
+
+    #[test]
+    fn channel_mentions_are_parsed() {
+        assert_eq!(
+            parse_channel_id("<#C0123ABC|music>"),
+            Some("C0123ABC".to_owned())
+        );
+        assert_eq!(parse_channel_id("<#G0123ABC>"), Some("G0123ABC".to_owned()));
+        assert_eq!(parse_channel_id("C0123ABC"), Some("C0123ABC".to_owned()));
+        assert_eq!(parse_channel_id("#music"), None);
+        assert_eq!(parse_channel_id("<@U0123ABC>"), None);
+    }
+
+    #[test]
+    fn errors_get_a_matching_status() {
+        use slack_morphism::{
+            errors::{
+                SlackClientEndOfStreamError, SlackClientError, SlackClientProtocolError,
+                SlackClientSystemError,
+            },
+            signature_verifier::SlackEventAbsentSignatureError,
+        };
+
+        assert_eq!(
+            error_status(&SlackEventAbsentSignatureError::new()),
+            HttpStatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            error_status(&SlackClientError::ProtocolError(
+                SlackClientProtocolError::new(serde_json::from_str::<()>("{").unwrap_err())
+            )),
+            HttpStatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            error_status(&SlackClientError::SystemError(
+                SlackClientSystemError::new()
+                    .with_message("Absent payload in the request from Slack".into())
+            )),
+            HttpStatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            error_status(&SlackClientError::EndOfStream(
+                SlackClientEndOfStreamError::new()
+            )),
+            HttpStatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn emoji_must_be_a_single_shortcode() {
+        assert!(is_emoji(":music:"));
+        assert!(is_emoji(":man-surfing:"));
+        assert!(is_emoji(":+1:"));
+        assert!(is_emoji(":call_me_hand:"));
+
+        assert!(!is_emoji("music"));
+        assert!(!is_emoji("::"));
+        assert!(!is_emoji(":music: :heart:"));
+        assert!(!is_emoji(":music::heart:"));
+        assert!(!is_emoji(":not an emoji:"));
     }
 }
@@ -1,19 +1,61 @@
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
+use std::{error::Error, fmt};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use error_stack::{Result, ResultExt};
+use oauth2::{
+    basic::BasicClient, reqwest::async_http_client, url::Url, AuthUrl, ClientId, ClientSecret,
+    CsrfToken, RedirectUrl, RefreshToken, TokenResponse, TokenUrl,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::env;
 
+const AUTH_URL: &str = "https://slack.com/oauth/v2/authorize";
+const TOKEN_URL: &str = "https://slack.com/api/oauth.v2.access";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SlackAuthedUser {
     pub id: String,
-    pub scope: String,
-    pub access_token: String,
-    pub token_type: String,
+    // these are missing if the app wasn't granted any user scopes
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub token_type: Option<String>,
+    // only sent if the app has token rotation turned on
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<i64>,
+}
+
+impl SlackAuthedUser {
+    /// The user token, if Slack actually gave us one.
+    ///
+    /// A misconfigured app can end up with only a bot token, which can't update anyone's status.
+    pub fn user_token(&self) -> Option<&str> {
+        self.access_token
+            .as_deref()
+            .filter(|token| token.starts_with("xoxp-") || token.starts_with("xoxe.xoxp-"))
+    }
+
+    /// When the user token stops working, if Slack rotates it
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_in.map(expires_at)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackTeam {
+    pub id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SlackTokenFields {
     pub authed_user: SlackAuthedUser,
+    #[serde(default)]
+    pub team: Option<SlackTeam>,
 }
 impl oauth2::ExtraTokenFields for SlackTokenFields {}
 
@@ -26,14 +68,106 @@ pub type SlackOauthClient = oauth2::Client<
     oauth2::basic::BasicRevocationErrorResponse,
 >;
 
-pub fn create_oauth_client() -> SlackOauthClient {
+pub fn create_oauth_client(client_secret: &str) -> SlackOauthClient {
     SlackOauthClient::new(
         ClientId::new(env::slack_client_id()),
-        Some(ClientSecret::new(env::slack_client_secret())),
-        AuthUrl::new("https://slack.com/oauth/v2/authorize".to_owned()).unwrap(),
-        Some(TokenUrl::new("https://slack.com/api/oauth.v2.access".to_owned()).unwrap()),
+        Some(ClientSecret::new(client_secret.to_owned())),
+        AuthUrl::new(AUTH_URL.to_owned()).unwrap(),
+        Some(TokenUrl::new(TOKEN_URL.to_owned()).unwrap()),
     )
-    .set_redirect_uri(RedirectUrl::new("https://slackfm.wobbl.in/auth".to_owned()).unwrap())
+    .set_redirect_uri(RedirectUrl::new(format!("{}/auth", crate::PUBLIC_URL)).unwrap())
+}
+
+#[derive(Debug)]
+pub struct TokenRefreshError;
+
+impl fmt::Display for TokenRefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Couldn't refresh the Slack token")
+    }
+}
+impl Error for TokenRefreshError {}
+
+/// A user token Slack rotated
+#[derive(Debug)]
+pub struct RefreshedToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Trades a rotating user token's refresh token for a new access token.
+///
+/// Refresh responses have the user token at the top level rather than under `authed_user`, so
+/// this uses a plain client instead of [`SlackOauthClient`].
+pub async fn refresh_user_token(
+    client_secret: &str,
+    refresh_token: &str,
+) -> Result<RefreshedToken, TokenRefreshError> {
+    let client = BasicClient::new(
+        ClientId::new(env::slack_client_id()),
+        Some(ClientSecret::new(client_secret.to_owned())),
+        AuthUrl::new(AUTH_URL.to_owned()).unwrap(),
+        Some(TokenUrl::new(TOKEN_URL.to_owned()).unwrap()),
+    );
+
+    let response = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_owned()))
+        .request_async(async_http_client)
+        .await
+        .attach_printable("Slack rejected the refresh token")
+        .change_context(TokenRefreshError)?;
+
+    Ok(RefreshedToken {
+        access_token: response.access_token().secret().clone(),
+        refresh_token: response.refresh_token().map(|token| token.secret().clone()),
+        expires_at: response
+            .expires_in()
+            .and_then(|expires_in| i64::try_from(expires_in.as_secs()).ok())
+            .map(expires_at),
+    })
+}
+
+fn expires_at(expires_in: i64) -> DateTime<Utc> {
+    Utc::now() + TimeDelta::seconds(expires_in)
+}
+
+/// The user scopes SlackFM always needs
+pub const USER_SCOPES: &[&str] = &["users.profile:read", "users.profile:write"];
+
+/// Lets the updater skip users who have Do Not Disturb on, with RESPECT_DND
+pub const DND_SCOPE: &str = "dnd:read";
+
+/// Lets the updater mark users who turned on /presence as active or away
+pub const PRESENCE_SCOPE: &str = "users:write";
+
+/// The scopes every user authorized before scopes were stored was granted
+pub const ORIGINAL_USER_SCOPES: &[&str] = &["users.profile:read", "users.profile:write"];
+
+/// The user scopes SlackFM currently needs, with `dnd:read` only if Do Not Disturb is respected
+/// and `users:write` only for users who turned on /presence. Users who authorized with fewer of
+/// these have to run /reauth before features needing the new ones work for them
+pub fn user_scopes(respect_dnd: bool, sync_presence: bool) -> Vec<&'static str> {
+    let mut scopes = USER_SCOPES.to_vec();
+    if respect_dnd {
+        scopes.push(DND_SCOPE);
+    }
+    if sync_presence {
+        scopes.push(PRESENCE_SCOPE);
+    }
+    scopes
+}
+
+/// The URL a user has to visit to authorize SlackFM with `scopes` (see [`user_scopes`]), tied to
+/// the given CSRF token so the callback can be matched back to them
+pub fn authorize_url(client: &SlackOauthClient, csrf_token: CsrfToken, scopes: &[&str]) -> Url {
+    let (url, _) = client
+        .authorize_url(|| csrf_token)
+        .add_extra_param("scope", "commands")
+        .add_extra_param("user_scope", scopes.join(","))
+        .url();
+
+    url
 }
 
 #[derive(Deserialize)]
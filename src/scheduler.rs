@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use error_stack::Report;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use slack_morphism::prelude::*;
+use slackfm::{
+    lastfm::{self, LastFMError, RecentTrack},
+    source::{MusicSource, NowPlaying},
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::debug;
+
+/// How often the scheduler checks whether anyone is due a poll
+const TICK: Duration = Duration::from_secs(1);
+/// How many users are polled at once, so a tick where everyone is due doesn't flood Last.fm
+const MAX_CONCURRENT_POLLS: usize = 10;
+
+pub type PollResult = Result<Vec<RecentTrack>, Report<LastFMError>>;
+
+/// Polls Last.fm for every user from a single timer, instead of every user sleeping on their own.
+///
+/// Each user still has their own polling interval, and gets their recent tracks sent to the
+/// receiver returned by [`PollScheduler::subscribe`]. Polls run in the background, so a slow one
+/// only holds up its own user.
+pub struct PollScheduler {
+    users: Mutex<HashMap<SlackUserId, ScheduledUser>>,
+    polls: Arc<Semaphore>,
+}
+
+impl Default for PollScheduler {
+    fn default() -> Self {
+        Self {
+            users: Mutex::default(),
+            polls: Arc::new(Semaphore::new(MAX_CONCURRENT_POLLS)),
+        }
+    }
+}
+
+struct ScheduledUser {
+    lastfm_username: String,
+    lastfm_client: Arc<lastfm::Client>,
+    interval: Duration,
+    next_poll: Instant,
+    sender: mpsc::Sender<PollResult>,
+    /// The user's last poll, which has to finish before they're polled again
+    poll: Option<JoinHandle<()>>,
+}
+
+impl PollScheduler {
+    /// Starts polling a user, replacing any earlier subscription for them. The user is dropped
+    /// from the schedule once the receiver is dropped.
+    pub fn subscribe(
+        &self,
+        user_id: SlackUserId,
+        lastfm_username: String,
+        lastfm_client: Arc<lastfm::Client>,
+        interval: Duration,
+    ) -> mpsc::Receiver<PollResult> {
+        // a poll result that isn't picked up before the next one is skipped, so one slow user
+        // can't hold up the scheduler
+        let (sender, receiver) = mpsc::channel(1);
+        let interval = interval.max(lastfm::MIN_POLLING_INTERVAL);
+
+        self.users.lock().unwrap().insert(
+            user_id,
+            ScheduledUser {
+                lastfm_username,
+                lastfm_client,
+                interval,
+                next_poll: Instant::now() + interval,
+                sender,
+                poll: None,
+            },
+        );
+
+        receiver
+    }
+
+    /// Polls every due user each tick, forever
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            let now = ticker.tick().await;
+
+            // a std lock, which mustn't be held across the next tick
+            {
+                let mut users = self.users.lock().unwrap();
+                users.retain(|user_id, user| {
+                    let subscribed = !user.sender.is_closed();
+                    if !subscribed {
+                        debug!("{} stopped listening for polls", user_id);
+                    }
+                    subscribed
+                });
+
+                for (user_id, user) in users.iter_mut() {
+                    if user.next_poll > now {
+                        continue;
+                    }
+                    // they're polled again on the first tick after it finishes
+                    if user.poll.as_ref().is_some_and(|poll| !poll.is_finished()) {
+                        debug!("{} is still waiting on their last poll", user_id);
+                        continue;
+                    }
+
+                    user.next_poll = now + user.interval;
+                    user.poll = Some(tokio::spawn(Self::poll(
+                        self.polls.clone(),
+                        user.lastfm_username.clone(),
+                        user.lastfm_client.clone(),
+                        user.sender.clone(),
+                    )));
+                }
+            }
+        }
+    }
+
+    async fn poll(
+        polls: Arc<Semaphore>,
+        lastfm_username: String,
+        lastfm_client: Arc<lastfm::Client>,
+        sender: mpsc::Sender<PollResult>,
+    ) {
+        let Ok(_permit) = polls.acquire().await else {
+            return;
+        };
+
+        debug!("Polling Last.fm for {}", lastfm_username);
+        let result = lastfm_client.get_user_recent_tracks(&lastfm_username).await;
+
+        if sender.try_send(result).is_err() {
+            debug!("Skipped a poll result for {}", lastfm_username);
+        }
+    }
+}
+
+/// Follows a Last.fm user through the [`PollScheduler`], so their polls share its timer rather
+/// than each user sleeping on their own like [`lastfm::Client::stream_now_playing`] does
+pub struct ScheduledLastfm {
+    scheduler: Arc<PollScheduler>,
+    user_id: SlackUserId,
+    lastfm_client: Arc<lastfm::Client>,
+    latest_scrobble_window: Option<Duration>,
+}
+
+impl ScheduledLastfm {
+    pub fn new(
+        scheduler: Arc<PollScheduler>,
+        user_id: SlackUserId,
+        lastfm_client: Arc<lastfm::Client>,
+    ) -> Self {
+        Self {
+            scheduler,
+            user_id,
+            lastfm_client,
+            latest_scrobble_window: None,
+        }
+    }
+
+    /// See [`lastfm::NowPlayingTracker::with_latest_scrobble_window`]
+    pub fn with_latest_scrobble_window(mut self, window: Duration) -> Self {
+        self.latest_scrobble_window = Some(window);
+        self
+    }
+}
+
+impl MusicSource for ScheduledLastfm {
+    type Error = LastFMError;
+
+    /// Subscribes straight away, replacing any earlier subscription for the same Slack user. The
+    /// stream ends once a newer one does, or after yielding a fatal error (see
+    /// [`LastFMError::is_fatal`])
+    fn stream_now_playing<'a>(
+        &'a self,
+        user: &'a str,
+        polling_interval: Duration,
+    ) -> BoxStream<'a, error_stack::Result<Option<NowPlaying>, Self::Error>> {
+        let polls = self.scheduler.subscribe(
+            self.user_id.clone(),
+            user.to_owned(),
+            self.lastfm_client.clone(),
+            polling_interval,
+        );
+        let mut tracker = lastfm::NowPlayingTracker::default();
+        if let Some(window) = self.latest_scrobble_window {
+            tracker = tracker.with_latest_scrobble_window(window);
+        }
+
+        // the receiver is dropped after a fatal error, ending the stream and the subscription
+        stream::unfold((Some(polls), tracker), |(polls, mut tracker)| async move {
+            let mut polls = polls?;
+            loop {
+                let change = match polls.recv().await? {
+                    Ok(tracks) => tracker.update(tracks).map(Ok),
+                    // the next poll is tried as usual, unless it can't help
+                    Err(e) => Some(Err(e)),
+                };
+                if let Some(change) = change {
+                    let fatal = matches!(&change, Err(e) if e.current_context().is_fatal());
+                    let polls = (!fatal).then_some(polls);
+                    let change = change.map(|track| track.map(NowPlaying::from));
+                    return Some((change, (polls, tracker)));
+                }
+            }
+        })
+        .boxed()
+    }
+}
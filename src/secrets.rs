@@ -0,0 +1,154 @@
+use std::{error::Error, fmt, future::Future};
+
+use error_stack::{Report, Result};
+
+use crate::env;
+
+/// The secrets the server needs at startup.
+#[derive(Debug, Clone, Copy)]
+pub enum SecretKind {
+    SlackClientSecret,
+    SlackSigningSecret,
+    DbKey,
+}
+
+impl SecretKind {
+    /// The name the secret is stored under, both as an env var and in external secret stores
+    pub fn key(&self) -> &'static str {
+        match self {
+            SecretKind::SlackClientSecret => "SLACK_CLIENT_SECRET",
+            SecretKind::SlackSigningSecret => "SLACK_SIGNING_SECRET",
+            SecretKind::DbKey => "DB_KEY",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    Missing,
+    ProviderError,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Missing => f.write_str("A required secret is missing"),
+            SecretError::ProviderError => f.write_str("Error fetching a secret from the provider"),
+        }
+    }
+}
+
+impl Error for SecretError {}
+
+/// Somewhere secrets can be loaded from
+pub trait SecretProvider {
+    fn secret(&self, kind: SecretKind) -> impl Future<Output = Result<String, SecretError>> + Send;
+}
+
+/// Reads secrets from the environment. This is the default provider.
+pub struct EnvSecretProvider;
+
+impl SecretProvider for EnvSecretProvider {
+    async fn secret(&self, kind: SecretKind) -> Result<String, SecretError> {
+        let secret = match kind {
+            SecretKind::SlackClientSecret => env::slack_client_secret(),
+            SecretKind::SlackSigningSecret => env::slack_signing_secret(),
+            // the signing secret used to be the database key, so existing databases keep working
+            SecretKind::DbKey => env::db_key().or_else(env::slack_signing_secret),
+        };
+
+        secret.ok_or_else(|| {
+            Report::new(SecretError::Missing)
+                .attach_printable(format!("{} isn't set in the environment", kind.key()))
+        })
+    }
+}
+
+/// Reads secrets from a HashiCorp Vault KV v2 secret, where each secret is stored under its
+/// [`SecretKind::key`].
+#[cfg(feature = "vault")]
+pub struct VaultSecretProvider {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+#[cfg(feature = "vault")]
+impl VaultSecretProvider {
+    /// Creates a provider from `VAULT_ADDR`, `VAULT_TOKEN` and `VAULT_SECRET_PATH`
+    pub fn from_env(client: reqwest::Client) -> Result<Self, SecretError> {
+        let (Some(addr), Some(token), Some(path)) = (
+            env::vault_addr(),
+            env::vault_token(),
+            env::vault_secret_path(),
+        ) else {
+            return Err(Report::new(SecretError::Missing).attach_printable(
+                "VAULT_ADDR, VAULT_TOKEN and VAULT_SECRET_PATH all need to be set to use Vault",
+            ));
+        };
+
+        Ok(Self {
+            client,
+            url: format!(
+                "{}/v1/{}",
+                addr.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            ),
+            token,
+        })
+    }
+}
+
+#[cfg(feature = "vault")]
+impl SecretProvider for VaultSecretProvider {
+    #[tracing::instrument(skip(self))]
+    async fn secret(&self, kind: SecretKind) -> Result<String, SecretError> {
+        use error_stack::ResultExt;
+
+        #[derive(serde::Deserialize)]
+        struct VaultResponse {
+            data: VaultData,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct VaultData {
+            data: std::collections::HashMap<String, String>,
+        }
+
+        let mut response = self
+            .client
+            .get(&self.url)
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .attach_printable("Couldn't fetch the secret from Vault")
+            .change_context(SecretError::ProviderError)?
+            .json::<VaultResponse>()
+            .await
+            .attach_printable("Couldn't deserialize the Vault response")
+            .change_context(SecretError::ProviderError)?;
+
+        response.data.data.remove(kind.key()).ok_or_else(|| {
+            Report::new(SecretError::Missing)
+                .attach_printable(format!("{} isn't set in the Vault secret", kind.key()))
+        })
+    }
+}
+
+/// All the secrets the server needs, resolved once at startup
+pub struct Secrets {
+    pub slack_client_secret: String,
+    pub slack_signing_secret: String,
+    pub db_key: String,
+}
+
+impl Secrets {
+    pub async fn resolve(provider: &impl SecretProvider) -> Result<Self, SecretError> {
+        Ok(Self {
+            slack_client_secret: provider.secret(SecretKind::SlackClientSecret).await?,
+            slack_signing_secret: provider.secret(SecretKind::SlackSigningSecret).await?,
+            db_key: provider.secret(SecretKind::DbKey).await?,
+        })
+    }
+}
@@ -0,0 +1,453 @@
+use age::{secrecy::Secret, x25519};
+use error_stack::{Report, Result, ResultExt};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::db::{DbError, LockExt, UserData};
+
+pub type Users = HashMap<String, Arc<Mutex<UserData>>>;
+
+/// Serializes a user even if a panicked task poisoned their lock, which serde's `Mutex` impl
+/// refuses to do. One bad user would otherwise stop the whole database from being saved
+struct LockedUser<'a>(&'a Mutex<UserData>);
+
+impl Serialize for LockedUser<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.0.lock_or_recover().serialize(serializer)
+    }
+}
+
+/// Serializes every user, see [`LockedUser`]
+struct LockedUsers<'a>(&'a Users);
+
+impl Serialize for LockedUsers<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(user_id, user)| (user_id, LockedUser(user))),
+        )
+    }
+}
+
+/// Where the users of a [`crate::db::Db`] are persisted.
+///
+/// The `Db` keeps every user in memory and tells its store about each change. Stores that can
+/// write a single user only write that one, the rest get every user to write at once.
+pub trait UserStore: Send {
+    /// Every stored user
+    fn load(&self) -> Result<Users, DbError>;
+
+    /// Persists a new or changed user
+    fn save_user(&self, users: &Users, user_id: &str) -> Result<(), DbError>;
+
+    /// Forgets a user that's already been removed from `users`
+    fn remove_user(&self, users: &Users, user_id: &str) -> Result<(), DbError>;
+
+    /// Persists every user, forgetting anyone not in `users`
+    fn save_all(&self, users: &Users) -> Result<(), DbError>;
+
+    /// Persists several changed users at once, forgetting those no longer in `users`
+    fn save_users(&self, users: &Users, user_ids: &[String]) -> Result<(), DbError> {
+        for user_id in user_ids {
+            if users.contains_key(user_id) {
+                self.save_user(users, user_id)?;
+            } else {
+                self.remove_user(users, user_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the storage from scratch, checking it reads back the same users
+    fn compact(&self, users: &Users) -> Result<(), DbError>;
+
+    /// When the storage was last changed, so replicas can tell when to reload
+    fn modified(&self) -> Option<SystemTime>;
+}
+
+/// How often a failed flush of the database is retried, for storage that fails intermittently
+/// (e.g. network filesystems). Retries wait asynchronously, without holding the database
+#[derive(Debug, Clone, Copy)]
+pub struct SaveRetry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for SaveRetry {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Every user in a single age encrypted JSON file, which is rewritten on every change.
+///
+/// The file is encrypted with a passphrase, or to an X25519 identity when one is given. A file
+/// encrypted with the passphrase can still be read with an identity set, so switching to an
+/// identity only needs a restart.
+pub struct EncryptedJsonStore {
+    location: PathBuf,
+    key: String,
+    identity: Option<x25519::Identity>,
+}
+
+impl EncryptedJsonStore {
+    pub fn new(location: PathBuf, key: String) -> Self {
+        Self {
+            location,
+            key,
+            identity: None,
+        }
+    }
+
+    /// Encrypts the file to `identity` instead of with the passphrase
+    pub fn with_identity(mut self, identity: x25519::Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    fn save(&self, users: &Users, verify: bool) -> Result<(), DbError> {
+        // what's checked against the written file. Users can change while saving, so it's also
+        // exactly what gets written
+        let expected = verify
+            .then(|| serde_json::to_value(LockedUsers(users)))
+            .transpose()
+            .attach_printable("Couldn't serialize database")
+            .change_context(DbError::SerdeError)?;
+
+        let encrypted = {
+            let encryptor = match &self.identity {
+                Some(identity) => {
+                    age::Encryptor::with_recipients(vec![Box::new(identity.to_public())])
+                        .expect("there's always a recipient")
+                }
+                None => age::Encryptor::with_user_passphrase(Secret::new(self.key.clone())),
+            };
+
+            let mut encrypted = vec![];
+            let mut writer = encryptor
+                .wrap_output(&mut encrypted)
+                .attach_printable("Couldn't create database encryptor")
+                .change_context(DbError::EncryptionError)?;
+
+            match &expected {
+                Some(expected) => serde_json::to_writer(&mut writer, expected),
+                None => serde_json::to_writer(&mut writer, &LockedUsers(users)),
+            }
+            .attach_printable("Couldn't serialize database")
+            .change_context(DbError::SerdeError)?;
+
+            writer
+                .finish()
+                .attach_printable("Couldn't finish encrypting database")
+                .change_context(DbError::EncryptionError)?;
+
+            encrypted
+        };
+
+        self.write_atomically(&encrypted, expected.as_ref())
+    }
+
+    /// Writes to a temporary file next to the database and renames it over the old one, so a
+    /// crash or failed write can't leave a half written database behind. With `expected`, the
+    /// temporary file has to read back as exactly those users first
+    fn write_atomically(
+        &self,
+        encrypted: &[u8],
+        expected: Option<&serde_json::Value>,
+    ) -> Result<(), DbError> {
+        let mut temp_location = self.location.clone().into_os_string();
+        temp_location.push(".tmp");
+        let temp_location = PathBuf::from(temp_location);
+
+        let written = std::fs::write(&temp_location, encrypted)
+            .attach_printable("Couldn't write encrypted database to temporary file")
+            .change_context(DbError::IoError)
+            .and_then(|()| match expected {
+                Some(expected) => self.verify_written(&temp_location, expected),
+                None => Ok(()),
+            })
+            .and_then(|()| {
+                std::fs::rename(&temp_location, &self.location)
+                    .attach_printable("Couldn't move the temporary database file into place")
+                    .change_context(DbError::IoError)
+            });
+
+        if written.is_err() {
+            // a file that didn't make it into place is of no use to anyone
+            let _ = std::fs::remove_file(&temp_location);
+        }
+        written
+    }
+
+    fn verify_written(
+        &self,
+        file_path: &Path,
+        expected: &serde_json::Value,
+    ) -> Result<(), DbError> {
+        let written = read_encrypted_file(file_path, &self.key, self.identity.as_ref())
+            .attach_printable("Couldn't read back the written database")?;
+        let written = serde_json::to_value(LockedUsers(&written))
+            .attach_printable("Couldn't serialize the written database")
+            .change_context(DbError::SerdeError)?;
+
+        if &written != expected {
+            return Err(Report::new(DbError::VerificationError)
+                .attach_printable("The written database doesn't match the users"));
+        }
+        Ok(())
+    }
+}
+
+impl UserStore for EncryptedJsonStore {
+    fn load(&self) -> Result<Users, DbError> {
+        if !self.location.exists() {
+            return Ok(HashMap::new());
+        }
+
+        read_encrypted_file(&self.location, &self.key, self.identity.as_ref())
+    }
+
+    fn save_user(&self, users: &Users, _: &str) -> Result<(), DbError> {
+        self.save(users, false)
+    }
+
+    fn remove_user(&self, users: &Users, _: &str) -> Result<(), DbError> {
+        self.save(users, false)
+    }
+
+    fn save_all(&self, users: &Users) -> Result<(), DbError> {
+        self.save(users, false)
+    }
+
+    fn save_users(&self, users: &Users, _: &[String]) -> Result<(), DbError> {
+        self.save(users, false)
+    }
+
+    fn compact(&self, users: &Users) -> Result<(), DbError> {
+        self.save(users, true)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        file_modified(&self.location)
+    }
+}
+
+fn file_modified(file_path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reads the X25519 identity from an age identity file, as written by `age-keygen`
+pub fn read_identity_file(file_path: &Path) -> Result<x25519::Identity, DbError> {
+    let contents = std::fs::read_to_string(file_path)
+        .attach_printable("Couldn't read the age identity file")
+        .change_context(DbError::IoError)?;
+
+    let identity = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| {
+            Report::new(DbError::EncryptionError)
+                .attach_printable("The age identity file doesn't contain an identity")
+        })?;
+
+    identity
+        .parse()
+        .map_err(|e| Report::new(DbError::EncryptionError).attach_printable(e))
+        .attach_printable("Couldn't parse the age identity")
+}
+
+fn read_encrypted_file(
+    file_path: &Path,
+    key: &str,
+    identity: Option<&x25519::Identity>,
+) -> Result<Users, DbError> {
+    let file_reader = std::fs::File::open(file_path)
+        .map(BufReader::new)
+        .attach_printable("Couldn't open database file")
+        .change_context(DbError::IoError)?;
+
+    let decrypted = match age::Decryptor::new(file_reader)
+        .attach_printable("Couldn't create database decryptor")
+        .change_context(DbError::EncryptionError)?
+    {
+        age::Decryptor::Passphrase(d) => d.decrypt(&Secret::new(key.to_owned()), None),
+        age::Decryptor::Recipients(d) => {
+            let identity = identity.ok_or_else(|| {
+                Report::new(DbError::EncryptionError).attach_printable(
+                    "The database is encrypted to an identity, but none was given",
+                )
+            })?;
+            d.decrypt(std::iter::once(identity as &dyn age::Identity))
+        }
+    };
+
+    // decrypted and deserialized as a stream, so the plaintext is never fully in memory
+    let reader = decrypted
+        .map(BufReader::new)
+        .attach_printable("Couldn't decrypt database")
+        .change_context(DbError::EncryptionError)?;
+
+    serde_json::from_reader(reader)
+        .attach_printable("Couldn't deserialize database")
+        .change_context(DbError::SerdeError)
+}
+
+/// One row per user in an SQLCipher encrypted SQLite database, so a change only writes that
+/// user's row
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    location: PathBuf,
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Opens (or creates) the database at `location`, encrypted with `key`
+    pub fn open(location: PathBuf, key: &str) -> Result<Self, DbError> {
+        let connection = rusqlite::Connection::open(&location)
+            .attach_printable("Couldn't open the SQLite database")
+            .change_context(DbError::IoError)?;
+
+        connection
+            .pragma_update(None, "key", key)
+            .attach_printable("Couldn't set the SQLite database key")
+            .change_context(DbError::EncryptionError)?;
+
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS users (user_id TEXT PRIMARY KEY, data TEXT NOT NULL)",
+                (),
+            )
+            .attach_printable("Couldn't create the users table. Is the key right?")
+            .change_context(DbError::EncryptionError)?;
+
+        Ok(Self {
+            location,
+            connection,
+        })
+    }
+
+    fn write_user(
+        connection: &rusqlite::Connection,
+        user_id: &str,
+        user: &Mutex<UserData>,
+    ) -> Result<(), DbError> {
+        let data = serde_json::to_string(&LockedUser(user))
+            .attach_printable("Couldn't serialize user")
+            .change_context(DbError::SerdeError)?;
+
+        connection
+            .execute(
+                "INSERT INTO users (user_id, data) VALUES (?1, ?2)
+                 ON CONFLICT (user_id) DO UPDATE SET data = excluded.data",
+                (user_id, data),
+            )
+            .attach_printable("Couldn't write user")
+            .change_context(DbError::IoError)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl UserStore for SqliteStore {
+    fn load(&self) -> Result<Users, DbError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id, data FROM users")
+            .attach_printable("Couldn't prepare to read users")
+            .change_context(DbError::IoError)?;
+
+        let rows = statement
+            .query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .attach_printable("Couldn't read users")
+            .change_context(DbError::IoError)?;
+
+        let mut users = HashMap::new();
+        for row in rows {
+            let (user_id, data) = row
+                .attach_printable("Couldn't read a user")
+                .change_context(DbError::IoError)?;
+            let user: UserData = serde_json::from_str(&data)
+                .attach_printable_lazy(|| format!("Couldn't deserialize user {}", user_id))
+                .change_context(DbError::SerdeError)?;
+            users.insert(user_id, Arc::new(Mutex::new(user)));
+        }
+
+        Ok(users)
+    }
+
+    fn save_user(&self, users: &Users, user_id: &str) -> Result<(), DbError> {
+        match users.get(user_id) {
+            Some(user) => Self::write_user(&self.connection, user_id, user),
+            None => self.remove_user(users, user_id),
+        }
+    }
+
+    fn remove_user(&self, _: &Users, user_id: &str) -> Result<(), DbError> {
+        self.connection
+            .execute("DELETE FROM users WHERE user_id = ?1", (user_id,))
+            .attach_printable("Couldn't remove user")
+            .change_context(DbError::IoError)?;
+
+        Ok(())
+    }
+
+    fn save_all(&self, users: &Users) -> Result<(), DbError> {
+        let transaction = self
+            .connection
+            .unchecked_transaction()
+            .attach_printable("Couldn't start a transaction")
+            .change_context(DbError::IoError)?;
+
+        transaction
+            .execute("DELETE FROM users", ())
+            .attach_printable("Couldn't clear users")
+            .change_context(DbError::IoError)?;
+        for (user_id, user) in users {
+            Self::write_user(&transaction, user_id, user)?;
+        }
+
+        transaction
+            .commit()
+            .attach_printable("Couldn't commit users")
+            .change_context(DbError::IoError)
+    }
+
+    fn compact(&self, users: &Users) -> Result<(), DbError> {
+        self.save_all(users)?;
+
+        self.connection
+            .execute("VACUUM", ())
+            .attach_printable("Couldn't vacuum the SQLite database")
+            .change_context(DbError::IoError)?;
+
+        let stored = self.load()?;
+        if stored.len() != users.len() || users.keys().any(|user_id| !stored.contains_key(user_id))
+        {
+            return Err(Report::new(DbError::VerificationError)
+                .attach_printable("The SQLite database has different users"));
+        }
+
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        file_modified(&self.location)
+    }
+}
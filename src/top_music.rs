@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use slackfm::lastfm::{self, RecentTrack};
+
+/// How long a user's recent tracks are reused for before asking Last.fm again
+const CACHE_DURATION: Duration = Duration::from_secs(60);
+/// The most users whose recent tracks are kept at once. The oldest are dropped to make room
+const MAX_CACHED_USERS: usize = 1000;
+/// The most users whose tracks are fetched for one /topmusic, to keep big workspaces fast and
+/// within Last.fm's rate limits
+pub const MAX_USERS: usize = 25;
+/// How many Last.fm requests /topmusic makes at once
+pub const CONCURRENT_REQUESTS: usize = 5;
+/// How many artists and tracks are shown
+const TOP_COUNT: usize = 5;
+
+/// Recent tracks per Last.fm user, so repeated /topmusic calls don't hit Last.fm every time.
+/// Expired entries are dropped whenever a new one is added
+#[derive(Default)]
+pub struct RecentTracksCache {
+    entries: Mutex<HashMap<String, (Instant, Arc<Vec<RecentTrack>>)>>,
+}
+
+impl RecentTracksCache {
+    pub async fn recent_tracks(
+        &self,
+        client: &lastfm::Client,
+        lastfm_username: &str,
+    ) -> error_stack::Result<Arc<Vec<RecentTrack>>, lastfm::LastFMError> {
+        let cached = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(lastfm_username)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < CACHE_DURATION)
+            .map(|(_, tracks)| tracks.clone());
+        if let Some(tracks) = cached {
+            return Ok(tracks);
+        }
+
+        let tracks = Arc::new(client.get_user_recent_tracks(lastfm_username).await?);
+        self.insert(lastfm_username, tracks.clone(), Instant::now());
+
+        Ok(tracks)
+    }
+
+    fn insert(&self, lastfm_username: &str, tracks: Arc<Vec<RecentTrack>>, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (fetched_at, _)| now.duration_since(*fetched_at) < CACHE_DURATION);
+
+        if entries.len() >= MAX_CACHED_USERS && !entries.contains_key(lastfm_username) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (fetched_at, _))| *fetched_at)
+                .map(|(username, _)| username.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(lastfm_username.to_owned(), (now, tracks));
+    }
+}
+
+/// The most listened to artists and tracks, by how many users listened to them
+#[derive(Debug, PartialEq, Eq)]
+pub struct TopMusic {
+    pub artists: Vec<(String, usize)>,
+    pub tracks: Vec<(String, usize)>,
+}
+
+/// Tallies each user's recent `(track name, artist)` plays. Every artist and track is only
+/// counted once per user, so one person looping a song doesn't top the chart on their own
+pub fn tally<'a, P>(users: impl IntoIterator<Item = P>) -> TopMusic
+where
+    P: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut artists: HashMap<String, usize> = HashMap::new();
+    let mut tracks: HashMap<String, usize> = HashMap::new();
+
+    for plays in users {
+        let plays: HashSet<(&str, &str)> = plays.into_iter().collect();
+        let user_artists: HashSet<&str> = plays.iter().map(|(_, artist)| *artist).collect();
+
+        for artist in user_artists {
+            *artists.entry(artist.to_owned()).or_default() += 1;
+        }
+        for (name, artist) in plays {
+            *tracks.entry(format!("{} - {}", name, artist)).or_default() += 1;
+        }
+    }
+
+    TopMusic {
+        artists: top(artists),
+        tracks: top(tracks),
+    }
+}
+
+/// The most counted entries, ties broken alphabetically so the order is stable
+fn top(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    counts.truncate(TOP_COUNT);
+    counts
+}
+
+impl TopMusic {
+    pub fn render(&self) -> String {
+        if self.artists.is_empty() {
+            return "Nobody has listened to anything recently".to_owned();
+        }
+
+        let list = |entries: &[(String, usize)]| {
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, (name, listeners))| {
+                    let plural = if *listeners == 1 { "" } else { "s" };
+                    format!("{}. {} ({} listener{})", i + 1, name, listeners, plural)
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        format!(
+            "*Top artists*\n{}\n\n*Top tracks*\n{}",
+            list(&self.artists),
+            list(&self.tracks)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_listeners_not_plays() {
+        let alice = vec![("Song", "Artist"), ("Song", "Artist"), ("Other", "Artist")];
+        let bob = vec![("Song", "Artist"), ("Hit", "Band")];
+
+        let top = tally([alice, bob]);
+
+        assert_eq!(
+            top.artists,
+            vec![("Artist".to_owned(), 2), ("Band".to_owned(), 1)]
+        );
+        assert_eq!(
+            top.tracks,
+            vec![
+                ("Song - Artist".to_owned(), 2),
+                ("Hit - Band".to_owned(), 1),
+                ("Other - Artist".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn cache_drops_expired_and_oldest_entries() {
+        let cache = RecentTracksCache::default();
+        let start = Instant::now();
+
+        for i in 0..MAX_CACHED_USERS {
+            let fetched_at = start + Duration::from_millis(i as u64);
+            cache.insert(&format!("user-{}", i), Arc::default(), fetched_at);
+        }
+        cache.insert("newest", Arc::default(), start + Duration::from_secs(1));
+
+        {
+            let entries = cache.entries.lock().unwrap();
+            assert_eq!(entries.len(), MAX_CACHED_USERS);
+            assert!(!entries.contains_key("user-0"));
+            assert!(entries.contains_key("newest"));
+        }
+
+        cache.insert("later", Arc::default(), start + CACHE_DURATION * 2);
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["later"]);
+    }
+
+    #[test]
+    fn nothing_played_renders_message() {
+        let top = tally(std::iter::empty::<Vec<(&str, &str)>>());
+        assert_eq!(top.render(), "Nobody has listened to anything recently");
+    }
+}